@@ -0,0 +1,150 @@
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+use crate::{Command, FrameDecoder, ReceiveError};
+
+/// Number of bytes requested from the stream per read
+const READ_CHUNK_SIZE: usize = 256;
+
+/// An asynchronous counterpart to `UartConnection`
+///
+/// Uses the same COBS framing as `UartConnection`. Defaults to a tokio serial
+/// port but can wrap any async byte stream.
+pub struct AsyncUartConnection<S = SerialStream> {
+    stream: S,
+    decoder: FrameDecoder,
+}
+
+impl AsyncUartConnection<SerialStream> {
+    /// Open a UART device, must be called from within a tokio runtime
+    ///
+    /// # Arguments
+    ///
+    /// * `uart_path` - The path to the UART device
+    /// * `baud_rate` - The baud rate of the UART device
+    ///
+    /// # Returns
+    ///
+    /// * A new AsyncUartConnection
+    ///
+    pub fn new(uart_path: &str, baud_rate: u32) -> std::io::Result<Self> {
+        let stream = tokio_serial::new(uart_path, baud_rate).open_native_async()?;
+        Ok(AsyncUartConnection::from_stream(stream))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncUartConnection<S> {
+    /// Create a connection over an existing async byte stream
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The stream to exchange frames over
+    ///
+    /// # Returns
+    ///
+    /// * A new AsyncUartConnection
+    ///
+    pub fn from_stream(stream: S) -> Self {
+        AsyncUartConnection {
+            stream,
+            decoder: FrameDecoder::new(),
+        }
+    }
+
+    /// Send a message to the UART device
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to send
+    ///
+    /// # Returns
+    ///
+    /// * The number of bytes written, the length of the whole frame
+    ///
+    pub async fn send_message(&mut self, command: Command) -> std::io::Result<usize> {
+        let frame = command.to_bytes();
+        self.stream.write_all(&frame).await?;
+        self.stream.flush().await?;
+        Ok(frame.len())
+    }
+
+    /// Receive a message from the UART device
+    ///
+    /// Bytes following the received frame are kept for the next call, and
+    /// cancelling the future does not lose any bytes. As with `UartConnection`,
+    /// a frame that fails to decode is returned as an error and the next call
+    /// resyncs on the next frame.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The timeout of the receive
+    ///
+    /// # Returns
+    ///
+    /// * The received message
+    ///
+    /// # Errors
+    ///
+    /// * `ReceiveError::Timeout` if no frame arrived within `timeout`
+    /// * `ReceiveError::Parse` if a frame arrived but could not be decoded
+    /// * `ReceiveError::Io` if reading from the stream failed or it was closed
+    ///
+    pub async fn receive_message(&mut self, timeout: Duration) -> Result<Command, ReceiveError> {
+        match tokio::time::timeout(timeout, self.next_frame()).await {
+            Ok(frame) => Ok(Command::from_bytes(frame?)?),
+            Err(_) => Err(ReceiveError::Timeout),
+        }
+    }
+
+    /// Read from the stream until a complete frame has been buffered
+    async fn next_frame(&mut self) -> Result<Vec<u8>, ReceiveError> {
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        loop {
+            if let Some(frame) = self.decoder.next_frame()? {
+                return Ok(frame);
+            }
+            let count = self.stream.read(&mut chunk).await?;
+            if count == 0 {
+                return Err(ReceiveError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.decoder.extend(&chunk[..count]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CommandType;
+
+    #[tokio::test]
+    async fn test_async_round_trip() {
+        let (a, b) = tokio::io::duplex(64);
+        let mut ground = AsyncUartConnection::from_stream(a);
+        let mut payload = AsyncUartConnection::from_stream(b);
+
+        ground.send_message(Command::startup_command(b"patch01.json".to_vec())).await.unwrap();
+        ground.send_message(Command::simple_command(CommandType::PowerDown)).await.unwrap();
+
+        let first = payload.receive_message(Duration::from_secs(1)).await.unwrap();
+        let second = payload.receive_message(Duration::from_secs(1)).await.unwrap();
+        assert_eq!(first, Command::startup_command(b"patch01.json".to_vec()));
+        assert_eq!(second.command_type, CommandType::PowerDown);
+        assert!(matches!(payload.receive_message(Duration::from_millis(10)).await, Err(ReceiveError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn test_async_corrupt_frame() {
+        let (mut a, b) = tokio::io::duplex(64);
+        let mut payload = AsyncUartConnection::from_stream(b);
+
+        // The COBS code promises four bytes before the terminator
+        a.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        a.write_all(&Command::simple_command(CommandType::Heartbeat).to_bytes()).await.unwrap();
+
+        let corrupt = payload.receive_message(Duration::from_secs(1)).await;
+        assert!(matches!(corrupt, Err(ReceiveError::Parse(_))));
+        let next = payload.receive_message(Duration::from_secs(1)).await.unwrap();
+        assert_eq!(next.command_type, CommandType::Heartbeat);
+    }
+}
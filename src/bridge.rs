@@ -0,0 +1,136 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use log::{debug, info, warn};
+use crate::{BridgeError, Transport};
+
+/// Relay commands between the ground station and the payload until stopped
+///
+/// Commands received from either side are forwarded unchanged to the other,
+/// including the payload's acknowledgements and Nacks, so the ground
+/// station's `send_and_await_ack` works through the bridge. Each side is
+/// polled for up to `poll_interval` in turn, so a command waits at most
+/// twice that before it is forwarded. Every hop is logged at debug level.
+///
+/// # Arguments
+///
+/// * `ground` - The ground station's link, e.g. a WebSocketTransport
+/// * `payload` - The payload's link, e.g. a UartConnection
+/// * `stop` - Set to stop the bridge, checked between polls
+/// * `poll_interval` - The longest to wait for one side before polling the other
+///
+/// # Errors
+///
+/// * `BridgeError::Ground` or `BridgeError::Payload` carrying the error of
+///   the side whose link failed, the bridge stops at the first failure
+///
+pub fn bridge(
+    ground: &mut impl Transport,
+    payload: &mut impl Transport,
+    stop: &AtomicBool,
+    poll_interval: Duration,
+) -> Result<(), BridgeError> {
+    info!("Bridge started");
+    while !stop.load(Ordering::Relaxed) {
+        let uplink = forward(ground, BridgeError::Ground, payload, BridgeError::Payload, poll_interval, "Uplink");
+        let result = uplink.and_then(|_| {
+            forward(payload, BridgeError::Payload, ground, BridgeError::Ground, poll_interval, "Downlink")
+        });
+        if let Err(e) = result {
+            warn!("Bridge stopped: {}", e);
+            return Err(e);
+        }
+    }
+    info!("Bridge stopped");
+    Ok(())
+}
+
+/// Forward the next command received on one side, if any, to the other
+///
+/// # Arguments
+///
+/// * `from` - The side to receive from
+/// * `from_error` - Wraps an error receiving from `from`
+/// * `to` - The side to send to
+/// * `to_error` - Wraps an error sending to `to`
+/// * `poll_interval` - How long to wait for a command
+/// * `hop` - The direction, uplink to the payload or downlink to the ground station, for logging
+///
+fn forward(
+    from: &mut impl Transport,
+    from_error: fn(std::io::Error) -> BridgeError,
+    to: &mut impl Transport,
+    to_error: fn(std::io::Error) -> BridgeError,
+    poll_interval: Duration,
+    hop: &str,
+) -> Result<(), BridgeError> {
+    let command = match from.receive_message(poll_interval) {
+        Ok(Some(command)) => command,
+        Ok(None) => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => return Ok(()),
+        Err(e) => return Err(from_error(e)),
+    };
+    debug!("{}: {}", hop, command);
+    to.send_message(command).map_err(to_error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Command, CommandType, LoopbackTransport};
+    use std::sync::Arc;
+
+    /// A link that has been unplugged
+    struct Unplugged;
+
+    impl Transport for Unplugged {
+        fn send_message(&mut self, _command: Command) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn receive_message(&mut self, _timeout: Duration) -> std::io::Result<Option<Command>> {
+            Err(std::io::ErrorKind::NotConnected.into())
+        }
+    }
+
+    #[test]
+    fn test_bridge() {
+        let (mut ground, mut ground_end) = LoopbackTransport::pair();
+        let (mut payload_end, mut payload) = LoopbackTransport::pair();
+        let stop = Arc::new(AtomicBool::new(false));
+        let bridge = {
+            let stop = stop.clone();
+            std::thread::spawn(move || bridge(&mut ground_end, &mut payload_end, &stop, Duration::from_millis(10)))
+        };
+        let responder = std::thread::spawn(move || {
+            let command = payload.receive_message(Duration::from_secs(1)).unwrap().unwrap();
+            payload.send_message(Command::ack_for(&command).unwrap()).unwrap();
+            command
+        });
+
+        let startup = Command::new_with_seq(CommandType::StartupCommand, b"orbit05.json".to_vec(), 3);
+        let ack = ground.send_and_await_ack(startup.clone(), Duration::from_secs(1)).unwrap();
+        assert!(ack.acknowledges(&startup));
+        assert_eq!(responder.join().unwrap(), startup);
+
+        stop.store(true, Ordering::Relaxed);
+        assert!(bridge.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_bridge_errors() {
+        let stop = AtomicBool::new(false);
+        let mut ground = LoopbackTransport::new();
+        let error = bridge(&mut ground, &mut Unplugged, &stop, Duration::from_millis(10)).unwrap_err();
+        assert!(matches!(error, BridgeError::Payload(ref e) if e.kind() == std::io::ErrorKind::NotConnected));
+
+        // A command from the ground station that cannot be sent on
+        ground.send_message(Command::simple_command(CommandType::Heartbeat)).unwrap();
+        let error = bridge(&mut ground, &mut Unplugged, &stop, Duration::from_millis(10)).unwrap_err();
+        assert!(matches!(error, BridgeError::Payload(ref e) if e.kind() == std::io::ErrorKind::BrokenPipe));
+
+        let error = bridge(&mut Unplugged, &mut ground, &stop, Duration::from_millis(10)).unwrap_err();
+        assert!(matches!(error, BridgeError::Ground(_)));
+        assert_eq!(std::io::Error::from(error).kind(), std::io::ErrorKind::NotConnected);
+    }
+}
@@ -0,0 +1,90 @@
+use std::io::{Read, Write};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+use crate::ParseError;
+
+/// Largest size compressed data may inflate to, guarding against decompression bombs
+const MAX_INFLATED_SIZE: u64 = 1024 * 1024;
+
+/// DEFLATE compress some bytes
+///
+/// # Arguments
+///
+/// * `data` - The bytes to compress
+///
+/// # Returns
+///
+/// * The compressed bytes
+///
+pub(crate) fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data).expect("Writing to a Vec cannot fail");
+    encoder.finish().expect("Writing to a Vec cannot fail")
+}
+
+/// Inflate DEFLATE compressed bytes
+///
+/// # Arguments
+///
+/// * `data` - The compressed bytes
+///
+/// # Returns
+///
+/// * The inflated bytes
+///
+/// # Errors
+///
+/// * `ParseError::Decompress` if the bytes are not valid DEFLATE data or
+///   inflate to more than `MAX_INFLATED_SIZE`
+///
+pub(crate) fn inflate(data: &[u8]) -> Result<Vec<u8>, ParseError> {
+    let mut inflated = Vec::new();
+    DeflateDecoder::new(data)
+        .take(MAX_INFLATED_SIZE + 1)
+        .read_to_end(&mut inflated)
+        .map_err(|_| ParseError::Decompress)?;
+    if inflated.len() as u64 > MAX_INFLATED_SIZE {
+        return Err(ParseError::Decompress);
+    }
+    Ok(inflated)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Command, CommandType, COMPRESSED_FLAG};
+
+    #[test]
+    fn test_compressed_round_trip() {
+        let json = br#"{"mode": "imaging", "exposure": 100, "targets": ["a", "b", "c"]}"#.repeat(50);
+        let command = Command::startup_command_compressed(json.clone());
+        let bytes = command.to_bytes();
+        assert!(bytes.len() < json.len() / 4);
+        assert_eq!(command.frame_len(), bytes.len());
+        let decoded = Command::from_bytes(bytes).unwrap();
+        assert_eq!(decoded, (CommandType::StartupCommand, &json[..]));
+        assert!(decoded.is_compressed());
+        assert_eq!(decoded, command);
+    }
+
+    #[test]
+    fn test_incompressible_data_is_stored_raw() {
+        // A short run of distinct bytes grows when deflated
+        let data: Vec<u8> = (0..16).map(|x| x * 17).collect();
+        let command = Command::startup_command_compressed(data.clone());
+        let raw = Command::startup_command(data.clone()).to_bytes();
+        assert_eq!(command.to_bytes(), raw);
+        let decoded = Command::from_bytes(command.to_bytes()).unwrap();
+        assert_eq!(decoded.data, data);
+        assert!(!decoded.is_compressed());
+    }
+
+    #[test]
+    fn test_invalid_compressed_data() {
+        let mut frame = cobs::encode_vec(&[u8::from(CommandType::StartupCommand) | COMPRESSED_FLAG, 0xff, 0xff]);
+        frame.push(0);
+        assert_eq!(Command::from_bytes(frame).unwrap_err(), crate::ParseError::Decompress);
+    }
+}
@@ -0,0 +1,221 @@
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
+
+use crate::{decode_frame, encode_frame, Command, ParseError, SEQUENCE_FLAG};
+
+/// Appended to the associated data of addressed commands, so an addressed
+/// frame cannot be authenticated when read as unaddressed or the other way round
+const ADDRESSED_MARKER: u8 = 0x01;
+
+impl Command {
+    /// Convert the command to a Vec<u8> with its data encrypted using AES-256-GCM, encoded with COBS
+    ///
+    /// The address, command type and sequence number stay in the clear for
+    /// routing but are authenticated as associated data, so none can be altered
+    /// undetected. A nonce must never be reused with the same key. Addressed
+    /// commands are read back with `from_bytes_addressed_encrypted`.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The 256-bit key
+    /// * `nonce` - The 96-bit nonce, unique to this message
+    ///
+    /// # Returns
+    ///
+    /// * A Vec<u8> containing the command with its data encrypted
+    ///
+    pub fn to_bytes_encrypted(&self, key: &[u8; 32], nonce: &[u8; 12]) -> Vec<u8> {
+        let payload = self.payload();
+        let addressed = self.address.is_some();
+        let (header, data) = payload.split_at(header_len(&payload, addressed));
+        let aad = associated_data(header, addressed);
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(nonce), Payload { msg: data, aad: &aad })
+            .expect("AES-GCM encryption cannot fail for command sized messages");
+        encode_frame(&[header, &ciphertext].concat())
+    }
+
+    /// Convert a COBS encoded Vec<u8> produced by `to_bytes_encrypted` to a Command
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The Vec<u8> to convert
+    /// * `key` - The 256-bit key the command was encrypted with
+    /// * `nonce` - The 96-bit nonce the command was encrypted with
+    ///
+    /// # Returns
+    ///
+    /// * A Command containing the decrypted data
+    ///
+    /// # Errors
+    ///
+    /// * As `from_bytes`
+    /// * `ParseError::AuthenticationFailed` if the frame was altered or the key or nonce is wrong
+    ///
+    pub fn from_bytes_encrypted(bytes: Vec<u8>, key: &[u8; 32], nonce: &[u8; 12]) -> Result<Command, ParseError> {
+        let payload = decrypt_payload(&bytes, key, nonce, false)?;
+        Ok(Command::from_payload(&payload)?.with_raw(bytes))
+    }
+
+    /// Convert a COBS encoded Vec<u8> produced by `to_bytes_encrypted` for an addressed command to a Command
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The Vec<u8> to convert
+    /// * `key` - The 256-bit key the command was encrypted with
+    /// * `nonce` - The 96-bit nonce the command was encrypted with
+    ///
+    /// # Returns
+    ///
+    /// * A Command containing the address and decrypted data
+    ///
+    /// # Errors
+    ///
+    /// * As `from_bytes_encrypted`
+    ///
+    pub fn from_bytes_addressed_encrypted(
+        bytes: Vec<u8>,
+        key: &[u8; 32],
+        nonce: &[u8; 12],
+    ) -> Result<Command, ParseError> {
+        let payload = decrypt_payload(&bytes, key, nonce, true)?;
+        let (&address, payload) = payload.split_first().ok_or(ParseError::EmptyFrame)?;
+        let mut command = Command::from_payload(payload)?;
+        command.address = Some(address);
+        Ok(command.with_raw(bytes))
+    }
+}
+
+/// Decode an encrypted frame and decrypt its data
+///
+/// # Arguments
+///
+/// * `bytes` - The COBS encoded frame
+/// * `key` - The 256-bit key the command was encrypted with
+/// * `nonce` - The 96-bit nonce the command was encrypted with
+/// * `addressed` - Whether the frame starts with an address
+///
+/// # Returns
+///
+/// * The frame contents with the data decrypted
+///
+fn decrypt_payload(bytes: &[u8], key: &[u8; 32], nonce: &[u8; 12], addressed: bool) -> Result<Vec<u8>, ParseError> {
+    let payload = decode_frame(bytes)?;
+    let header_len = header_len(&payload, addressed);
+    if payload.len() < header_len {
+        return Err(ParseError::EmptyFrame);
+    }
+    let (header, ciphertext) = payload.split_at(header_len);
+    let aad = associated_data(header, addressed);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let data = cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+        .map_err(|_| ParseError::AuthenticationFailed)?;
+    Ok([header, &data].concat())
+}
+
+/// The data authenticated along with the ciphertext, the clear header and,
+/// for addressed commands, `ADDRESSED_MARKER`
+fn associated_data(header: &[u8], addressed: bool) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    if addressed {
+        aad.push(ADDRESSED_MARKER);
+    }
+    aad
+}
+
+/// Length of the optional address, command type and optional sequence number at the start of a payload
+fn header_len(payload: &[u8], addressed: bool) -> usize {
+    let address_len = usize::from(addressed);
+    match payload.get(address_len) {
+        Some(&command_type) if command_type & SEQUENCE_FLAG != 0 => address_len + 2,
+        _ => address_len + 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CommandType;
+
+    const KEY: [u8; 32] = [7; 32];
+    const NONCE: [u8; 12] = [1; 12];
+
+    #[test]
+    fn test_encrypted_round_trip() {
+        for command in [
+            Command::startup_command(b"patch01.json".to_vec()),
+            Command::new_with_seq(CommandType::StartupCommand, b"orbit05.json".to_vec(), 9),
+            Command::simple_command(CommandType::PowerDown),
+        ] {
+            let bytes = command.to_bytes_encrypted(&KEY, &NONCE);
+            assert_eq!(Command::from_bytes_encrypted(bytes, &KEY, &NONCE).unwrap(), command);
+        }
+        // The data does not travel in the clear
+        let bytes = Command::startup_command(b"patch01.json".to_vec()).to_bytes_encrypted(&KEY, &NONCE);
+        assert!(!bytes.windows(7).any(|window| window == b"patch01"));
+    }
+
+    #[test]
+    fn test_encrypted_addressed() {
+        for address in [5, 0x90] {
+            for command in [
+                Command::new_addressed(address, CommandType::Time, vec![1, 2, 3]),
+                Command {
+                    seq: Some(4),
+                    ..Command::new_addressed(address, CommandType::StartupCommand, b"orbit05.json".to_vec())
+                },
+            ] {
+                let bytes = command.to_bytes_encrypted(&KEY, &NONCE);
+                let decoded = Command::from_bytes_addressed_encrypted(bytes.clone(), &KEY, &NONCE).unwrap();
+                assert_eq!(decoded, command);
+                assert_eq!(decoded.address(), Some(address));
+                // Read as unaddressed, the header no longer matches what was authenticated
+                assert_eq!(
+                    Command::from_bytes_encrypted(bytes, &KEY, &NONCE).unwrap_err(),
+                    ParseError::AuthenticationFailed
+                );
+            }
+        }
+
+        // The address is authenticated too
+        let command = Command::new_addressed(5, CommandType::Time, vec![1, 2, 3]);
+        let mut payload = decode_frame(&command.to_bytes_encrypted(&KEY, &NONCE)).unwrap();
+        payload[0] = 6;
+        assert_eq!(
+            Command::from_bytes_addressed_encrypted(encode_frame(&payload), &KEY, &NONCE).unwrap_err(),
+            ParseError::AuthenticationFailed
+        );
+    }
+
+    #[test]
+    fn test_encrypted_tampering() {
+        let command = Command::startup_command(b"patch01.json".to_vec());
+        let mut payload = decode_frame(&command.to_bytes_encrypted(&KEY, &NONCE)).unwrap();
+
+        // Flipping a ciphertext byte fails authentication
+        payload[3] ^= 0x01;
+        let bytes = encode_frame(&payload);
+        assert_eq!(
+            Command::from_bytes_encrypted(bytes, &KEY, &NONCE).unwrap_err(),
+            ParseError::AuthenticationFailed
+        );
+
+        // As does changing the clear command type
+        payload[3] ^= 0x01;
+        payload[0] = u8::from(CommandType::PowerDown);
+        let bytes = encode_frame(&payload);
+        assert_eq!(
+            Command::from_bytes_encrypted(bytes, &KEY, &NONCE).unwrap_err(),
+            ParseError::AuthenticationFailed
+        );
+
+        // And the wrong key
+        let bytes = command.to_bytes_encrypted(&KEY, &NONCE);
+        assert_eq!(
+            Command::from_bytes_encrypted(bytes, &[8; 32], &NONCE).unwrap_err(),
+            ParseError::AuthenticationFailed
+        );
+    }
+}
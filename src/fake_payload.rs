@@ -0,0 +1,195 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use crate::{Command, CommandType, LoopbackTransport, Transport};
+
+/// How often the payload thread checks whether it has been stopped
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Decides how a `FakePayload` replies to each command it receives
+///
+/// Override `handle` for the command types of interest and fall back to
+/// `default_response` for the rest. Closures taking a `&Command` and
+/// returning the replies are handlers too.
+pub trait PayloadHandler: Send {
+    /// The commands to send back in reply to `command`, in order
+    fn handle(&mut self, command: &Command) -> Vec<Command> {
+        default_response(command)
+    }
+}
+
+impl<F: FnMut(&Command) -> Vec<Command> + Send> PayloadHandler for F {
+    fn handle(&mut self, command: &Command) -> Vec<Command> {
+        self(command)
+    }
+}
+
+/// The handler replying to everything with `default_response`
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Acknowledger;
+
+impl PayloadHandler for Acknowledger {}
+
+/// How a well-behaved payload replies to a command
+///
+/// Acknowledged commands get their acknowledgement from `Command::ack_for`,
+/// and a StartupCommand's is followed by Initialised, as the payload sends
+/// once the startup command has run. Anything else gets no reply.
+///
+/// # Arguments
+///
+/// * `command` - The command the payload received
+///
+/// # Returns
+///
+/// * The commands the payload sends back
+///
+pub fn default_response(command: &Command) -> Vec<Command> {
+    let Some(ack) = Command::ack_for(command) else {
+        return Vec::new();
+    };
+    if command.command_type == CommandType::StartupCommand {
+        vec![ack, Command::simple_command(CommandType::Initialised)]
+    } else {
+        vec![ack]
+    }
+}
+
+/// A simulated payload answering commands on a background thread
+///
+/// The payload runs on one end of a `LoopbackTransport::pair`, and the other
+/// end is handed back to talk to it, so code written against `Transport` can
+/// be tested end to end without hardware. The thread stops when the
+/// FakePayload is stopped or dropped.
+pub struct FakePayload {
+    received: Arc<Mutex<Vec<Command>>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FakePayload {
+    /// Start a payload that replies with `default_response`
+    ///
+    /// # Returns
+    ///
+    /// * The payload and the transport connected to it
+    ///
+    pub fn spawn() -> (FakePayload, LoopbackTransport) {
+        FakePayload::spawn_with(Acknowledger)
+    }
+
+    /// Start a payload that replies with `handler`
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - Decides the replies to each command
+    ///
+    /// # Returns
+    ///
+    /// * The payload and the transport connected to it
+    ///
+    pub fn spawn_with(mut handler: impl PayloadHandler + 'static) -> (FakePayload, LoopbackTransport) {
+        let (host, mut payload) = LoopbackTransport::pair();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let received = received.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let command = match payload.receive_message(POLL_INTERVAL) {
+                        Ok(Some(command)) => command,
+                        Ok(None) => continue,
+                        Err(_) => break,
+                    };
+                    let replies = handler.handle(&command);
+                    received.lock().unwrap().push(command);
+                    for reply in replies {
+                        if payload.send_message(reply).is_err() {
+                            return;
+                        }
+                    }
+                }
+            })
+        };
+        let fake_payload = FakePayload {
+            received,
+            stop,
+            thread: Some(thread),
+        };
+        (fake_payload, host)
+    }
+
+    /// The commands received so far, oldest first
+    pub fn received(&self) -> Vec<Command> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// Stop the payload thread and wait for it to finish
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for FakePayload {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PROTOCOL_VERSION;
+
+    #[test]
+    fn test_default_response() {
+        let startup = Command::new_with_seq(CommandType::StartupCommand, b"orbit05.json".to_vec(), 4);
+        let replies = default_response(&startup);
+        assert_eq!(replies.len(), 2);
+        assert!(replies[0].acknowledges(&startup));
+        assert_eq!(replies[0].data, b"orbit05.json");
+        assert_eq!(replies[1].command_type, CommandType::Initialised);
+
+        let version = Command::new(CommandType::Version, vec![PROTOCOL_VERSION]);
+        assert_eq!(default_response(&version), vec![Command::new(CommandType::VersionAcknowledge, vec![PROTOCOL_VERSION])]);
+        assert!(default_response(&Command::simple_command(CommandType::TimeAcknowledge)).is_empty());
+    }
+
+    #[test]
+    fn test_fake_payload_acknowledges() {
+        let (payload, mut host) = FakePayload::spawn();
+        let time = Command::time_now();
+        let ack = host.send_and_await_ack(time.clone(), Duration::from_secs(1)).unwrap();
+        assert_eq!(ack.command_type, CommandType::TimeAcknowledge);
+
+        let startup = Command::startup_command(b"orbit05.json".to_vec());
+        host.send_and_await_ack(startup.clone(), Duration::from_secs(1)).unwrap();
+        let initialised = host.receive_message(Duration::from_secs(1)).unwrap().unwrap();
+        assert_eq!(initialised.command_type, CommandType::Initialised);
+        assert_eq!(payload.received(), vec![time, startup]);
+        payload.stop();
+    }
+
+    #[test]
+    fn test_fake_payload_custom_handler() {
+        // A payload that never acknowledges heartbeats
+        let (_payload, mut host) = FakePayload::spawn_with(|command: &Command| match command.command_type {
+            CommandType::Heartbeat => Vec::new(),
+            _ => default_response(command),
+        });
+        let heartbeat = Command::simple_command(CommandType::Heartbeat);
+        let error = host.send_and_await_ack(heartbeat, Duration::from_millis(100)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        let power_down = Command::simple_command(CommandType::PowerDown);
+        host.send_and_await_ack(power_down, Duration::from_secs(1)).unwrap();
+    }
+}
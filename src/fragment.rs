@@ -0,0 +1,250 @@
+use alloc::{vec, vec::Vec};
+
+use crate::{Command, CommandType, FragmentError};
+
+/// Number of bytes at the start of a Fragment command's data: the command
+/// type byte of the fragmented command, the fragment's index and the total
+/// number of fragments
+pub const FRAGMENT_HEADER_LEN: usize = 3;
+
+impl Command {
+    /// Split the command into Fragment commands that each fit in `max_payload` bytes
+    ///
+    /// Each fragment carries the command's sequence number and address, and
+    /// its data is `[command_type, index, total, data...]`. Fragments are sent
+    /// uncompressed. A command that already fits is returned unchanged.
+    /// Put the fragments back together with a `Reassembler`.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_payload` - The most bytes a frame may hold before encoding, header included
+    ///
+    /// # Returns
+    ///
+    /// * The fragments in order, or the command itself if it fits
+    ///
+    /// # Errors
+    ///
+    /// * `FragmentError::PayloadTooSmall` - `max_payload` leaves no room for data after the headers
+    /// * `FragmentError::TooManyFragments` - The command would need more than 255 fragments
+    ///
+    pub fn fragment(&self, max_payload: usize) -> Result<Vec<Command>, FragmentError> {
+        if self.payload().len() <= max_payload {
+            return Ok(vec![self.clone()]);
+        }
+        let overhead = self.header(false).count() + FRAGMENT_HEADER_LEN;
+        if max_payload <= overhead {
+            return Err(FragmentError::PayloadTooSmall(max_payload));
+        }
+        let chunks = self.data.chunks(max_payload - overhead);
+        let total = u8::try_from(chunks.len()).map_err(|_| FragmentError::TooManyFragments(chunks.len()))?;
+        Ok(chunks
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut data = Vec::with_capacity(FRAGMENT_HEADER_LEN + chunk.len());
+                data.extend([u8::from(self.command_type), index as u8, total]);
+                data.extend_from_slice(chunk);
+                Command {
+                    seq: self.seq,
+                    address: self.address,
+                    ..Command::new(CommandType::Fragment, data)
+                }
+            })
+            .collect())
+    }
+}
+
+/// The fragments received so far of one fragmented command
+#[derive(Clone, Debug)]
+struct Partial {
+    command_type: CommandType,
+    seq: Option<u8>,
+    address: Option<u8>,
+    fragments: Vec<Option<Vec<u8>>>,
+}
+
+/// Puts commands split by `Command::fragment` back together
+///
+/// Fragments may arrive in any order, and a repeated fragment replaces the
+/// earlier copy. One command is reassembled at a time: a fragment of a
+/// different command, told apart by its command type, sequence number,
+/// address and total, discards the fragments of the incomplete one.
+#[derive(Clone, Debug, Default)]
+pub struct Reassembler {
+    partial: Option<Partial>,
+}
+
+impl Reassembler {
+    /// Create a Reassembler with nothing received
+    pub fn new() -> Reassembler {
+        Reassembler::default()
+    }
+
+    /// Take a received command, returning the reassembled command once complete
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The received command, a Fragment or any other command
+    ///
+    /// # Returns
+    ///
+    /// * The reassembled command once its last missing fragment arrives,
+    ///   `command` itself if it is not a Fragment, or None while fragments are missing
+    ///
+    /// # Errors
+    ///
+    /// * If the fragment's header is truncated or invalid, leaving any
+    ///   fragments already received in place
+    ///
+    pub fn push(&mut self, command: Command) -> Result<Option<Command>, FragmentError> {
+        if command.command_type != CommandType::Fragment {
+            return Ok(Some(command));
+        }
+        let [command_type, index, total, ref data @ ..] = command.data[..] else {
+            return Err(FragmentError::Truncated);
+        };
+        if index >= total {
+            return Err(FragmentError::InvalidIndex { index, total });
+        }
+        let command_type =
+            CommandType::try_from(command_type).map_err(|_| FragmentError::InvalidCommandType(command_type))?;
+
+        let partial = match self.partial.take() {
+            Some(partial)
+                if partial.command_type == command_type
+                    && partial.seq == command.seq
+                    && partial.address == command.address
+                    && partial.fragments.len() == usize::from(total) =>
+            {
+                partial
+            }
+            _ => Partial {
+                command_type,
+                seq: command.seq,
+                address: command.address,
+                fragments: vec![None; usize::from(total)],
+            },
+        };
+        let partial = self.partial.insert(partial);
+        partial.fragments[usize::from(index)] = Some(data.to_vec());
+        if !partial.fragments.iter().all(Option::is_some) {
+            return Ok(None);
+        }
+
+        let partial = self.partial.take().expect("partial was just inserted");
+        Ok(Some(Command {
+            seq: partial.seq,
+            address: partial.address,
+            ..Command::new(partial.command_type, partial.fragments.into_iter().flatten().flatten().collect())
+        }))
+    }
+
+    /// The indices of the fragments still missing from the command being reassembled
+    pub fn missing(&self) -> Vec<u8> {
+        match &self.partial {
+            Some(partial) => (0..=u8::MAX)
+                .zip(&partial.fragments)
+                .filter(|(_, fragment)| fragment.is_none())
+                .map(|(index, _)| index)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Discard the fragments of the command being reassembled, e.g. after giving up on it
+    pub fn reset(&mut self) {
+        self.partial = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn large_command() -> Command {
+        let data = (0..100u8).collect();
+        Command::new_with_seq(CommandType::StartupCommand, data, 9)
+    }
+
+    #[test]
+    fn test_fragment() {
+        let command = large_command();
+        let fragments = command.fragment(40).unwrap();
+        // 2 header bytes and 3 fragment header bytes leave 35 bytes of data per fragment
+        assert_eq!(fragments.len(), 3);
+        for (index, fragment) in fragments.iter().enumerate() {
+            assert_eq!(fragment.command_type, CommandType::Fragment);
+            assert_eq!(fragment.seq(), Some(9));
+            assert_eq!(&fragment.data[..3], &[1, index as u8, 3]);
+            assert!(fragment.payload().len() <= 40);
+        }
+        assert_eq!(fragments[2].data.len(), FRAGMENT_HEADER_LEN + 30);
+        assert_eq!(command.fragment(200), Ok(vec![command.clone()]));
+    }
+
+    #[test]
+    fn test_fragment_errors() {
+        assert_eq!(large_command().fragment(5), Err(FragmentError::PayloadTooSmall(5)));
+        // 6 bytes of data per fragment need 256 fragments for 1536 bytes
+        let command = Command::new(CommandType::StartupCommand, vec![0; 1536]);
+        assert_eq!(command.fragment(10), Err(FragmentError::TooManyFragments(256)));
+        assert_eq!(command.fragment(11).map(|fragments| fragments.len()), Ok(220));
+    }
+
+    #[test]
+    fn test_reassemble_out_of_order() {
+        let command = large_command();
+        let mut fragments = command.fragment(40).unwrap();
+        fragments.swap(0, 2);
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.push(fragments[0].clone()), Ok(None));
+        assert_eq!(reassembler.missing(), vec![0, 1]);
+        assert_eq!(reassembler.push(fragments[1].clone()), Ok(None));
+        // A duplicate fragment changes nothing
+        assert_eq!(reassembler.push(fragments[1].clone()), Ok(None));
+        assert_eq!(reassembler.push(fragments[2].clone()), Ok(Some(command)));
+        assert!(reassembler.missing().is_empty());
+
+        let heartbeat = Command::simple_command(CommandType::Heartbeat);
+        assert_eq!(reassembler.push(heartbeat.clone()), Ok(Some(heartbeat)));
+    }
+
+    #[test]
+    fn test_reassemble_missing_fragment() {
+        let command = large_command();
+        let fragments = command.fragment(40).unwrap();
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.push(fragments[0].clone()), Ok(None));
+        assert_eq!(reassembler.push(fragments[2].clone()), Ok(None));
+        assert_eq!(reassembler.missing(), vec![1]);
+
+        // The next command's fragments replace the incomplete one
+        let next = Command::new_with_seq(CommandType::StartupCommand, vec![7; 50], 10);
+        let next_fragments = next.fragment(40).unwrap();
+        assert_eq!(reassembler.push(next_fragments[0].clone()), Ok(None));
+        assert_eq!(reassembler.push(fragments[1].clone()), Ok(None));
+        assert_eq!(reassembler.missing(), vec![0, 2]);
+        reassembler.reset();
+        assert!(reassembler.missing().is_empty());
+        let mut results: Vec<_> = next_fragments.into_iter().map(|fragment| reassembler.push(fragment)).collect();
+        assert_eq!(results.pop(), Some(Ok(Some(next))));
+        assert_eq!(results, vec![Ok(None)]);
+    }
+
+    #[test]
+    fn test_invalid_fragments() {
+        let mut reassembler = Reassembler::new();
+        let fragment = |data: Vec<u8>| Command::new(CommandType::Fragment, data);
+        assert_eq!(reassembler.push(fragment(vec![1, 0])), Err(FragmentError::Truncated));
+        assert_eq!(
+            reassembler.push(fragment(vec![1, 2, 2, 0xff])),
+            Err(FragmentError::InvalidIndex { index: 2, total: 2 })
+        );
+        assert_eq!(
+            reassembler.push(fragment(vec![0x80, 0, 1])),
+            Err(FragmentError::InvalidCommandType(0x80))
+        );
+        let single = Command::new(CommandType::StartupCommand, vec![0xff]);
+        assert_eq!(reassembler.push(fragment(vec![1, 0, 1, 0xff])), Ok(Some(single)));
+    }
+}
@@ -0,0 +1,522 @@
+use alloc::vec::Vec;
+use crate::{read_len, Command, Endianness, ParseError, FRAME_LENGTH_WIDTH};
+
+/// Default largest frame, excluding its terminator, that a FrameDecoder accepts
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Byte ending every frame with `Framing::Cobs`
+pub const FRAME_TERMINATOR: u8 = 0x00;
+
+/// How commands are delimited on the wire
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum Framing {
+    /// COBS encoded and terminated by a null byte, for raw byte streams
+    #[default]
+    Cobs,
+    /// A big-endian u16 length followed by the raw command, for transports
+    /// that already delimit or protect their messages
+    LengthPrefixed,
+    /// COBS encoded and terminated by the given byte, for peers using a
+    /// different delimiter. Every byte of the encoded frame is XORed with the
+    /// terminator so it appears only at the end, as the `cobs` crate's
+    /// sentinel functions do. `CobsTerminated(FRAME_TERMINATOR)` is the same as `Cobs`.
+    CobsTerminated(u8),
+}
+
+impl Framing {
+    /// The byte ending each frame, or None if frames are length-prefixed
+    pub fn terminator(&self) -> Option<u8> {
+        match self {
+            Framing::Cobs => Some(FRAME_TERMINATOR),
+            Framing::CobsTerminated(terminator) => Some(*terminator),
+            Framing::LengthPrefixed => None,
+        }
+    }
+}
+
+/// Incrementally splits a stream of bytes into frames
+///
+/// Bytes can be pushed in arbitrarily sized chunks, any incomplete frame at
+/// the end of a chunk is kept until the rest of it arrives.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    /// Number of bytes at the start of `buffer` known not to contain a terminator
+    scanned: usize,
+    max_frame_size: usize,
+    /// Whether the start of `buffer` is the rest of a frame already rejected as too large
+    discarding: bool,
+    framing: Framing,
+    /// Bytes of a rejected length-prefixed frame still to be skipped
+    skip: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> FrameDecoder {
+        FrameDecoder::with_max_frame_size(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl FrameDecoder {
+    /// Create a new FrameDecoder with an empty buffer
+    ///
+    /// # Returns
+    ///
+    /// * A new FrameDecoder
+    ///
+    pub fn new() -> FrameDecoder {
+        FrameDecoder::default()
+    }
+
+    /// Create a new FrameDecoder that rejects frames longer than `max_frame_size`
+    ///
+    /// # Arguments
+    ///
+    /// * `max_frame_size` - The largest frame accepted, excluding its terminator
+    ///
+    /// # Returns
+    ///
+    /// * A new FrameDecoder
+    ///
+    pub fn with_max_frame_size(max_frame_size: usize) -> FrameDecoder {
+        FrameDecoder {
+            buffer: Vec::new(),
+            scanned: 0,
+            max_frame_size,
+            discarding: false,
+            framing: Framing::Cobs,
+            skip: 0,
+        }
+    }
+
+    /// Set how frames are delimited, defaults to `Framing::Cobs`
+    ///
+    /// Bytes already buffered are split using the new framing.
+    ///
+    /// # Arguments
+    ///
+    /// * `framing` - The framing the peer sends
+    ///
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    /// How frames are delimited
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Set the largest frame accepted, excluding its terminator
+    ///
+    /// # Arguments
+    ///
+    /// * `max_frame_size` - The largest frame accepted
+    ///
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    /// The largest frame accepted, excluding its terminator
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    /// Add bytes to the decoder and decode every frame they complete
+    ///
+    /// Frames that fail to decode or are too large are discarded.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The bytes to add
+    ///
+    /// # Returns
+    ///
+    /// * The Commands decoded from every frame completed by `bytes`
+    ///
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Command> {
+        self.extend(bytes);
+        let mut commands = Vec::new();
+        loop {
+            match self.next_frame() {
+                Ok(Some(frame)) => {
+                    if let Ok(command) = Command::from_bytes_with(frame, self.framing) {
+                        commands.push(command);
+                    }
+                }
+                Ok(None) => return commands,
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Add bytes to the decoder without decoding them
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The bytes to add
+    ///
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Take the next complete frame out of the decoder
+    ///
+    /// # Returns
+    ///
+    /// * The raw frame including its terminator or length prefix, or None
+    ///   if no complete frame is buffered
+    ///
+    /// # Errors
+    ///
+    /// * `ParseError::FrameTooLarge` if the next frame is longer than the maximum
+    ///   frame size. The frame is discarded, including any of it that has yet to arrive.
+    ///
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, ParseError> {
+        match self.framing.terminator() {
+            Some(terminator) => self.next_cobs_frame(terminator),
+            None => self.next_length_prefixed_frame(),
+        }
+    }
+
+    /// Take the next frame ending in `terminator` out of the decoder
+    fn next_cobs_frame(&mut self, terminator: u8) -> Result<Option<Vec<u8>>, ParseError> {
+        loop {
+            match self.buffer[self.scanned..].iter().position(|&x| x == terminator) {
+                Some(index) => {
+                    let end = self.scanned + index;
+                    self.scanned = 0;
+                    let frame: Vec<u8> = self.buffer.drain(..=end).collect();
+                    if self.discarding {
+                        self.discarding = false;
+                        continue;
+                    }
+                    if end > self.max_frame_size {
+                        return Err(ParseError::FrameTooLarge);
+                    }
+                    return Ok(Some(frame));
+                }
+                None if self.discarding => {
+                    self.buffer.clear();
+                    self.scanned = 0;
+                    return Ok(None);
+                }
+                None if self.buffer.len() > self.max_frame_size => {
+                    self.buffer.clear();
+                    self.scanned = 0;
+                    self.discarding = true;
+                    return Err(ParseError::FrameTooLarge);
+                }
+                None => {
+                    self.scanned = self.buffer.len();
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    /// Take the next length-prefixed frame out of the decoder
+    fn next_length_prefixed_frame(&mut self) -> Result<Option<Vec<u8>>, ParseError> {
+        if self.skip > 0 {
+            let count = self.skip.min(self.buffer.len());
+            self.buffer.drain(..count);
+            self.skip -= count;
+            if self.skip > 0 {
+                return Ok(None);
+            }
+        }
+        let Some(length) = read_len(&self.buffer, FRAME_LENGTH_WIDTH, Endianness::Big) else {
+            return Ok(None);
+        };
+        let frame_len = FRAME_LENGTH_WIDTH.size() + length;
+        if length > self.max_frame_size {
+            self.skip = frame_len;
+            return self
+                .next_length_prefixed_frame()
+                .and(Err(ParseError::FrameTooLarge));
+        }
+        if self.buffer.len() < frame_len {
+            return Ok(None);
+        }
+        Ok(Some(self.buffer.drain(..frame_len).collect()))
+    }
+
+    /// The bytes buffered so far that are not yet part of a complete frame
+    /// (or have not yet been taken with `next_frame`)
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Discard everything buffered, including any partial frame
+    ///
+    /// # Returns
+    ///
+    /// * The number of bytes discarded
+    ///
+    pub fn clear(&mut self) -> usize {
+        let count = self.buffer.len();
+        self.buffer.clear();
+        self.scanned = 0;
+        self.discarding = false;
+        self.skip = 0;
+        count
+    }
+
+    /// Move buffered bytes out of the decoder, bypassing framing
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - The buffer to copy the bytes into
+    ///
+    /// # Returns
+    ///
+    /// * The number of bytes copied into `buffer`
+    ///
+    #[cfg(feature = "std")]
+    pub(crate) fn take_buffered(&mut self, buffer: &mut [u8]) -> usize {
+        let count = buffer.len().min(self.buffer.len());
+        buffer[..count].copy_from_slice(&self.buffer[..count]);
+        self.buffer.drain(..count);
+        self.scanned = self.scanned.saturating_sub(count);
+        self.discarding = false;
+        self.skip = 0;
+        count
+    }
+}
+
+/// Reads commands from any byte stream, e.g. a capture of UART traffic to replay
+///
+/// Frames are split with a FrameDecoder, so the reader may return any
+/// amount at a time. Frames that fail to decode are yielded as
+/// `ReceiveError::Parse` and reading carries on with the next frame. An
+/// incomplete frame at the end of the stream is yielded as
+/// `ParseError::MissingTerminator`, after which the iterator ends.
+#[cfg(feature = "std")]
+pub struct FrameReader<R> {
+    reader: R,
+    decoder: FrameDecoder,
+    /// Whether the reader has reached its end or failed
+    done: bool,
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> FrameReader<R> {
+    /// Create a FrameReader expecting COBS frames
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The stream to read frames from
+    ///
+    /// # Returns
+    ///
+    /// * A new FrameReader
+    ///
+    pub fn new(reader: R) -> FrameReader<R> {
+        FrameReader {
+            reader,
+            decoder: FrameDecoder::new(),
+            done: false,
+        }
+    }
+
+    /// Set how frames are delimited, defaults to `Framing::Cobs`
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.decoder.set_framing(framing);
+    }
+
+    /// Set the largest frame accepted, defaults to `DEFAULT_MAX_FRAME_SIZE`
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.decoder.set_max_frame_size(max_frame_size);
+    }
+
+    /// The underlying reader
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// The underlying reader, mutably
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Unwrap the underlying reader, dropping any partial frame
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> Iterator for FrameReader<R> {
+    type Item = Result<Command, crate::ReceiveError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut chunk = [0; 1024];
+        loop {
+            match self.decoder.next_frame() {
+                Ok(Some(frame)) => {
+                    return Some(Command::from_bytes_with(frame, self.decoder.framing()).map_err(Into::into))
+                }
+                Ok(None) => {}
+                Err(e) => return Some(Err(e.into())),
+            }
+            if self.done {
+                return None;
+            }
+            match self.reader.read(&mut chunk) {
+                Ok(0) => {
+                    self.done = true;
+                    if self.decoder.clear() > 0 {
+                        return Some(Err(ParseError::MissingTerminator.into()));
+                    }
+                }
+                Ok(count) => self.decoder.extend(&chunk[..count]),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CommandType;
+
+    #[test]
+    fn test_partial_frames() {
+        let mut stream = Command::simple_command(CommandType::Initialised).to_bytes();
+        stream.extend(Command::startup_command(b"patch01.json".to_vec()).to_bytes());
+        stream.extend(Command::simple_command(CommandType::PowerDown).to_bytes());
+
+        for chunk_size in 1..stream.len() {
+            let mut decoder = FrameDecoder::new();
+            let mut commands = Vec::new();
+            for chunk in stream.chunks(chunk_size) {
+                commands.extend(decoder.push(chunk));
+            }
+            assert_eq!(commands.len(), 3);
+            assert_eq!(commands[0].command_type, CommandType::Initialised);
+            assert_eq!(commands[1].command_type, CommandType::StartupCommand);
+            assert_eq!(commands[1].data, b"patch01.json");
+            assert_eq!(commands[2].command_type, CommandType::PowerDown);
+            assert!(decoder.buffered().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_zero_bytes_in_data() {
+        let command = Command::startup_command(vec![0, 1, 0, 2, 0]);
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = Vec::new();
+        for byte in command.to_bytes() {
+            decoded.extend(decoder.push(&[byte]));
+        }
+        assert_eq!(decoded, vec![command]);
+        assert!(decoder.buffered().is_empty());
+    }
+
+    #[test]
+    fn test_incomplete_tail_is_kept() {
+        let frame = Command::startup_command(vec![1, 2, 3]).to_bytes();
+        let mut decoder = FrameDecoder::new();
+        assert!(decoder.push(&frame[..3]).is_empty());
+        assert_eq!(decoder.buffered(), &frame[..3]);
+        let commands = decoder.push(&frame[3..]);
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].data, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_frame_too_large() {
+        let large = Command::startup_command(vec![7; 100]).to_bytes();
+        let small = Command::simple_command(CommandType::PowerDown).to_bytes();
+        let mut decoder = FrameDecoder::with_max_frame_size(50);
+
+        // A complete oversized frame is rejected without disturbing the next one
+        decoder.extend(&large);
+        decoder.extend(&small);
+        assert_eq!(decoder.next_frame(), Err(ParseError::FrameTooLarge));
+        assert_eq!(decoder.next_frame(), Ok(Some(small.clone())));
+
+        // An oversized frame still arriving is rejected early and the rest of it skipped
+        decoder.extend(&large[..60]);
+        assert_eq!(decoder.next_frame(), Err(ParseError::FrameTooLarge));
+        assert!(decoder.buffered().is_empty());
+        decoder.extend(&large[60..]);
+        decoder.extend(&small);
+        assert_eq!(decoder.next_frame(), Ok(Some(small)));
+    }
+
+    #[test]
+    fn test_custom_terminator() {
+        let framing = Framing::CobsTerminated(0x7e);
+        let commands = [
+            Command::startup_command(vec![0, 0x7e, 1, 0x7e ^ 0x01]),
+            Command::simple_command(CommandType::PowerDown),
+        ];
+        let stream: Vec<u8> = commands.iter().flat_map(|command| command.to_bytes_with(framing)).collect();
+        assert_eq!(stream.iter().filter(|&&byte| byte == 0x7e).count(), 2);
+
+        for chunk_size in 1..stream.len() {
+            let mut decoder = FrameDecoder::new();
+            decoder.set_framing(framing);
+            let decoded: Vec<Command> = stream.chunks(chunk_size).flat_map(|chunk| decoder.push(chunk)).collect();
+            assert_eq!(decoded, commands);
+            assert!(decoder.buffered().is_empty());
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_frame_reader() {
+        let commands = [
+            Command::startup_command(b"orbit05.json".to_vec()),
+            Command::simple_command(CommandType::Initialised),
+        ];
+        let mut capture = commands[0].to_bytes();
+        // A frame corrupted in the field, then one cut off by the end of the capture
+        capture.extend([0x05, 0x01, 0x00]);
+        capture.extend(&commands[1].to_bytes());
+        capture.extend(&commands[0].to_bytes()[..4]);
+
+        let path = std::env::temp_dir().join("ws_api_test_frame_reader.bin");
+        std::fs::write(&path, &capture).unwrap();
+        let replayed: Vec<_> = FrameReader::new(std::fs::File::open(&path).unwrap()).collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(replayed.len(), 4);
+        assert_eq!(replayed[0].as_ref().unwrap(), &commands[0]);
+        assert!(matches!(replayed[1], Err(crate::ReceiveError::Parse(ParseError::CobsDecode))));
+        assert_eq!(replayed[2].as_ref().unwrap(), &commands[1]);
+        assert!(matches!(replayed[3], Err(crate::ReceiveError::Parse(ParseError::MissingTerminator))));
+
+        let capture: Vec<u8> =
+            commands.iter().flat_map(|command| command.to_bytes_with(Framing::LengthPrefixed)).collect();
+        let mut reader = FrameReader::new(&capture[..]);
+        reader.set_framing(Framing::LengthPrefixed);
+        assert_eq!(reader.map(Result::unwrap).collect::<Vec<_>>(), commands);
+    }
+
+    #[test]
+    fn test_length_prefixed() {
+        let mut stream = Command::startup_command(vec![0, 1, 0]).to_bytes_with(Framing::LengthPrefixed);
+        stream.extend(Command::startup_command(vec![9; 100]).to_bytes_with(Framing::LengthPrefixed));
+        stream.extend(Command::simple_command(CommandType::PowerDown).to_bytes_with(Framing::LengthPrefixed));
+        assert_eq!(&stream[..6], &[0x00, 0x04, 0x01, 0x00, 0x01, 0x00]);
+
+        for chunk_size in 1..stream.len() {
+            let mut decoder = FrameDecoder::with_max_frame_size(50);
+            decoder.set_framing(Framing::LengthPrefixed);
+            let mut commands = Vec::new();
+            for chunk in stream.chunks(chunk_size) {
+                commands.extend(decoder.push(chunk));
+            }
+            // The oversized middle frame is skipped
+            assert_eq!(commands.len(), 2);
+            assert_eq!(commands[0].data, vec![0, 1, 0]);
+            assert_eq!(commands[1].command_type, CommandType::PowerDown);
+            assert!(decoder.buffered().is_empty());
+        }
+    }
+}
@@ -0,0 +1,778 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use log::{debug, warn};
+use crate::{read_len, write_len, Endianness, Ftp, FtpError, FtpProgress, LengthWidth};
+
+// File transfer control messages
+pub(crate) const READY_RECEIVE_FILE: &[u8] = b"READY_RECEIVE_FILE";
+pub(crate) const RECEIVED_FILE_DATA: &[u8] = b"RECEIVED_FILE_DATA";
+pub(crate) const SEND_FILE_HASH: &[u8] = b"SEND_FILE_HASH";
+pub(crate) const RECEIVE_FILE_SUCCESS: &[u8] = b"RECEIVE_FILE_SUCCESS";
+pub(crate) const RECEIVE_FILE_ERROR_RETRY: &[u8] = b"RECEIVE_FILE_ERROR_RETRY";
+
+/// Default number of times `send_file` resends a file or chunk whose hash the peer rejected
+pub const FTP_MAX_RETRIES: u32 = 2;
+
+/// Default time each phase of a file transfer may take, e.g. receiving one chunk
+pub const FTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default size of the chunks files are transferred in
+pub const FTP_CHUNK_SIZE: usize = 4096;
+
+/// Longest file name, in bytes, that is sent or accepted
+pub const FTP_MAX_FILENAME_LEN: usize = 255;
+
+/// Default size, in bytes, of the largest file accepted
+pub const FTP_MAX_FILE_SIZE: u64 = 1 << 30;
+
+/// Width of the big-endian length field preceding each chunk of file data
+const CHUNK_LENGTH_WIDTH: LengthWidth = LengthWidth::U32;
+
+/// Settings of a file transfer
+///
+/// Both ends must agree on `chunk_size` and `verify_per_chunk`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FtpConfig {
+    /// Largest chunk of file data sent or accepted, defaults to `FTP_CHUNK_SIZE`
+    pub chunk_size: usize,
+    /// Longest each phase of a transfer may take, e.g. receiving one chunk,
+    /// defaults to `FTP_TIMEOUT`
+    pub phase_timeout: Duration,
+    /// Number of times the sender resends a file or chunk whose hash the peer
+    /// rejected, defaults to `FTP_MAX_RETRIES`
+    pub max_retries: u32,
+    /// Directory that received files are written into, defaults to the working directory
+    pub dest_root: PathBuf,
+    /// Whether each chunk of file data carries its own SHA-256, so a corrupted
+    /// chunk can be resent on its own, defaults to true
+    pub verify_per_chunk: bool,
+    /// Largest file size a sender may announce before the transfer is refused,
+    /// defaults to `FTP_MAX_FILE_SIZE`
+    pub max_file_size: u64,
+}
+
+impl Default for FtpConfig {
+    fn default() -> FtpConfig {
+        FtpConfig {
+            chunk_size: FTP_CHUNK_SIZE,
+            phase_timeout: FTP_TIMEOUT,
+            max_retries: FTP_MAX_RETRIES,
+            dest_root: PathBuf::from("."),
+            verify_per_chunk: true,
+            max_file_size: FTP_MAX_FILE_SIZE,
+        }
+    }
+}
+
+impl FtpConfig {
+    /// Number of times a file or chunk is sent before giving up
+    fn max_attempts(&self) -> u32 {
+        self.max_retries.saturating_add(1)
+    }
+
+    /// The chunk size, treating zero as one byte
+    fn chunk_size(&self) -> usize {
+        self.chunk_size.max(1)
+    }
+}
+
+/// The byte stream a file transfer runs over
+pub(crate) trait FtpLink {
+    /// Read into `buffer`, failing with `TimedOut` if nothing arrives before `deadline`
+    fn read_before(&mut self, buffer: &mut [u8], deadline: Instant) -> std::io::Result<usize>;
+
+    /// Write all of `bytes` to the peer, failing with `TimedOut` if they are not all written before `deadline`
+    fn send(&mut self, bytes: &[u8], deadline: Instant) -> std::io::Result<()>;
+
+    /// Called after each phase's reads, e.g. to restore a timeout `read_before` shortened
+    fn end_reads(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs file transfers over any byte stream, e.g. a TcpStream or one end of
+/// `LoopbackTransport::pair`
+///
+/// Each phase of a transfer gives up after the FTP timeout, but only between
+/// reads and writes, so the stream should have read and write timeouts of its
+/// own shorter than the FTP timeout. Reads and writes failing with `TimedOut`
+/// or `WouldBlock` are retried until the phase's deadline.
+pub struct FtpStream<T> {
+    stream: T,
+    config: FtpConfig,
+}
+
+impl<T: Read + Write> FtpStream<T> {
+    /// Create a new FtpStream with the default settings
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The stream connected to the peer
+    ///
+    /// # Returns
+    ///
+    /// * A new FtpStream
+    ///
+    pub fn new(stream: T) -> FtpStream<T> {
+        FtpStream {
+            stream,
+            config: FtpConfig::default(),
+        }
+    }
+
+    /// Create a new FtpStream with the given settings
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The stream connected to the peer
+    /// * `config` - The settings of every transfer
+    ///
+    /// # Returns
+    ///
+    /// * A new FtpStream
+    ///
+    pub fn with_config(stream: T, config: FtpConfig) -> FtpStream<T> {
+        FtpStream { stream, config }
+    }
+
+    /// The settings of every transfer
+    pub fn config(&self) -> &FtpConfig {
+        &self.config
+    }
+
+    /// Replace the settings of every transfer
+    pub fn set_config(&mut self, config: FtpConfig) {
+        self.config = config;
+    }
+
+    /// Set the directory that files received with `ftp` are written into
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - The destination directory, defaults to the working directory
+    ///
+    pub fn set_root(&mut self, root: impl Into<PathBuf>) {
+        self.config.dest_root = root.into();
+    }
+
+    /// Set the size of the chunks files are transferred in, defaults to `FTP_CHUNK_SIZE`
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.config.chunk_size = chunk_size.max(1);
+    }
+
+    /// Set whether each chunk of file data carries its own SHA-256, defaults to true
+    pub fn set_verify_chunks(&mut self, verify_chunks: bool) {
+        self.config.verify_per_chunk = verify_chunks;
+    }
+
+    /// Set the longest each phase of a file transfer may take, defaults to `FTP_TIMEOUT`
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.config.phase_timeout = timeout;
+    }
+
+    /// The underlying stream
+    pub fn get_ref(&self) -> &T {
+        &self.stream
+    }
+
+    /// The underlying stream, mutably
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.stream
+    }
+
+    /// Unwrap the underlying stream
+    pub fn into_inner(self) -> T {
+        self.stream
+    }
+}
+
+impl<T: Read + Write> FtpLink for FtpStream<T> {
+    fn read_before(&mut self, buffer: &mut [u8], deadline: Instant) -> std::io::Result<usize> {
+        if Instant::now() >= deadline {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        match self.stream.read(buffer) {
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Err(std::io::ErrorKind::TimedOut.into()),
+            result => result,
+        }
+    }
+
+    fn send(&mut self, bytes: &[u8], deadline: Instant) -> std::io::Result<()> {
+        let mut written = 0;
+        while written < bytes.len() {
+            if Instant::now() >= deadline {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("Sent {} of {} bytes before the FTP timeout", written, bytes.len()),
+                ));
+            }
+            match self.stream.write(&bytes[written..]) {
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(count) => written += count,
+                Err(e) if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted
+                ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.stream.flush()
+    }
+}
+
+impl<T: Read + Write> Ftp for FtpStream<T> {
+    fn ftp_with_progress(&mut self, progress: FtpProgress) -> Result<(), FtpError> {
+        let config = self.config.clone();
+        receive_file(self, &config, progress)
+    }
+
+    fn send_file_with_progress(&mut self, path: &std::path::Path, progress: FtpProgress) -> Result<(), FtpError> {
+        let config = self.config.clone();
+        send_file(self, &config, path, progress)
+    }
+
+    fn ftp_with_config(&mut self, config: &FtpConfig) -> Result<(), FtpError> {
+        receive_file(self, config, &mut |_, _| {})
+    }
+
+    fn send_file_with_config(&mut self, path: &std::path::Path, config: &FtpConfig) -> Result<(), FtpError> {
+        send_file(self, config, path, &mut |_, _| {})
+    }
+}
+
+/// Receive a file sent by the peer into `config.dest_root`
+///
+/// # Arguments
+///
+/// * `link` - The stream connected to the peer
+/// * `config` - How to run the transfer
+/// * `progress` - Called after each chunk is accepted or rejected
+///
+pub(crate) fn receive_file<L: FtpLink + ?Sized>(
+    link: &mut L,
+    config: &FtpConfig,
+    progress: FtpProgress,
+) -> Result<(), FtpError> {
+    let mut byte = [0; 1];
+    let mut file_name = Vec::new();
+
+    // Receive file name, up to its null terminator, giving up on a peer that never sends one
+    let deadline = Instant::now() + config.phase_timeout;
+    loop {
+        read_exact_before(link, &mut byte, deadline)?;
+        if byte[0] == 0 {
+            break;
+        }
+        if file_name.len() == FTP_MAX_FILENAME_LEN {
+            warn!("File name longer than {} bytes, abandoning transfer", FTP_MAX_FILENAME_LEN);
+            return Err(FtpError::BadFilename);
+        }
+        file_name.push(byte[0]);
+    }
+
+    // Refuse anything that could escape the root
+    let file_name = sanitize_file_name(&file_name)?;
+
+    // Receive file size
+    let mut file_size = [0; 8];
+    read_exact_before(link, &mut file_size, deadline)?;
+    let file_size = u64::from_be_bytes(file_size);
+    if file_size > config.max_file_size {
+        warn!("File of {} bytes exceeds the maximum of {}, abandoning transfer", file_size, config.max_file_size);
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("File of {} bytes exceeds the maximum of {}", file_size, config.max_file_size),
+        )
+        .into());
+    }
+    let file_path = config.dest_root.join(file_name);
+    let part_path = config.dest_root.join(format!("{}.part", file_name));
+
+    // Send READY_RECEIVE_FILE message
+    link.send(READY_RECEIVE_FILE, deadline)?;
+
+    // Receive file data into a partial file, only moving it into place once the hash matches
+    debug!("Receiving {} ({} bytes)", file_name, file_size);
+    match receive_file_data(link, config, &part_path, file_size, progress) {
+        Ok(()) => {
+            debug!("Received {}", file_name);
+            Ok(std::fs::rename(&part_path, &file_path)?)
+        }
+        Err(e) => {
+            warn!("Failed to receive {}: {}", file_name, e);
+            let _ = std::fs::remove_file(&part_path);
+            Err(e)
+        }
+    }
+}
+
+/// Send a file to the peer, resending it if the peer rejects its hash
+///
+/// # Arguments
+///
+/// * `link` - The stream connected to the peer
+/// * `config` - How to run the transfer
+/// * `path` - The file to send
+/// * `progress` - Called after each chunk is accepted or rejected
+///
+pub(crate) fn send_file<L: FtpLink + ?Sized>(
+    link: &mut L,
+    config: &FtpConfig,
+    path: &std::path::Path,
+    progress: FtpProgress,
+) -> Result<(), FtpError> {
+    let file_name = path.file_name().and_then(|name| name.to_str()).ok_or(FtpError::BadFilename)?;
+    if file_name.len() > FTP_MAX_FILENAME_LEN {
+        return Err(FtpError::BadFilename);
+    }
+
+    for _ in 0..config.max_attempts() {
+        // Send file name, null terminated, followed by the file size
+        let mut file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+        let header = [file_name.as_bytes(), &[0], &file_size.to_be_bytes()].concat();
+        link.send(&header, Instant::now() + config.phase_timeout)?;
+        expect_message(link, config, &[READY_RECEIVE_FILE])?;
+
+        // Send file data chunk by chunk, followed by an empty chunk
+        let mut file_hasher = Sha256::new();
+        let mut bytes_sent = 0;
+        let mut chunk = Vec::with_capacity(config.chunk_size());
+        loop {
+            chunk.clear();
+            (&mut file).take(config.chunk_size() as u64).read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                break;
+            }
+            file_hasher.update(&chunk);
+            send_file_chunk(link, config, &chunk, &mut |rejected| {
+                if !rejected {
+                    bytes_sent += chunk.len() as u64;
+                }
+                progress(bytes_sent, Some(file_size));
+            })?;
+        }
+        let mut end = Vec::with_capacity(CHUNK_LENGTH_WIDTH.size());
+        write_len(&mut end, 0, CHUNK_LENGTH_WIDTH, Endianness::Big);
+        link.send(&end, Instant::now() + config.phase_timeout)?;
+
+        // Send file hash when asked for it
+        expect_message(link, config, &[SEND_FILE_HASH])?;
+        link.send(file_hasher.finalize().as_slice(), Instant::now() + config.phase_timeout)?;
+
+        if expect_message(link, config, &[RECEIVE_FILE_SUCCESS, RECEIVE_FILE_ERROR_RETRY])? == 0 {
+            debug!("Sent {} ({} bytes)", file_name, file_size);
+            return Ok(());
+        }
+        warn!("Peer rejected the hash of {}, resending", file_name);
+    }
+    Err(FtpError::RetriesExhausted { attempts: config.max_attempts() })
+}
+
+/// Receive file data chunks until the empty end chunk, then check the file hash
+///
+/// Each chunk is a big-endian u32 length, the data, then the data's SHA-256
+/// if `verify_chunks` is set. Chunks are acknowledged with
+/// RECEIVED_FILE_DATA, or RECEIVE_FILE_ERROR_RETRY if their hash is wrong.
+///
+/// # Arguments
+///
+/// * `link` - The stream connected to the peer
+/// * `config` - How to run the transfer
+/// * `path` - Where to write the file data
+/// * `file_size` - The size of the file announced by the sender
+/// * `progress` - Called after each chunk is accepted or rejected
+///
+fn receive_file_data<L: FtpLink + ?Sized>(
+    link: &mut L,
+    config: &FtpConfig,
+    path: &std::path::Path,
+    file_size: u64,
+    progress: FtpProgress,
+) -> Result<(), FtpError> {
+    let mut file = File::create(path)?;
+    let mut file_hasher = Sha256::new();
+    let mut bytes_received = 0;
+    let mut chunk = Vec::with_capacity(config.chunk_size());
+    loop {
+        let deadline = Instant::now() + config.phase_timeout;
+        let mut length = [0u8; CHUNK_LENGTH_WIDTH.size()];
+        read_exact_before(link, &mut length, deadline)?;
+        let length = read_len(&length, CHUNK_LENGTH_WIDTH, Endianness::Big).expect("length field is full width");
+        if length == 0 {
+            if bytes_received != file_size {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("Received {} of the announced {} bytes", bytes_received, file_size),
+                )
+                .into());
+            }
+            break;
+        }
+        if length > config.chunk_size() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Chunk of {} bytes exceeds the chunk size of {}", length, config.chunk_size()),
+            )
+            .into());
+        }
+        if bytes_received + length as u64 > file_size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Received more than the announced {} bytes", file_size),
+            )
+            .into());
+        }
+        chunk.resize(length, 0);
+        read_exact_before(link, &mut chunk, deadline)?;
+
+        if config.verify_per_chunk {
+            let mut chunk_hash = [0; 32];
+            read_exact_before(link, &mut chunk_hash, deadline)?;
+            if !hash_matches(&chunk_hash, &Sha256::digest(&chunk)) {
+                warn!("File chunk hash mismatch, requesting a resend");
+                link.send(RECEIVE_FILE_ERROR_RETRY, deadline)?;
+                progress(bytes_received, Some(file_size));
+                continue;
+            }
+        }
+
+        file_hasher.update(&chunk);
+        file.write_all(&chunk)?;
+        bytes_received += length as u64;
+        link.send(RECEIVED_FILE_DATA, deadline)?;
+        progress(bytes_received, Some(file_size));
+    }
+
+    // Send SEND_FILE_HASH message
+    let deadline = Instant::now() + config.phase_timeout;
+    link.send(SEND_FILE_HASH, deadline)?;
+
+    // Receive file hash
+    let mut hash_buffer = [0; 32];
+    read_exact_before(link, &mut hash_buffer, deadline)?;
+
+    // Check file hash
+    if !hash_matches(&hash_buffer, &file_hasher.finalize()) {
+        link.send(RECEIVE_FILE_ERROR_RETRY, Instant::now() + config.phase_timeout)?;
+        return Err(FtpError::HashMismatch);
+    }
+
+    // Send RECEIVE_FILE_SUCCESS message
+    link.send(RECEIVE_FILE_SUCCESS, Instant::now() + config.phase_timeout)?;
+    Ok(())
+}
+
+/// Send one chunk of file data, resending it if the peer reports it corrupted
+///
+/// # Arguments
+///
+/// * `link` - The stream connected to the peer
+/// * `config` - How to run the transfer
+/// * `chunk` - The file data to send
+/// * `on_response` - Called with whether the peer rejected the chunk after each attempt
+///
+fn send_file_chunk<L: FtpLink + ?Sized>(
+    link: &mut L,
+    config: &FtpConfig,
+    chunk: &[u8],
+    on_response: &mut dyn FnMut(bool),
+) -> Result<(), FtpError> {
+    let mut frame = Vec::with_capacity(CHUNK_LENGTH_WIDTH.size() + chunk.len() + 32);
+    write_len(&mut frame, chunk.len(), CHUNK_LENGTH_WIDTH, Endianness::Big);
+    frame.extend_from_slice(chunk);
+    if config.verify_per_chunk {
+        frame.extend_from_slice(Sha256::digest(chunk).as_slice());
+    }
+    for _ in 0..config.max_attempts() {
+        link.send(&frame, Instant::now() + config.phase_timeout)?;
+        let rejected = expect_message(link, config, &[RECEIVED_FILE_DATA, RECEIVE_FILE_ERROR_RETRY])? == 1;
+        on_response(rejected);
+        if !rejected {
+            return Ok(());
+        }
+    }
+    Err(FtpError::RetriesExhausted { attempts: config.max_attempts() })
+}
+
+/// Read a control message from the peer, waiting up to the FTP timeout
+///
+/// # Arguments
+///
+/// * `link` - The stream connected to the peer
+/// * `config` - How to run the transfer
+/// * `expected` - The messages that are acceptable at this point in the protocol
+///
+/// # Returns
+///
+/// * The index in `expected` of the message that was received
+///
+/// # Errors
+///
+/// * `FtpError::UnexpectedResponse` carrying what the peer sent if it sent anything else
+/// * `FtpError::Timeout` if no acceptable message arrived in time
+///
+fn expect_message<L: FtpLink + ?Sized>(
+    link: &mut L,
+    config: &FtpConfig,
+    expected: &[&[u8]],
+) -> Result<usize, FtpError> {
+    let deadline = Instant::now() + config.phase_timeout;
+    let mut message = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        if let Some(index) = expected.iter().position(|m| *m == message.as_slice()) {
+            return Ok(index);
+        }
+        if !expected.iter().any(|m| m.starts_with(&message)) {
+            return Err(FtpError::UnexpectedResponse(message));
+        }
+        read_exact_before(link, &mut byte, deadline)?;
+        message.push(byte[0]);
+    }
+}
+
+/// Fill `buffer` from the link, giving up once `deadline` passes
+///
+/// # Arguments
+///
+/// * `link` - The stream connected to the peer
+/// * `buffer` - The buffer to fill
+/// * `deadline` - The instant after which the read gives up
+///
+/// # Errors
+///
+/// * `FtpError::Timeout` if `buffer` could not be filled before `deadline`
+/// * `FtpError::Io` if reading from the link failed
+///
+fn read_exact_before<L: FtpLink + ?Sized>(
+    link: &mut L,
+    buffer: &mut [u8],
+    deadline: Instant,
+) -> Result<(), FtpError> {
+    let mut filled = 0;
+    let result = loop {
+        if filled == buffer.len() {
+            break Ok(());
+        }
+        match link.read_before(&mut buffer[filled..], deadline) {
+            Ok(0) => break Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+            Ok(count) => filled += count,
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                if Instant::now() >= deadline {
+                    break Err(FtpError::Timeout);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => break Err(e.into()),
+        }
+    };
+    link.end_reads()?;
+    result
+}
+
+/// Compare a received hash with the expected one in constant time
+///
+/// # Arguments
+///
+/// * `received` - The hash sent by the peer
+/// * `expected` - The hash of the data actually received
+///
+/// # Returns
+///
+/// * Whether the hashes are equal, taking the same time wherever they differ
+///
+fn hash_matches(received: &[u8], expected: &[u8]) -> bool {
+    received.ct_eq(expected).into()
+}
+
+/// Check a file name received from the peer is safe to create
+///
+/// # Arguments
+///
+/// * `file_name` - The raw file name
+///
+/// # Returns
+///
+/// * The file name as a str
+///
+/// # Errors
+///
+/// * `FtpError::BadFilename` if the name is not UTF-8, is empty, is `.` or
+///   `..`, or contains a path separator, drive prefix or null byte
+///
+fn sanitize_file_name(file_name: &[u8]) -> Result<&str, FtpError> {
+    let file_name = std::str::from_utf8(file_name).map_err(|_| FtpError::BadFilename)?;
+    if file_name.is_empty() || file_name == "." || file_name == ".." {
+        return Err(FtpError::BadFilename);
+    }
+    if file_name.contains(['/', '\\', ':', '\0']) {
+        return Err(FtpError::BadFilename);
+    }
+    Ok(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LoopbackTransport;
+
+    #[test]
+    fn test_sanitize_file_name() {
+        assert_eq!(sanitize_file_name(b"patch01.json").unwrap(), "patch01.json");
+        for name in [&b"../../etc/passwd"[..], b"..", b".", b"", b"C:evil", b"dir\\file", b"\xff"] {
+            assert!(matches!(sanitize_file_name(name), Err(FtpError::BadFilename)));
+        }
+    }
+
+    #[test]
+    fn test_file_name_length_is_bounded() {
+        // A peer that never terminates the file name is cut off rather than read until the timeout
+        let (mut sender, receiver) = LoopbackTransport::pair();
+        let mut receiver = FtpStream::new(receiver);
+        sender.write_all(&[b'a'; FTP_MAX_FILENAME_LEN + 1]).unwrap();
+        let start_time = Instant::now();
+        assert!(matches!(receiver.ftp(), Err(FtpError::BadFilename)));
+        assert!(start_time.elapsed() < FTP_TIMEOUT);
+
+        let root = std::env::temp_dir().join("ws_api_test_ftp_long_name");
+        std::fs::create_dir_all(&root).unwrap();
+        let path = root.join("a".repeat(FTP_MAX_FILENAME_LEN + 1));
+        let mut sender = FtpStream::new(sender);
+        assert!(matches!(sender.send_file(&path), Err(FtpError::BadFilename)));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_hash_matches() {
+        let hash = Sha256::digest(b"orbit05.json");
+        assert!(hash_matches(&hash, &Sha256::digest(b"orbit05.json")));
+        assert!(!hash_matches(&hash, &Sha256::digest(b"orbit06.json")));
+        assert!(!hash_matches(&hash[..31], &hash));
+    }
+
+    #[test]
+    fn test_round_trip_over_loopback() {
+        let root = std::env::temp_dir().join("ws_api_test_ftp_round_trip");
+        let source_dir = root.join("source");
+        let dest_dir = root.join("dest");
+        std::fs::create_dir_all(&source_dir).unwrap();
+        std::fs::create_dir_all(&dest_dir).unwrap();
+        let contents: Vec<u8> = (0..10000u32).map(|x| (x * 13) as u8).collect();
+        let source = source_dir.join("orbit05.json");
+        std::fs::write(&source, &contents).unwrap();
+
+        let (sender, receiver) = LoopbackTransport::pair();
+        let sender = std::thread::spawn(move || {
+            let mut sender = FtpStream::new(sender);
+            sender.set_chunk_size(1000);
+            sender.send_file(&source)
+        });
+        let mut receiver = FtpStream::new(receiver);
+        receiver.set_root(&dest_dir);
+        receiver.set_chunk_size(1000);
+        let mut reports = Vec::new();
+        receiver.ftp_with_progress(&mut |received, total| reports.push((received, total))).unwrap();
+        sender.join().unwrap().unwrap();
+
+        assert_eq!(reports.len(), 10);
+        assert_eq!(reports.last(), Some(&(10000, Some(10000))));
+        assert_eq!(std::fs::read(dest_dir.join("orbit05.json")).unwrap(), contents);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_ftp_with_config() {
+        let root = std::env::temp_dir().join("ws_api_test_ftp_with_config");
+        std::fs::create_dir_all(&root).unwrap();
+        let source = std::env::temp_dir().join("ws_api_test_ftp_with_config.bin");
+        let contents: Vec<u8> = (0..3000u32).map(|x| (x * 3) as u8).collect();
+        std::fs::write(&source, &contents).unwrap();
+        let config = FtpConfig {
+            chunk_size: 700,
+            phase_timeout: Duration::from_secs(2),
+            max_retries: 0,
+            dest_root: root.clone(),
+            verify_per_chunk: false,
+            max_file_size: 3000,
+        };
+
+        let (sender, receiver) = LoopbackTransport::pair();
+        let sender_config = config.clone();
+        let sender = std::thread::spawn(move || FtpStream::new(sender).send_file_with_config(&source, &sender_config));
+        let mut receiver = FtpStream::new(receiver);
+        receiver.ftp_with_config(&config).unwrap();
+        sender.join().unwrap().unwrap();
+
+        assert_eq!(std::fs::read(root.join("ws_api_test_ftp_with_config.bin")).unwrap(), contents);
+        assert_eq!(receiver.config(), &FtpConfig::default());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// A peer that sends whatever it is given but has stopped reading
+    struct Wedged(std::io::Cursor<Vec<u8>>);
+
+    impl Read for Wedged {
+        fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buffer)
+        }
+    }
+
+    impl Write for Wedged {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            std::thread::sleep(Duration::from_millis(10));
+            Err(std::io::ErrorKind::WouldBlock.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_timeout() {
+        let header = [&b"orbit05.json\0"[..], &4u64.to_be_bytes()].concat();
+        let mut receiver = FtpStream::new(Wedged(std::io::Cursor::new(header)));
+        receiver.set_timeout(Duration::from_millis(100));
+        let start_time = Instant::now();
+        assert!(matches!(receiver.ftp(), Err(FtpError::Timeout)));
+        assert!(start_time.elapsed() < Duration::from_secs(1));
+
+        let source = std::env::temp_dir().join("ws_api_test_ftp_write_timeout.bin");
+        std::fs::write(&source, b"data").unwrap();
+        let mut sender = FtpStream::new(Wedged(std::io::Cursor::new(Vec::new())));
+        sender.set_timeout(Duration::from_millis(100));
+        assert!(matches!(sender.send_file(&source), Err(FtpError::Timeout)));
+        std::fs::remove_file(&source).unwrap();
+    }
+
+    #[test]
+    fn test_file_size_is_bounded() {
+        // The announced size is refused before anything is written
+        let root = std::env::temp_dir().join("ws_api_test_ftp_file_size");
+        std::fs::create_dir_all(&root).unwrap();
+        let config = FtpConfig { dest_root: root.clone(), max_file_size: 1000, ..FtpConfig::default() };
+        let (mut sender, receiver) = LoopbackTransport::pair();
+        sender.write_all(&[b"big.bin\0", &1001u64.to_be_bytes()[..]].concat()).unwrap();
+        let mut receiver = FtpStream::new(receiver);
+        let result = receiver.ftp_with_config(&config);
+        assert!(matches!(result, Err(FtpError::Io(e)) if e.kind() == std::io::ErrorKind::InvalidData));
+        assert!(!root.join("big.bin.part").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_timeout_over_loopback() {
+        let (_sender, receiver) = LoopbackTransport::pair();
+        let mut receiver = FtpStream::new(receiver);
+        receiver.get_mut().set_timeout(Duration::from_millis(20));
+        receiver.set_timeout(Duration::from_millis(100));
+        let start_time = Instant::now();
+        assert!(matches!(receiver.ftp(), Err(FtpError::Timeout)));
+        assert!(start_time.elapsed() < Duration::from_secs(1));
+    }
+}
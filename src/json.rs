@@ -0,0 +1,86 @@
+use alloc::{string::String, vec::Vec};
+use serde::{Deserialize, Serialize};
+
+use crate::{Command, CommandType};
+
+/// A Command as the ground station's websocket messages carry it
+#[derive(Serialize, Deserialize)]
+struct JsonCommand {
+    #[serde(rename = "type")]
+    command_type: CommandType,
+    #[serde(with = "crate::base64_data")]
+    data: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seq: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    address: Option<u8>,
+}
+
+impl Command {
+    /// Convert the command to the JSON sent over the ground station's websocket
+    ///
+    /// The command is an object such as `{"type":"Time","data":"AAABiWg..."}`,
+    /// with its data base64 encoded, and `seq` and `address` fields if it has them.
+    ///
+    /// # Returns
+    ///
+    /// * The JSON object as a String
+    ///
+    pub fn to_json(&self) -> String {
+        let json = JsonCommand {
+            command_type: self.command_type,
+            data: self.data.clone(),
+            seq: self.seq,
+            address: self.address,
+        };
+        serde_json::to_string(&json).expect("a command always serializes to JSON")
+    }
+
+    /// Convert JSON produced by `to_json` to a Command
+    ///
+    /// # Arguments
+    ///
+    /// * `json` - The JSON object
+    ///
+    /// # Returns
+    ///
+    /// * A Command containing the data from the JSON
+    ///
+    /// # Errors
+    ///
+    /// * If the JSON is malformed, has an unknown type or its data is not valid base64
+    ///
+    pub fn from_json(json: &str) -> serde_json::Result<Command> {
+        let json: JsonCommand = serde_json::from_str(json)?;
+        Ok(Command {
+            seq: json.seq,
+            address: json.address,
+            ..Command::new(json.command_type, json.data)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_round_trip() {
+        let command = Command::startup_command(vec![1, 2, 3]);
+        assert_eq!(command.to_json(), r#"{"type":"StartupCommand","data":"AQID"}"#);
+        assert_eq!(Command::from_json(&command.to_json()).unwrap(), command);
+
+        let command = Command::new_addressed(4, CommandType::PowerDown, Vec::new());
+        let command = Command { seq: Some(9), ..command };
+        assert_eq!(command.to_json(), r#"{"type":"PowerDown","data":"","seq":9,"address":4}"#);
+        assert_eq!(Command::from_json(&command.to_json()).unwrap(), command);
+    }
+
+    #[test]
+    fn test_invalid_json() {
+        assert!(Command::from_json(r#"{"type":"StartupCommand","data":"not base64!"}"#).is_err());
+        assert!(Command::from_json(r#"{"type":"Launch","data":""}"#).is_err());
+        assert!(Command::from_json(r#"{"data":"AQID"}"#).is_err());
+        assert!(Command::from_json("[]").is_err());
+    }
+}
@@ -1,14 +1,69 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+use alloc::{vec, vec::Vec};
+#[cfg(feature = "chrono")]
 use chrono::prelude::*;
-use cobs::{decode_vec, encode_vec};
+use cobs::{decode, encode, max_encoding_length};
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "async")]
+mod async_uart;
+#[cfg(feature = "std")]
+mod bridge;
+#[cfg(feature = "compress")]
+mod compress;
+#[cfg(feature = "crypto")]
+mod crypto;
+#[cfg(feature = "test-util")]
+mod fake_payload;
+mod fragment;
+mod framing;
+#[cfg(feature = "std")]
+mod ftp;
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "chrono")]
+mod message;
+#[cfg(feature = "std")]
+mod transport;
+#[cfg(feature = "std")]
 mod uart;
+#[cfg(feature = "ws")]
+mod websocket;
 
-pub use crate::uart::{UartConnection};
+#[cfg(feature = "async")]
+pub use crate::async_uart::AsyncUartConnection;
+#[cfg(feature = "std")]
+pub use crate::bridge::bridge;
+#[cfg(feature = "test-util")]
+pub use crate::fake_payload::{default_response, Acknowledger, FakePayload, PayloadHandler};
+pub use crate::fragment::{Reassembler, FRAGMENT_HEADER_LEN};
+pub use crate::framing::{FrameDecoder, Framing, DEFAULT_MAX_FRAME_SIZE, FRAME_TERMINATOR};
+#[cfg(feature = "std")]
+pub use crate::framing::FrameReader;
+#[cfg(feature = "std")]
+pub use crate::ftp::{
+    FtpConfig, FtpStream, FTP_CHUNK_SIZE, FTP_MAX_FILENAME_LEN, FTP_MAX_FILE_SIZE, FTP_MAX_RETRIES, FTP_TIMEOUT,
+};
+#[cfg(feature = "chrono")]
+pub use crate::message::Message;
+#[cfg(feature = "std")]
+pub use crate::transport::{Backoff, LoopbackTransport, RetriesExhausted, Transport};
+#[cfg(feature = "std")]
+pub use crate::uart::{Stats, UartConnection, UartConnectionBuilder, DEFAULT_PORT_SETTINGS};
+#[cfg(feature = "ws")]
+pub use crate::websocket::{WebSocketEncoding, WebSocketTransport};
 
 /// Single byte identifier for the type of command
-#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+///
+/// New command types may be added, so matches on it need a wildcard arm.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
+#[non_exhaustive]
 pub enum CommandType {
     Time = 0,
     StartupCommand = 1,
@@ -27,258 +82,3377 @@ pub enum CommandType {
     ReceiveFileErrorRetry = 14,
     ReceiveFileErrorAbort = 15,
     SendFileAbort = 16,
+    /// Time with nanosecond precision, see `datetime_to_bytes_nanos`
+    TimeNanos = 17,
+    /// Keepalive checking the link is still up
+    Heartbeat = 18,
+    HeartbeatAcknowledge = 19,
+    /// Protocol version exchanged when the link comes up, see `PROTOCOL_VERSION`
+    Version = 20,
+    VersionAcknowledge = 21,
+    /// One piece of a command too large for a single frame, see `Command::fragment`
+    Fragment = 22,
+    /// Rejection of a received command, carrying its command type and a reason code, see `Command::nack`
+    Nack = 23,
+    /// A command byte this version does not recognise, kept so it can be
+    /// passed on or ignored, see `UnknownCommandType`
+    Unknown(UnknownCommandType),
 }
 
-impl From<u8> for CommandType {
-    fn from(byte: u8) -> CommandType {
-        match byte {
-            0 => CommandType::Time,
-            1 => CommandType::StartupCommand,
-            2 => CommandType::Initialised,
-            3 => CommandType::PowerDown,
-            4 => CommandType::TimeAcknowledge,
-            5 => CommandType::StartupCommandAcknowledge,
-            6 => CommandType::InitialisedAcknowledge,
-            7 => CommandType::PowerDownAcknowledge,
-            8 => CommandType::RequestSendFile,
-            9 => CommandType::ReadyReceiveFile,
-            10 => CommandType::SendFileData,
-            11 => CommandType::ReceivedFileData,
-            12 => CommandType::SendFileHash,
-            13 => CommandType::ReceiveFileSuccess,
-            14 => CommandType::ReceiveFileErrorRetry,
-            15 => CommandType::ReceiveFileErrorAbort,
-            16 => CommandType::SendFileAbort,
-            _ => panic!("Invalid command type"),
+/// A command type byte this version does not recognise
+///
+/// It can only be made by `TryFrom<u8>`, or `CommandType::try_from`, so it
+/// always holds an unassigned byte in 0x00-0x3f, the range free for custom
+/// command types. Bytes with `SEQUENCE_FLAG` or `COMPRESSED_FLAG` set would
+/// be read back as a different command, and assigned bytes are the known types.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(try_from = "u8", into = "u8"))]
+pub struct UnknownCommandType(u8);
+
+impl UnknownCommandType {
+    /// The command type byte
+    pub fn byte(self) -> u8 {
+        self.0
+    }
+}
+
+impl TryFrom<u8> for UnknownCommandType {
+    type Error = InvalidCommandType;
+
+    /// Wrap an unassigned command type byte
+    ///
+    /// # Arguments
+    ///
+    /// * `byte` - The command type byte
+    ///
+    /// # Returns
+    ///
+    /// * The UnknownCommandType, or an `InvalidCommandType` carrying the byte
+    ///   if it is assigned to a known type or has `SEQUENCE_FLAG` or `COMPRESSED_FLAG` set
+    ///
+    fn try_from(byte: u8) -> Result<UnknownCommandType, InvalidCommandType> {
+        match CommandType::try_from(byte)? {
+            CommandType::Unknown(unknown) => Ok(unknown),
+            _ => Err(InvalidCommandType(byte)),
         }
     }
 }
 
-pub trait Ftp {
-    fn ftp(&mut self) -> Result<(), std::io::Error>;
+impl From<UnknownCommandType> for u8 {
+    fn from(unknown: UnknownCommandType) -> u8 {
+        unknown.0
+    }
 }
 
-/// A command used in communicating with the payload
-///
-/// # Fields
-///
-/// * `command_type` - The type of command
-/// * `data` - The data associated with the command
-///
-#[derive(Serialize,Deserialize,Debug)]
-pub struct Command {
-    pub command_type: CommandType,
-    pub data: Vec<u8>,
+/// Number of known command types, those listed by `CommandType::all`
+pub const COMMAND_TYPE_COUNT: usize = 24;
+
+/// Every known command type, indexed by its byte
+const ALL_COMMAND_TYPES: [CommandType; COMMAND_TYPE_COUNT] = [
+    CommandType::Time,
+    CommandType::StartupCommand,
+    CommandType::Initialised,
+    CommandType::PowerDown,
+    CommandType::TimeAcknowledge,
+    CommandType::StartupCommandAcknowledge,
+    CommandType::InitialisedAcknowledge,
+    CommandType::PowerDownAcknowledge,
+    CommandType::RequestSendFile,
+    CommandType::ReadyReceiveFile,
+    CommandType::SendFileData,
+    CommandType::ReceivedFileData,
+    CommandType::SendFileHash,
+    CommandType::ReceiveFileSuccess,
+    CommandType::ReceiveFileErrorRetry,
+    CommandType::ReceiveFileErrorAbort,
+    CommandType::SendFileAbort,
+    CommandType::TimeNanos,
+    CommandType::Heartbeat,
+    CommandType::HeartbeatAcknowledge,
+    CommandType::Version,
+    CommandType::VersionAcknowledge,
+    CommandType::Fragment,
+    CommandType::Nack,
+];
+
+impl core::fmt::Display for CommandType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self, f)
+    }
 }
 
-/// Convert a DateTime<Utc> to a Vec<u8>
-///
-/// # Arguments
-///
-/// * `time` - The DateTime<Utc> to convert
-///
-/// # Returns
-///
-/// * A Vec<u8> containing the bytes of the DateTime<Utc>
-///
-pub fn datetime_to_bytes(time: DateTime<Utc>) -> Vec<u8> {
-    let time = time.timestamp();
-    time.to_be_bytes().to_vec()
+impl CommandType {
+    /// Every known command type in order of its byte, excluding `Unknown`
+    pub fn all() -> &'static [CommandType] {
+        &ALL_COMMAND_TYPES
+    }
+
+    /// Whether this command type acknowledges another
+    pub fn is_acknowledgement(&self) -> bool {
+        matches!(
+            self,
+            CommandType::TimeAcknowledge
+                | CommandType::StartupCommandAcknowledge
+                | CommandType::InitialisedAcknowledge
+                | CommandType::PowerDownAcknowledge
+                | CommandType::HeartbeatAcknowledge
+                | CommandType::VersionAcknowledge
+        )
+    }
+
+    /// The acknowledgement the receiver replies with for this command type
+    ///
+    /// # Returns
+    ///
+    /// * The acknowledging CommandType, or None if this type is not acknowledged
+    ///
+    pub fn expected_ack(&self) -> Option<CommandType> {
+        match self {
+            CommandType::Time | CommandType::TimeNanos => Some(CommandType::TimeAcknowledge),
+            CommandType::StartupCommand => Some(CommandType::StartupCommandAcknowledge),
+            CommandType::Initialised => Some(CommandType::InitialisedAcknowledge),
+            CommandType::PowerDown => Some(CommandType::PowerDownAcknowledge),
+            CommandType::Heartbeat => Some(CommandType::HeartbeatAcknowledge),
+            CommandType::Version => Some(CommandType::VersionAcknowledge),
+            _ => None,
+        }
+    }
 }
 
-/// Convert a Vec<u8> to a DateTime<Utc>
-///
-/// # Arguments
-///
-/// * `bytes` - The Vec<u8> to convert
-///
-/// # Returns
-///
-/// * A DateTime<Utc> containing the date and time of the bytes
+/// Error returned when a byte cannot be a `CommandType` because it has
+/// `SEQUENCE_FLAG` or `COMPRESSED_FLAG` set
 ///
-/// # Panics
+/// # Fields
 ///
-/// * If the bytes are not the correct length
-/// * If the bytes cannot be converted to a DateTime<Utc>
+/// * `0` - The offending byte
 ///
-pub fn bytes_to_datetime(bytes: &[u8]) -> DateTime<Utc> {
-    let mut time_bytes = [0u8; 8];
-    time_bytes.copy_from_slice(&bytes[..8]);
-    let time = i64::from_be_bytes(time_bytes);
-    Utc.timestamp_millis_opt(time).unwrap()
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct InvalidCommandType(pub u8);
+
+impl core::fmt::Display for InvalidCommandType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Invalid command type: {}", self.0)
+    }
 }
 
+impl core::error::Error for InvalidCommandType {}
 
-impl Command {
-    /// Create a new command
+impl TryFrom<u8> for CommandType {
+    type Error = InvalidCommandType;
+
+    /// Convert a byte received over the wire into a `CommandType`
     ///
     /// # Arguments
     ///
-    /// * `command_type` - The type of command
-    /// * `data` - The data associated with the command
+    /// * `byte` - The byte to convert
     ///
     /// # Returns
     ///
-    /// * A new Command
+    /// * The matching CommandType, `CommandType::Unknown` for unassigned bytes,
+    ///   or an `InvalidCommandType` carrying the byte if it has `SEQUENCE_FLAG`
+    ///   or `COMPRESSED_FLAG` set
     ///
-    pub fn new(command_type: CommandType, data: Vec<u8>) -> Command {
-        Command {
-            command_type,
-            data,
+    fn try_from(byte: u8) -> Result<CommandType, InvalidCommandType> {
+        match byte {
+            0 => Ok(CommandType::Time),
+            1 => Ok(CommandType::StartupCommand),
+            2 => Ok(CommandType::Initialised),
+            3 => Ok(CommandType::PowerDown),
+            4 => Ok(CommandType::TimeAcknowledge),
+            5 => Ok(CommandType::StartupCommandAcknowledge),
+            6 => Ok(CommandType::InitialisedAcknowledge),
+            7 => Ok(CommandType::PowerDownAcknowledge),
+            8 => Ok(CommandType::RequestSendFile),
+            9 => Ok(CommandType::ReadyReceiveFile),
+            10 => Ok(CommandType::SendFileData),
+            11 => Ok(CommandType::ReceivedFileData),
+            12 => Ok(CommandType::SendFileHash),
+            13 => Ok(CommandType::ReceiveFileSuccess),
+            14 => Ok(CommandType::ReceiveFileErrorRetry),
+            15 => Ok(CommandType::ReceiveFileErrorAbort),
+            16 => Ok(CommandType::SendFileAbort),
+            17 => Ok(CommandType::TimeNanos),
+            18 => Ok(CommandType::Heartbeat),
+            19 => Ok(CommandType::HeartbeatAcknowledge),
+            20 => Ok(CommandType::Version),
+            21 => Ok(CommandType::VersionAcknowledge),
+            22 => Ok(CommandType::Fragment),
+            23 => Ok(CommandType::Nack),
+            _ if byte & (SEQUENCE_FLAG | COMPRESSED_FLAG) != 0 => Err(InvalidCommandType(byte)),
+            _ => Ok(CommandType::Unknown(UnknownCommandType(byte))),
         }
     }
+}
 
-    /// Create a new time command
+impl From<CommandType> for u8 {
+    /// Convert a `CommandType` into the byte sent over the wire
     ///
     /// # Arguments
     ///
-    /// * `time` - The time to send
+    /// * `command_type` - The CommandType to convert
     ///
     /// # Returns
     ///
-    /// * A new Command containing the time
+    /// * The CommandType's wire byte
     ///
-    pub fn time(time: DateTime<Utc>) -> Command {
-        Command::new(CommandType::Time, datetime_to_bytes(time))
+    fn from(command_type: CommandType) -> u8 {
+        match command_type {
+            CommandType::Time => 0,
+            CommandType::StartupCommand => 1,
+            CommandType::Initialised => 2,
+            CommandType::PowerDown => 3,
+            CommandType::TimeAcknowledge => 4,
+            CommandType::StartupCommandAcknowledge => 5,
+            CommandType::InitialisedAcknowledge => 6,
+            CommandType::PowerDownAcknowledge => 7,
+            CommandType::RequestSendFile => 8,
+            CommandType::ReadyReceiveFile => 9,
+            CommandType::SendFileData => 10,
+            CommandType::ReceivedFileData => 11,
+            CommandType::SendFileHash => 12,
+            CommandType::ReceiveFileSuccess => 13,
+            CommandType::ReceiveFileErrorRetry => 14,
+            CommandType::ReceiveFileErrorAbort => 15,
+            CommandType::SendFileAbort => 16,
+            CommandType::TimeNanos => 17,
+            CommandType::Heartbeat => 18,
+            CommandType::HeartbeatAcknowledge => 19,
+            CommandType::Version => 20,
+            CommandType::VersionAcknowledge => 21,
+            CommandType::Fragment => 22,
+            CommandType::Nack => 23,
+            CommandType::Unknown(unknown) => unknown.0,
+        }
     }
+}
 
-    /// Create a new startup command
+/// Errors that can occur when parsing a Command from COBS encoded bytes
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ParseError {
+    /// No byte terminating the frame was found, more bytes are needed
+    MissingTerminator,
+    /// The frame is not valid COBS
+    CobsDecode,
+    /// The frame decoded to zero bytes, so there is no command type
+    EmptyFrame,
+    /// The command type byte is not a known CommandType
+    InvalidCommandType(u8),
+    /// The CRC-16 carried by the frame does not match its contents
+    ChecksumMismatch { expected: u16, actual: u16 },
+    /// The frame ended before a header field it announced
+    Truncated,
+    /// The frame is longer than the receiver's maximum frame size
+    FrameTooLarge,
+    /// The encrypted frame failed authentication, it was tampered with or the key or nonce is wrong
+    AuthenticationFailed,
+    /// The frame's data is compressed but could not be inflated, or the
+    /// `compress` feature is disabled
+    Decompress,
+}
+
+impl core::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ParseError::MissingTerminator => write!(f, "Frame is missing its terminator"),
+            ParseError::CobsDecode => write!(f, "Frame is not valid COBS"),
+            ParseError::EmptyFrame => write!(f, "Frame is empty"),
+            ParseError::InvalidCommandType(byte) => write!(f, "Invalid command type: {}", byte),
+            ParseError::ChecksumMismatch { expected, actual } => {
+                write!(f, "Checksum mismatch: expected {:04x}, got {:04x}", expected, actual)
+            }
+            ParseError::Truncated => write!(f, "Frame is truncated"),
+            ParseError::FrameTooLarge => write!(f, "Frame exceeds the maximum frame size"),
+            ParseError::AuthenticationFailed => write!(f, "Frame failed authentication"),
+            ParseError::Decompress => write!(f, "Frame data could not be decompressed"),
+        }
+    }
+}
+
+impl core::error::Error for ParseError {}
+
+#[cfg(feature = "std")]
+/// Errors that can occur when receiving a Command
+#[derive(Debug)]
+pub enum ReceiveError {
+    /// Nothing was received before the timeout elapsed
+    Timeout,
+    /// A frame was received but could not be parsed
+    Parse(ParseError),
+    /// Reading from the device failed
+    Io(std::io::Error),
+    /// The device was disconnected and could not be reopened, carrying the
+    /// error from the last attempt
+    Disconnected(std::io::Error),
+}
+
+#[cfg(feature = "std")]
+impl core::fmt::Display for ReceiveError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ReceiveError::Timeout => write!(f, "Timed out waiting for a command"),
+            ReceiveError::Parse(e) => write!(f, "Received an invalid frame: {}", e),
+            ReceiveError::Io(e) => write!(f, "IO error while receiving: {}", e),
+            ReceiveError::Disconnected(e) => write!(f, "Device disconnected and could not be reopened: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl core::error::Error for ReceiveError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            ReceiveError::Timeout => None,
+            ReceiveError::Parse(e) => Some(e),
+            ReceiveError::Io(e) | ReceiveError::Disconnected(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<ParseError> for ReceiveError {
+    fn from(error: ParseError) -> ReceiveError {
+        ReceiveError::Parse(error)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for ReceiveError {
+    fn from(error: std::io::Error) -> ReceiveError {
+        ReceiveError::Io(error)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<ReceiveError> for std::io::Error {
+    /// Convert a ReceiveError for callers working in std::io::Result
+    ///
+    /// `Timeout` becomes a `TimedOut` error, `Parse` an `InvalidData` error
+    /// carrying the ParseError and `Disconnected` a `NotConnected` error
+    /// carrying the error from the last reopen attempt.
+    fn from(error: ReceiveError) -> std::io::Error {
+        match error {
+            ReceiveError::Timeout => std::io::ErrorKind::TimedOut.into(),
+            ReceiveError::Parse(e) => invalid_frame(e),
+            ReceiveError::Io(e) => e,
+            ReceiveError::Disconnected(e) => std::io::Error::new(std::io::ErrorKind::NotConnected, e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+/// Wrap a ParseError in an `InvalidData` std::io::Error for the IO-based receive paths
+pub(crate) fn invalid_frame(error: ParseError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+}
+
+impl From<InvalidCommandType> for ParseError {
+    fn from(error: InvalidCommandType) -> ParseError {
+        ParseError::InvalidCommandType(error.0)
+    }
+}
+
+/// Errors returned when a Command's data does not suit its CommandType
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ValidationError {
+    /// The data is not the length this command type requires
+    WrongLength { command_type: CommandType, expected: usize, actual: usize },
+    /// The data of a time-bearing command is not a valid timestamp
+    InvalidTime(TimeDecodeError),
+    /// The data of a PowerDown command is not a known PowerDownReason
+    InvalidPowerDownReason(u8),
+    /// The command is not of the type the data was decoded as
+    WrongCommandType(CommandType),
+    /// The command type byte carried by a Nack is not a valid CommandType
+    InvalidCommandType(u8),
+    /// The filename carried by a command of this type is not UTF-8
+    InvalidUtf8(CommandType),
+    /// The status carried by a StartupCommandAcknowledge is not a known StartupStatus
+    InvalidStartupStatus(u8),
+}
+
+impl core::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ValidationError::WrongLength { command_type, expected, actual } => write!(
+                f,
+                "{} requires {} bytes of data, got {}",
+                command_type, expected, actual
+            ),
+            ValidationError::InvalidTime(e) => write!(f, "Invalid time: {}", e),
+            ValidationError::InvalidPowerDownReason(byte) => write!(f, "Invalid power down reason: {}", byte),
+            ValidationError::WrongCommandType(command_type) => write!(f, "Unexpected command type {}", command_type),
+            ValidationError::InvalidCommandType(byte) => write!(f, "Invalid rejected command type: {}", byte),
+            ValidationError::InvalidUtf8(command_type) => write!(f, "{} filename is not UTF-8", command_type),
+            ValidationError::InvalidStartupStatus(byte) => write!(f, "Invalid startup command status: {}", byte),
+        }
+    }
+}
+
+impl core::error::Error for ValidationError {}
+
+/// Errors returned when a command cannot be fragmented, or a received
+/// Fragment command cannot be reassembled
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FragmentError {
+    /// The fragment's data is shorter than the fragment header
+    Truncated,
+    /// The fragment's index is not below the total number of fragments
+    InvalidIndex { index: u8, total: u8 },
+    /// The command type byte of the fragmented command is not a valid CommandType
+    InvalidCommandType(u8),
+    /// The command needs more fragments than the total byte can count
+    TooManyFragments(usize),
+    /// The maximum payload leaves no room for data after the headers
+    PayloadTooSmall(usize),
+}
+
+impl core::fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FragmentError::Truncated => write!(f, "Fragment is shorter than its header"),
+            FragmentError::InvalidIndex { index, total } => {
+                write!(f, "Fragment index {} is out of range for {} fragments", index, total)
+            }
+            FragmentError::InvalidCommandType(byte) => write!(f, "Invalid fragmented command type: {}", byte),
+            FragmentError::TooManyFragments(count) => write!(f, "Command needs {} fragments, at most 255", count),
+            FragmentError::PayloadTooSmall(max_payload) => {
+                write!(f, "Maximum payload of {} leaves no room for fragment data", max_payload)
+            }
+        }
+    }
+}
+
+impl core::error::Error for FragmentError {}
+
+/// Check that data suits a command type
+///
+/// Time commands carry an 8 byte timestamp, version commands a single
+/// version byte, PowerDown carries an optional PowerDownReason, Initialised
+/// an optional BootStatus, Nack a Rejection, StartupCommandAcknowledge an
+/// optional StartupStatus after its filename, the other simple signals and
+/// acknowledgements carry nothing, and everything else is unconstrained.
+///
+/// # Arguments
+///
+/// * `command_type` - The type of command
+/// * `data` - The data associated with the command
+///
+fn validate_data(command_type: CommandType, data: &[u8]) -> Result<(), ValidationError> {
+    let expected = match command_type {
+        CommandType::Time | CommandType::TimeNanos => 8,
+        CommandType::Version | CommandType::VersionAcknowledge => 1,
+        CommandType::PowerDown => return PowerDownReason::from_data(data).map(|_| ()),
+        CommandType::Initialised => return BootStatus::from_data(data).map(|_| ()),
+        CommandType::Nack => return Rejection::from_data(data).map(|_| ()),
+        CommandType::StartupCommandAcknowledge => return StartupStatus::from_data(data).map(|_| ()),
+        CommandType::TimeAcknowledge
+        | CommandType::InitialisedAcknowledge
+        | CommandType::PowerDownAcknowledge
+        | CommandType::Heartbeat
+        | CommandType::HeartbeatAcknowledge => 0,
+        _ => return Ok(()),
+    };
+    if data.len() != expected {
+        return Err(ValidationError::WrongLength { command_type, expected, actual: data.len() });
+    }
+    // Without chrono the range of a time cannot be checked, only its length
+    #[cfg(feature = "chrono")]
+    if command_type == CommandType::Time {
+        try_bytes_to_datetime(data).map_err(ValidationError::InvalidTime)?;
+    }
+    Ok(())
+}
+
+/// Why the payload is being powered down, carried as the data of a PowerDown command
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[repr(u8)]
+pub enum PowerDownReason {
+    /// No reason given, sent as an empty payload for older receivers
+    Unspecified = 0,
+    /// A planned shutdown
+    Scheduled = 1,
+    /// The payload is too hot or too cold
+    Thermal = 2,
+    /// An operator commanded an emergency shutdown
+    Emergency = 3,
+}
+
+impl PowerDownReason {
+    /// Decode the reason from the data of a PowerDown command
     ///
     /// # Arguments
     ///
-    /// * `command` - The command to send
+    /// * `data` - The command's data, empty or a single reason byte
     ///
     /// # Returns
     ///
-    /// * A new Command containing the command
+    /// * The PowerDownReason, `Unspecified` if `data` is empty
     ///
-    pub fn startup_command(command: Vec<u8>) -> Command {
-        Command::new(CommandType::StartupCommand, command)
+    fn from_data(data: &[u8]) -> Result<PowerDownReason, ValidationError> {
+        match *data {
+            [] => Ok(PowerDownReason::Unspecified),
+            [byte] => PowerDownReason::try_from(byte),
+            _ => Err(ValidationError::WrongLength {
+                command_type: CommandType::PowerDown,
+                expected: 1,
+                actual: data.len(),
+            }),
+        }
     }
+}
 
-    /// Create a new simple command with no data
+impl From<PowerDownReason> for u8 {
+    fn from(reason: PowerDownReason) -> u8 {
+        reason as u8
+    }
+}
+
+impl TryFrom<u8> for PowerDownReason {
+    type Error = ValidationError;
+
+    /// Convert a PowerDown data byte into a `PowerDownReason`
     ///
     /// # Arguments
     ///
-    /// * `command_type` - The type of command
+    /// * `byte` - The byte to convert
     ///
     /// # Returns
     ///
-    /// * A new Command containing the command type and no data
+    /// * The matching PowerDownReason, or `ValidationError::InvalidPowerDownReason` carrying the byte
     ///
-    pub fn simple_command(command_type: CommandType) -> Command {
-        Command::new(command_type, Vec::new())
+    fn try_from(byte: u8) -> Result<PowerDownReason, ValidationError> {
+        match byte {
+            0 => Ok(PowerDownReason::Unspecified),
+            1 => Ok(PowerDownReason::Scheduled),
+            2 => Ok(PowerDownReason::Thermal),
+            3 => Ok(PowerDownReason::Emergency),
+            _ => Err(ValidationError::InvalidPowerDownReason(byte)),
+        }
     }
+}
 
-    /// Convert the command to a Vec<u8> encoded with COBS
+/// What the payload reports about itself when it comes up, carried as the
+/// data of an Initialised command
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BootStatus {
+    /// The payload's firmware version
+    pub version: u32,
+    /// The result of the payload's self-test, as defined by its firmware
+    pub status: u8,
+}
+
+impl BootStatus {
+    /// Number of data bytes in an Initialised command carrying a BootStatus
+    const LEN: usize = 5;
+
+    /// Decode the status from the data of an Initialised command
     ///
-    /// # Returns
+    /// # Arguments
     ///
-    /// * A Vec<u8> containing the command
+    /// * `data` - The data of the command
     ///
-    /// # Panics
+    /// # Returns
     ///
-    /// * If the command type is invalid
+    /// * The BootStatus, or None if `data` is empty as sent by older firmware
     ///
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.push(self.command_type as u8);
-        bytes.extend(self.data.iter());
-
-        // COBS encode ( decode in python with https://github.com/cmcqueen/cobs-python/ )
-        let mut encoded = encode_vec(&bytes);
-        encoded.push(0);  // Add a null byte to the end to indicate end of command
-        encoded
+    fn from_data(data: &[u8]) -> Result<Option<BootStatus>, ValidationError> {
+        match *data {
+            [] => Ok(None),
+            [a, b, c, d, status] => Ok(Some(BootStatus { version: u32::from_be_bytes([a, b, c, d]), status })),
+            _ => Err(ValidationError::WrongLength {
+                command_type: CommandType::Initialised,
+                expected: BootStatus::LEN,
+                actual: data.len(),
+            }),
+        }
     }
+}
 
-    /// Convert a COBS encoded Vec<u8> to a Command
+/// How the payload received a startup command, carried by its
+/// StartupCommandAcknowledge after the echoed filename and a null byte
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum StartupStatus {
+    /// The command arrived, but the payload has not yet decided whether to run it
+    Received,
+    /// The command was accepted and will be run
+    Queued,
+    /// The command will not be run, with a reason code defined by the payload's firmware
+    Rejected(u8),
+}
+
+impl StartupStatus {
+    /// Status byte of a `Received` startup command
+    const RECEIVED: u8 = 0;
+    /// Status byte of a `Queued` startup command
+    const QUEUED: u8 = 1;
+    /// Status byte of a `Rejected` startup command, followed by its reason
+    const REJECTED: u8 = 2;
+
+    /// Split the data of a StartupCommandAcknowledge into its filename and status bytes
     ///
     /// # Arguments
     ///
-    /// * `bytes` - The Vec<u8> to convert
+    /// * `data` - The command's data, the filename optionally followed by a null and the status
     ///
     /// # Returns
     ///
-    /// * A Command containing the data from the bytes
+    /// * The filename, and the status bytes if the acknowledgement carries a status
     ///
-    /// # Panics
+    fn split_data(data: &[u8]) -> (&[u8], Option<&[u8]>) {
+        match data.iter().position(|&byte| byte == 0) {
+            Some(null) => (&data[..null], Some(&data[null + 1..])),
+            None => (data, None),
+        }
+    }
+
+    /// Decode the status from the data of a StartupCommandAcknowledge
     ///
-    /// * If the bytes are not COBS encoded
-    /// * If the command type is invalid
+    /// # Arguments
     ///
-    pub fn from_bytes(bytes: Vec<u8>) -> Option<Command> {
-        if let Some(null_index) = bytes.iter().position(|&x| x == 0) {
-            if let Ok(decoded) = decode_vec(&bytes[0..null_index].to_vec()) {
-                let command_type = decoded[0];
-                let mut data = decoded[1..].to_vec();
-                // data.pop();  // Remove the null byte
-                return Some(Command::new(command_type.into(), data));
-            }
+    /// * `data` - The command's data
+    ///
+    /// # Returns
+    ///
+    /// * The StartupStatus, or None if `data` is only a filename as sent by older firmware
+    ///
+    fn from_data(data: &[u8]) -> Result<Option<StartupStatus>, ValidationError> {
+        let (name, Some(status)) = StartupStatus::split_data(data) else {
+            return Ok(None);
+        };
+        let expected = match *status {
+            [StartupStatus::RECEIVED] => return Ok(Some(StartupStatus::Received)),
+            [StartupStatus::QUEUED] => return Ok(Some(StartupStatus::Queued)),
+            [StartupStatus::REJECTED, reason] => return Ok(Some(StartupStatus::Rejected(reason))),
+            [StartupStatus::REJECTED, ..] => 2,
+            [StartupStatus::RECEIVED | StartupStatus::QUEUED, ..] | [] => 1,
+            [byte, ..] => return Err(ValidationError::InvalidStartupStatus(byte)),
+        };
+        Err(ValidationError::WrongLength {
+            command_type: CommandType::StartupCommandAcknowledge,
+            expected: name.len() + 1 + expected,
+            actual: data.len(),
+        })
+    }
+
+    /// The status bytes following the null in a StartupCommandAcknowledge
+    fn to_data(self) -> Vec<u8> {
+        match self {
+            StartupStatus::Received => vec![StartupStatus::RECEIVED],
+            StartupStatus::Queued => vec![StartupStatus::QUEUED],
+            StartupStatus::Rejected(reason) => vec![StartupStatus::REJECTED, reason],
         }
-        return None;
     }
 }
 
+/// Why the payload rejected a command, carried as the data of a Nack command
+///
+/// Also the error carried by the `std::io::Error` returned when a command
+/// sent with `Transport::send_and_await_ack` is rejected.
+///
+/// # Fields
+///
+/// * `command_type` - The type of the rejected command
+/// * `reason` - The payload's reason code
+///
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Rejection {
+    pub command_type: CommandType,
+    pub reason: u8,
+}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl Rejection {
+    /// Number of data bytes in a Nack command
+    const LEN: usize = 2;
 
-    #[test]
-    fn test_datetime_bytes_encoding() {
-        for offset in [-100, 0, 100].iter() {
-            let time = Utc::now() + chrono::Duration::milliseconds(*offset);
-            let bytes = datetime_to_bytes(time);
-            let decoded_time = bytes_to_datetime(&bytes);
-            assert_eq!(decoded_time.timestamp_millis(), time.timestamp_millis());
+    /// Decode the rejection from the data of a Nack command
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The command's data, the rejected command type byte and the reason code
+    ///
+    fn from_data(data: &[u8]) -> Result<Rejection, ValidationError> {
+        match *data {
+            [command_type, reason] => Ok(Rejection {
+                command_type: CommandType::try_from(command_type)
+                    .map_err(|_| ValidationError::InvalidCommandType(command_type))?,
+                reason,
+            }),
+            _ => Err(ValidationError::WrongLength {
+                command_type: CommandType::Nack,
+                expected: Rejection::LEN,
+                actual: data.len(),
+            }),
         }
     }
+}
 
-    #[test]
-    fn test_command_encoding() {
-        for command_type in [CommandType::Time, CommandType::StartupCommand].iter() {
-            for data in [vec![1, 2, 3], vec![4, 5, 6]].iter() {
-                let command = Command::new(*command_type, data.clone());
-                let bytes = command.to_bytes();
-                let decoded = Command::from_bytes(bytes);
-                assert_eq!(decoded.command_type, *command_type);
-                assert_eq!(decoded.data, *data);
+impl core::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} rejected with reason {}", self.command_type, self.reason)
+    }
+}
+
+impl core::error::Error for Rejection {}
+
+#[cfg(feature = "std")]
+/// Callback reporting file transfer progress, given the bytes transferred so
+/// far and the total size of the file if known
+pub type FtpProgress<'a> = &'a mut dyn FnMut(u64, Option<u64>);
+
+/// Errors that can occur during a file transfer
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub enum FtpError {
+    /// The received file did not match its hash
+    HashMismatch,
+    /// The peer rejected the hash of the file or one of its chunks on every attempt to send it
+    RetriesExhausted {
+        /// The number of times the file or chunk was sent
+        attempts: u32,
+    },
+    /// The peer sent a control message that does not fit the protocol, carrying what was received
+    UnexpectedResponse(Vec<u8>),
+    /// The file name is not one that can be sent or safely created
+    BadFilename,
+    /// The peer stopped responding partway through the transfer
+    Timeout,
+    /// Reading, writing or storing the file failed
+    Io(std::io::Error),
+}
+
+#[cfg(feature = "std")]
+impl core::fmt::Display for FtpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FtpError::HashMismatch => write!(f, "File hash does not match"),
+            FtpError::RetriesExhausted { attempts } => {
+                write!(f, "Peer rejected the file hash on all {} attempts", attempts)
             }
+            FtpError::UnexpectedResponse(message) => {
+                write!(f, "Unexpected response: {:?}", String::from_utf8_lossy(message))
+            }
+            FtpError::BadFilename => write!(f, "Invalid file name"),
+            FtpError::Timeout => write!(f, "Timed out waiting for the peer"),
+            FtpError::Io(e) => write!(f, "IO error during file transfer: {}", e),
         }
     }
+}
 
-    #[test]
-    fn test_time() {
-        for offset in [-100, 0, 100].iter() {
-            let time = Utc::now() + chrono::Duration::milliseconds(*offset);
-            let command = Command::time(time);
-            let bytes = command.to_bytes();
-            let decoded = Command::from_bytes(bytes);
-            assert_eq!(decoded.command_type, CommandType::Time);
-            let decoded_time = bytes_to_datetime(&decoded.data);
-            assert_eq!(decoded_time.timestamp_millis(), time.timestamp_millis());
+#[cfg(feature = "std")]
+impl core::error::Error for FtpError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            FtpError::Io(e) => Some(e),
+            _ => None,
         }
     }
+}
 
-    #[test]
-    fn test_startup_command() {
-        for startup_command in ["patch01.json", "orbit05.json", "asdfGHJK.json"].iter() {
-            let command = Command::startup_command(startup_command.as_bytes().to_vec());
-            let bytes = command.to_bytes();
-            let decoded = Command::from_bytes(bytes);
-            assert_eq!(decoded.command_type, CommandType::StartupCommand);
-            assert_eq!(decoded.data, startup_command.as_bytes());
+#[cfg(feature = "std")]
+impl From<std::io::Error> for FtpError {
+    /// Wrap an IO error, treating a timed out read or write as `FtpError::Timeout`
+    fn from(error: std::io::Error) -> FtpError {
+        match error.kind() {
+            std::io::ErrorKind::TimedOut => FtpError::Timeout,
+            _ => FtpError::Io(error),
         }
     }
+}
+
+#[cfg(feature = "std")]
+impl From<FtpError> for std::io::Error {
+    /// Convert an FtpError for callers working in std::io::Result
+    fn from(error: FtpError) -> std::io::Error {
+        match error {
+            FtpError::Io(e) => e,
+            FtpError::Timeout => std::io::ErrorKind::TimedOut.into(),
+            FtpError::BadFilename => std::io::Error::new(std::io::ErrorKind::InvalidInput, error),
+            e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+/// The step of the payload startup sequence that failed, carrying why
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub enum StartupError {
+    /// Sending the Time command or waiting for its acknowledgement failed
+    Time(std::io::Error),
+    /// Sending the StartupCommand or waiting for its acknowledgement failed
+    StartupCommand(std::io::Error),
+    /// Waiting for the payload to report Initialised failed
+    Initialised(std::io::Error),
+    /// Sending the InitialisedAcknowledge failed
+    InitialisedAcknowledge(std::io::Error),
+}
+
+#[cfg(feature = "std")]
+impl StartupError {
+    /// The underlying IO error
+    pub fn io_error(&self) -> &std::io::Error {
+        match self {
+            StartupError::Time(e)
+            | StartupError::StartupCommand(e)
+            | StartupError::Initialised(e)
+            | StartupError::InitialisedAcknowledge(e) => e,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl core::fmt::Display for StartupError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            StartupError::Time(e) => write!(f, "Failed to set the payload time: {}", e),
+            StartupError::StartupCommand(e) => write!(f, "Failed to send the startup command: {}", e),
+            StartupError::Initialised(e) => write!(f, "Payload did not initialise: {}", e),
+            StartupError::InitialisedAcknowledge(e) => write!(f, "Failed to acknowledge initialisation: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl core::error::Error for StartupError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        Some(self.io_error())
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<StartupError> for std::io::Error {
+    /// Convert a StartupError for callers working in std::io::Result, keeping the kind of the underlying error
+    fn from(error: StartupError) -> std::io::Error {
+        std::io::Error::new(error.io_error().kind(), error)
+    }
+}
+
+/// The side of a `bridge` whose transport failed, carrying why
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub enum BridgeError {
+    /// Receiving from or sending to the ground station failed
+    Ground(std::io::Error),
+    /// Receiving from or sending to the payload failed
+    Payload(std::io::Error),
+}
+
+#[cfg(feature = "std")]
+impl BridgeError {
+    /// The underlying IO error
+    pub fn io_error(&self) -> &std::io::Error {
+        match self {
+            BridgeError::Ground(e) | BridgeError::Payload(e) => e,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl core::fmt::Display for BridgeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BridgeError::Ground(e) => write!(f, "Ground station link failed: {}", e),
+            BridgeError::Payload(e) => write!(f, "Payload link failed: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl core::error::Error for BridgeError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        Some(self.io_error())
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<BridgeError> for std::io::Error {
+    /// Convert a BridgeError for callers working in std::io::Result, keeping the kind of the underlying error
+    fn from(error: BridgeError) -> std::io::Error {
+        std::io::Error::new(error.io_error().kind(), error)
+    }
+}
+
+#[cfg(feature = "std")]
+/// File transfer over a connection, implemented by UartConnection and by
+/// FtpStream for any other Read + Write stream
+pub trait Ftp {
+    /// Receive a file sent by the peer and write it to disk, using the connection's own settings
+    fn ftp(&mut self) -> Result<(), FtpError> {
+        self.ftp_with_progress(&mut |_, _| {})
+    }
+
+    /// Receive a file sent by the peer and write it to disk, reporting progress
+    ///
+    /// # Arguments
+    ///
+    /// * `progress` - Called each time a chunk of the file arrives or is rejected
+    ///
+    fn ftp_with_progress(&mut self, progress: FtpProgress) -> Result<(), FtpError>;
+
+    /// Receive a file sent by the peer using `config` rather than the connection's own settings
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The settings of this transfer
+    ///
+    fn ftp_with_config(&mut self, config: &FtpConfig) -> Result<(), FtpError>;
+
+    /// Send a file to the peer, which must be running `ftp`
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file to send
+    ///
+    fn send_file(&mut self, path: &std::path::Path) -> Result<(), FtpError> {
+        self.send_file_with_progress(path, &mut |_, _| {})
+    }
+
+    /// Send a file to the peer, which must be running `ftp`, reporting progress
+    ///
+    /// A chunk whose hash the peer rejects is resent, and the whole file is
+    /// resent if the peer rejects the file hash, a bounded number of times each.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file to send
+    /// * `progress` - Called each time a chunk of the file is acknowledged or rejected
+    ///
+    /// # Errors
+    ///
+    /// * `FtpError::RetriesExhausted` carrying the number of attempts if the
+    ///   peer kept rejecting a hash
+    ///
+    fn send_file_with_progress(&mut self, path: &std::path::Path, progress: FtpProgress) -> Result<(), FtpError>;
+
+    /// Send a file to the peer using `config` rather than the connection's own settings
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file to send
+    /// * `config` - The settings of this transfer
+    ///
+    fn send_file_with_config(&mut self, path: &std::path::Path, config: &FtpConfig) -> Result<(), FtpError>;
+}
+
+/// Version of the protocol spoken by this crate, exchanged by `UartConnection::handshake`
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Oldest peer protocol version this crate can talk to
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// Set in the command type byte when a sequence number follows it
+///
+/// A frame is `[command_type, data...]`, or `[command_type | SEQUENCE_FLAG, seq, data...]`
+/// when the command carries a sequence number.
+pub const SEQUENCE_FLAG: u8 = 0x80;
+
+/// Set in the command type byte when the data is DEFLATE compressed
+pub const COMPRESSED_FLAG: u8 = 0x40;
+
+/// A command used in communicating with the payload
+///
+/// # Fields
+///
+/// * `command_type` - The type of command
+/// * `data` - The data associated with the command
+/// * `seq` - Optional sequence number, echoed by the acknowledgement
+/// * `address` - Optional address of the device on a multi-drop bus, sent before the command type
+/// * `compress` - Whether to compress the data on the wire when that makes it smaller
+/// * `raw` - The frame the command was parsed from, see `raw_frame`
+///
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Command {
+    pub command_type: CommandType,
+    #[cfg_attr(feature = "serde-base64", serde(with = "base64_data"))]
+    pub data: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    seq: Option<u8>,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    address: Option<u8>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    compress: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    raw: Option<Vec<u8>>,
+}
+
+/// Commands are equal if they carry the same command, however they are encoded
+impl PartialEq for Command {
+    fn eq(&self, other: &Command) -> bool {
+        self.command_type == other.command_type
+            && self.data == other.data
+            && self.seq == other.seq
+            && self.address == other.address
+    }
+}
+
+impl Eq for Command {}
+
+/// Compares only the command type and data, ignoring the sequence number and
+/// address, so a command can be checked in one expression
+impl PartialEq<(CommandType, &[u8])> for Command {
+    fn eq(&self, (command_type, data): &(CommandType, &[u8])) -> bool {
+        self.command_type == *command_type && self.data == *data
+    }
+}
+
+impl TryFrom<&[u8]> for Command {
+    type Error = ParseError;
+
+    /// Decode the COBS frame at the start of `bytes`, as `from_bytes` does
+    /// but without needing an owned Vec
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The encoded frame, anything after its terminator is ignored
+    ///
+    /// # Returns
+    ///
+    /// * A Command containing the data from the bytes
+    ///
+    fn try_from(bytes: &[u8]) -> Result<Command, ParseError> {
+        Command::from_payload(&decode_frame(bytes)?)
+    }
+}
+
+impl TryFrom<Vec<u8>> for Command {
+    type Error = ParseError;
+
+    /// Decode a COBS frame, as `from_bytes` does
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The encoded frame
+    ///
+    /// # Returns
+    ///
+    /// * A Command containing the data from the bytes
+    ///
+    fn try_from(bytes: Vec<u8>) -> Result<Command, ParseError> {
+        Command::from_bytes(bytes)
+    }
+}
+
+/// Serializes Command data as a standard base64 string
+#[cfg(any(feature = "serde-base64", feature = "json"))]
+mod base64_data {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use alloc::{string::String, vec::Vec};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Number of data bytes shown when formatting a Command
+const DATA_PREVIEW_LEN: usize = 16;
+
+/// Formats bytes as space separated hex, truncated to `DATA_PREVIEW_LEN` bytes
+struct HexPreview<'a>(&'a [u8]);
+
+impl core::fmt::Display for HexPreview<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "[")?;
+        for (i, byte) in self.0.iter().take(DATA_PREVIEW_LEN).enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        if self.0.len() > DATA_PREVIEW_LEN {
+            write!(f, " ...")?;
+        }
+        write!(f, "] ({} bytes)", self.0.len())
+    }
+}
+
+impl core::fmt::Debug for HexPreview<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Display::fmt(self, f)
+    }
+}
+
+impl core::fmt::Display for Command {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.command_type)?;
+        if let Some(seq) = self.seq {
+            write!(f, " #{}", seq)?;
+        }
+        write!(f, " {}", HexPreview(&self.data))
+    }
+}
+
+impl core::fmt::Debug for Command {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut debug = f.debug_struct("Command");
+        debug.field("command_type", &self.command_type);
+        debug.field("data", &HexPreview(&self.data));
+        debug.field("checksum", &format_args!("{:04x}", self.checksum()));
+        if let Some(seq) = self.seq {
+            debug.field("seq", &seq);
+        }
+        if let Some(address) = self.address {
+            debug.field("address", &address);
+        }
+        debug.finish()
+    }
+}
+
+/// Byte order of the i64 timestamps carried by time commands, and of length fields
+///
+/// Timestamps are big-endian by default, which is what the Python reference
+/// implementation sends and expects (`struct.pack(">q", millis)`). Little-endian
+/// is for peers whose firmware cannot be changed to match. Length fields are
+/// read and written with `read_len` and `write_len`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum Endianness {
+    /// Most significant byte first
+    #[default]
+    Big,
+    /// Least significant byte first
+    Little,
+}
+
+#[cfg(feature = "chrono")]
+impl Endianness {
+    /// The bytes of `value` in this byte order
+    fn i64_to_bytes(self, value: i64) -> [u8; 8] {
+        match self {
+            Endianness::Big => value.to_be_bytes(),
+            Endianness::Little => value.to_le_bytes(),
+        }
+    }
+
+    /// The value of `bytes` in this byte order
+    fn i64_from_bytes(self, bytes: [u8; 8]) -> i64 {
+        match self {
+            Endianness::Big => i64::from_be_bytes(bytes),
+            Endianness::Little => i64::from_le_bytes(bytes),
+        }
+    }
+}
+
+/// Width of a length field preceding variable-length data on the wire
+///
+/// Length-prefixed frames use a u16, see `Framing::LengthPrefixed`, and file
+/// transfer chunks a u32. Both are big-endian.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LengthWidth {
+    /// Two bytes, lengths up to u16::MAX
+    U16,
+    /// Four bytes, lengths up to u32::MAX
+    U32,
+}
+
+impl LengthWidth {
+    /// Number of bytes in a length field of this width
+    pub const fn size(self) -> usize {
+        match self {
+            LengthWidth::U16 => 2,
+            LengthWidth::U32 => 4,
+        }
+    }
+
+    /// Longest length a field of this width can hold
+    pub const fn max_len(self) -> usize {
+        match self {
+            LengthWidth::U16 => u16::MAX as usize,
+            LengthWidth::U32 => u32::MAX as usize,
+        }
+    }
+}
+
+/// Width of the length field of `Framing::LengthPrefixed` frames
+pub(crate) const FRAME_LENGTH_WIDTH: LengthWidth = LengthWidth::U16;
+
+/// Append a length field
+///
+/// All lengths on the wire are written here so that every peer agrees on
+/// their width and byte order, see `read_len` for the reverse.
+///
+/// # Arguments
+///
+/// * `out` - The buffer to append the length field to
+/// * `len` - The length to write
+/// * `width` - The width of the length field
+/// * `endianness` - The byte order of the length field
+///
+/// # Panics
+///
+/// * If `len` does not fit in `width`
+///
+pub fn write_len(out: &mut Vec<u8>, len: usize, width: LengthWidth, endianness: Endianness) {
+    match width {
+        LengthWidth::U16 => {
+            let len = u16::try_from(len).expect("Length too long for a u16 length field");
+            out.extend_from_slice(&match endianness {
+                Endianness::Big => len.to_be_bytes(),
+                Endianness::Little => len.to_le_bytes(),
+            });
+        }
+        LengthWidth::U32 => {
+            let len = u32::try_from(len).expect("Length too long for a u32 length field");
+            out.extend_from_slice(&match endianness {
+                Endianness::Big => len.to_be_bytes(),
+                Endianness::Little => len.to_le_bytes(),
+            });
+        }
+    }
+}
+
+/// Read the length field at the start of some bytes, as written by `write_len`
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes starting with the length field
+/// * `width` - The width of the length field
+/// * `endianness` - The byte order of the length field
+///
+/// # Returns
+///
+/// * The length, or None if `bytes` is shorter than the length field
+///
+pub fn read_len(bytes: &[u8], width: LengthWidth, endianness: Endianness) -> Option<usize> {
+    match (width, endianness) {
+        (LengthWidth::U16, Endianness::Big) => bytes.first_chunk().map(|&b| usize::from(u16::from_be_bytes(b))),
+        (LengthWidth::U16, Endianness::Little) => bytes.first_chunk().map(|&b| usize::from(u16::from_le_bytes(b))),
+        (LengthWidth::U32, Endianness::Big) => bytes.first_chunk().map(|&b| u32::from_be_bytes(b) as usize),
+        (LengthWidth::U32, Endianness::Little) => bytes.first_chunk().map(|&b| u32::from_le_bytes(b) as usize),
+    }
+}
+
+/// Convert a DateTime<Utc> to a Vec<u8>
+///
+/// # Arguments
+///
+/// * `time` - The DateTime<Utc> to convert
+///
+/// # Returns
+///
+/// * A Vec<u8> containing the milliseconds since the epoch as a big-endian i64
+///
+#[cfg(feature = "chrono")]
+pub fn datetime_to_bytes(time: DateTime<Utc>) -> Vec<u8> {
+    datetime_to_bytes_with(time, Endianness::Big)
+}
+
+/// Convert a DateTime<Utc> to a Vec<u8> in the given byte order
+///
+/// # Arguments
+///
+/// * `time` - The DateTime<Utc> to convert
+/// * `endianness` - The byte order the peer expects
+///
+/// # Returns
+///
+/// * A Vec<u8> containing the milliseconds since the epoch as an i64
+///
+#[cfg(feature = "chrono")]
+pub fn datetime_to_bytes_with(time: DateTime<Utc>, endianness: Endianness) -> Vec<u8> {
+    endianness.i64_to_bytes(time.timestamp_millis()).to_vec()
+}
+
+/// Convert a DateTime<Utc> to a Vec<u8> with nanosecond precision
+///
+/// # Arguments
+///
+/// * `time` - The DateTime<Utc> to convert
+///
+/// # Returns
+///
+/// * A Vec<u8> containing the nanoseconds since the epoch as a big-endian i64
+///
+/// # Panics
+///
+/// * If `time` is not between 1677-09-21 and 2262-04-11, the range of i64 nanoseconds
+///
+#[cfg(feature = "chrono")]
+pub fn datetime_to_bytes_nanos(time: DateTime<Utc>) -> Vec<u8> {
+    try_datetime_to_bytes_nanos(time).unwrap()
+}
+
+/// Convert a DateTime<Utc> to a Vec<u8> with nanosecond precision without panicking
+///
+/// # Arguments
+///
+/// * `time` - The DateTime<Utc> to convert
+///
+/// # Returns
+///
+/// * A Vec<u8> containing the nanoseconds since the epoch as a big-endian i64
+///
+/// # Errors
+///
+/// * `TimeDecodeError::OutOfRange` carrying the time in milliseconds if it is
+///   not between 1677-09-21 and 2262-04-11, the range of i64 nanoseconds
+///
+#[cfg(feature = "chrono")]
+pub fn try_datetime_to_bytes_nanos(time: DateTime<Utc>) -> Result<Vec<u8>, TimeDecodeError> {
+    let nanos = time.timestamp_nanos_opt().ok_or(TimeDecodeError::OutOfRange(time.timestamp_millis()))?;
+    Ok(nanos.to_be_bytes().to_vec())
+}
+
+/// Errors that can occur when decoding a DateTime<Utc> from bytes
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TimeDecodeError {
+    /// Fewer than 8 bytes were provided
+    TooShort(usize),
+    /// The timestamp does not correspond to a representable DateTime<Utc>
+    OutOfRange(i64),
+    /// The command type does not carry a time
+    NotTimeCommand(CommandType),
+}
+
+impl core::fmt::Display for TimeDecodeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TimeDecodeError::TooShort(len) => write!(f, "Expected 8 time bytes, got {}", len),
+            TimeDecodeError::OutOfRange(millis) => write!(f, "Timestamp out of range: {}", millis),
+            TimeDecodeError::NotTimeCommand(command_type) => write!(f, "{} does not carry a time", command_type),
+        }
+    }
+}
+
+impl core::error::Error for TimeDecodeError {}
+
+/// Convert a Vec<u8> to a DateTime<Utc> without panicking
+///
+/// # Arguments
+///
+/// * `bytes` - The Vec<u8> to convert
+///
+/// # Returns
+///
+/// * A DateTime<Utc> containing the date and time of the bytes
+///
+/// # Errors
+///
+/// * `TimeDecodeError::TooShort` if there are fewer than 8 bytes
+/// * `TimeDecodeError::OutOfRange` if the bytes cannot be converted to a DateTime<Utc>
+///
+#[cfg(feature = "chrono")]
+pub fn try_bytes_to_datetime(bytes: &[u8]) -> Result<DateTime<Utc>, TimeDecodeError> {
+    try_bytes_to_datetime_with(bytes, Endianness::Big)
+}
+
+/// Convert bytes in the given byte order to a DateTime<Utc> without panicking
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes to convert
+/// * `endianness` - The byte order the peer sent
+///
+/// # Returns
+///
+/// * A DateTime<Utc> containing the date and time of the bytes
+///
+/// # Errors
+///
+/// * `TimeDecodeError::TooShort` if there are fewer than 8 bytes
+/// * `TimeDecodeError::OutOfRange` if the bytes cannot be converted to a DateTime<Utc>
+///
+#[cfg(feature = "chrono")]
+pub fn try_bytes_to_datetime_with(bytes: &[u8], endianness: Endianness) -> Result<DateTime<Utc>, TimeDecodeError> {
+    let time_bytes: [u8; 8] = bytes
+        .get(..8)
+        .and_then(|b| b.try_into().ok())
+        .ok_or(TimeDecodeError::TooShort(bytes.len()))?;
+    let time = endianness.i64_from_bytes(time_bytes);
+    Utc.timestamp_millis_opt(time)
+        .single()
+        .ok_or(TimeDecodeError::OutOfRange(time))
+}
+
+/// Convert bytes written by `datetime_to_bytes_nanos` to a DateTime<Utc> without panicking
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes to convert
+///
+/// # Returns
+///
+/// * A DateTime<Utc> containing the date and time of the bytes
+///
+/// # Errors
+///
+/// * `TimeDecodeError::TooShort` if there are fewer than 8 bytes
+///
+#[cfg(feature = "chrono")]
+pub fn try_bytes_to_datetime_nanos(bytes: &[u8]) -> Result<DateTime<Utc>, TimeDecodeError> {
+    let time_bytes: [u8; 8] = bytes
+        .get(..8)
+        .and_then(|b| b.try_into().ok())
+        .ok_or(TimeDecodeError::TooShort(bytes.len()))?;
+    Ok(Utc.timestamp_nanos(i64::from_be_bytes(time_bytes)))
+}
+
+/// Convert bytes written by `datetime_to_bytes_nanos` to a DateTime<Utc>
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes to convert
+///
+/// # Returns
+///
+/// * A DateTime<Utc> containing the date and time of the bytes
+///
+/// # Panics
+///
+/// * If there are fewer than 8 bytes
+///
+#[cfg(feature = "chrono")]
+pub fn bytes_to_datetime_nanos(bytes: &[u8]) -> DateTime<Utc> {
+    try_bytes_to_datetime_nanos(bytes).unwrap()
+}
+
+/// Convert a Vec<u8> to a DateTime<Utc>
+///
+/// # Arguments
+///
+/// * `bytes` - The Vec<u8> to convert
+///
+/// # Returns
+///
+/// * A DateTime<Utc> containing the date and time of the bytes
+///
+/// # Panics
+///
+/// * If the bytes are not the correct length
+/// * If the bytes cannot be converted to a DateTime<Utc>
+///
+#[cfg(feature = "chrono")]
+pub fn bytes_to_datetime(bytes: &[u8]) -> DateTime<Utc> {
+    try_bytes_to_datetime(bytes).unwrap()
+}
+
+
+impl Command {
+    /// Create a new command
+    ///
+    /// # Arguments
+    ///
+    /// * `command_type` - The type of command
+    /// * `data` - The data associated with the command
+    ///
+    /// # Returns
+    ///
+    /// * A new Command
+    ///
+    pub fn new(command_type: CommandType, data: Vec<u8>) -> Command {
+        Command {
+            command_type,
+            data,
+            seq: None,
+            address: None,
+            compress: false,
+            raw: None,
+        }
+    }
+
+    /// Create a new command, checking the data suits the command type
+    ///
+    /// # Arguments
+    ///
+    /// * `command_type` - The type of command
+    /// * `data` - The data associated with the command
+    ///
+    /// # Returns
+    ///
+    /// * A new Command
+    ///
+    /// # Errors
+    ///
+    /// * `ValidationError::WrongLength` if the data is the wrong length for the command type
+    /// * `ValidationError::InvalidTime` if a time command's data is not a valid timestamp
+    ///
+    pub fn new_validated(command_type: CommandType, data: Vec<u8>) -> Result<Command, ValidationError> {
+        validate_data(command_type, &data)?;
+        Ok(Command::new(command_type, data))
+    }
+
+    /// Check the data suits the command type, by the same rules as `new_validated`
+    ///
+    /// Parsing accepts any data, so a received command can be checked here
+    /// before it is acted on.
+    ///
+    /// # Errors
+    ///
+    /// * `ValidationError::WrongLength` if the data is the wrong length for the command type
+    /// * `ValidationError::InvalidTime` if a time command's data is not a valid timestamp
+    /// * `ValidationError::InvalidPowerDownReason` if a PowerDown's reason is not known
+    /// * `ValidationError::InvalidStartupStatus` if a StartupCommandAcknowledge's status is not known
+    ///
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        validate_data(self.command_type, &self.data)
+    }
+
+    /// Whether the data suits the command type, see `validate`
+    pub fn is_valid(&self) -> bool {
+        self.validate().is_ok()
+    }
+
+    /// Create a new command carrying a sequence number
+    ///
+    /// # Arguments
+    ///
+    /// * `command_type` - The type of command
+    /// * `data` - The data associated with the command
+    /// * `seq` - The sequence number, which the acknowledgement will echo
+    ///
+    /// # Returns
+    ///
+    /// * A new Command
+    ///
+    pub fn new_with_seq(command_type: CommandType, data: Vec<u8>, seq: u8) -> Command {
+        Command {
+            command_type,
+            data,
+            seq: Some(seq),
+            address: None,
+            compress: false,
+            raw: None,
+        }
+    }
+
+    /// The sequence number of the command, if it has one
+    pub fn seq(&self) -> Option<u8> {
+        self.seq
+    }
+
+    /// Create a new command addressed to one device on a multi-drop bus
+    ///
+    /// The address is sent as the first byte of the frame, before the command
+    /// type, so the receiver must be expecting addressed frames, see
+    /// `from_bytes_addressed`.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The address of the device the command is for
+    /// * `command_type` - The type of command
+    /// * `data` - The data associated with the command
+    ///
+    /// # Returns
+    ///
+    /// * A new Command
+    ///
+    pub fn new_addressed(address: u8, command_type: CommandType, data: Vec<u8>) -> Command {
+        Command {
+            address: Some(address),
+            ..Command::new(command_type, data)
+        }
+    }
+
+    /// The frame the command was parsed from, exactly as received
+    ///
+    /// Kept by the `from_bytes` family of parsers, including the frames
+    /// returned by `UartConnection::receive_message`, so a relay can forward
+    /// the frame unchanged rather than re-encoding it. Commands that were
+    /// built rather than parsed have none, and the frame is not updated if
+    /// the command's fields are changed after parsing.
+    ///
+    /// # Returns
+    ///
+    /// * The received frame, including its terminator or length prefix, or
+    ///   None if the command was not parsed from a frame
+    ///
+    pub fn raw_frame(&self) -> Option<&[u8]> {
+        self.raw.as_deref()
+    }
+
+    /// The address of the device the command is for, if it has one
+    pub fn address(&self) -> Option<u8> {
+        self.address
+    }
+
+    /// The byte identifying the command's type on the wire
+    ///
+    /// This is the type byte without the sequence and compression flags that
+    /// `to_bytes` may set, so `CommandType::try_from(command.kind_byte())` is
+    /// always `Ok(command.command_type)`.
+    ///
+    /// # Returns
+    ///
+    /// * The command type byte
+    ///
+    pub fn kind_byte(&self) -> u8 {
+        u8::from(self.command_type)
+    }
+
+    /// Decode the time carried by a Time or TimeNanos command
+    ///
+    /// TimeAcknowledge carries no data, as `validate` requires, so it is not a
+    /// time command.
+    ///
+    /// # Returns
+    ///
+    /// * The DateTime<Utc> in the command's data
+    ///
+    /// # Errors
+    ///
+    /// * `TimeDecodeError::NotTimeCommand` if the command type does not carry a time
+    /// * `TimeDecodeError::TooShort` or `TimeDecodeError::OutOfRange` if the data is malformed
+    ///
+    #[cfg(feature = "chrono")]
+    pub fn data_as_datetime(&self) -> Result<DateTime<Utc>, TimeDecodeError> {
+        match self.command_type {
+            CommandType::Time => try_bytes_to_datetime(&self.data),
+            CommandType::TimeNanos => try_bytes_to_datetime_nanos(&self.data),
+            command_type => Err(TimeDecodeError::NotTimeCommand(command_type)),
+        }
+    }
+
+    /// Create a new time command
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - The time to send
+    ///
+    /// # Returns
+    ///
+    /// * A new Command containing the time
+    ///
+    #[cfg(feature = "chrono")]
+    pub fn time(time: DateTime<Utc>) -> Command {
+        Command::new(CommandType::Time, datetime_to_bytes(time))
+    }
+
+    /// Create a new time command for a peer expecting the given byte order
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - The time to send
+    /// * `endianness` - The byte order the peer expects
+    ///
+    /// # Returns
+    ///
+    /// * A new Command containing the time
+    ///
+    #[cfg(feature = "chrono")]
+    pub fn time_with(time: DateTime<Utc>, endianness: Endianness) -> Command {
+        Command::new(CommandType::Time, datetime_to_bytes_with(time, endianness))
+    }
+
+    /// Create a new time command with nanosecond precision
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - The time to send
+    ///
+    /// # Returns
+    ///
+    /// * A new TimeNanos Command containing the time
+    ///
+    /// # Panics
+    ///
+    /// * If `time` is out of range for `datetime_to_bytes_nanos`
+    ///
+    #[cfg(feature = "chrono")]
+    pub fn time_nanos(time: DateTime<Utc>) -> Command {
+        Command::try_time_nanos(time).unwrap()
+    }
+
+    /// Create a new time command with nanosecond precision without panicking
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - The time to send
+    ///
+    /// # Returns
+    ///
+    /// * A new TimeNanos Command containing the time
+    ///
+    /// # Errors
+    ///
+    /// * `ValidationError::InvalidTime` if `time` is out of range for `try_datetime_to_bytes_nanos`
+    ///
+    #[cfg(feature = "chrono")]
+    pub fn try_time_nanos(time: DateTime<Utc>) -> Result<Command, ValidationError> {
+        let data = try_datetime_to_bytes_nanos(time).map_err(ValidationError::InvalidTime)?;
+        Ok(Command::new(CommandType::TimeNanos, data))
+    }
+
+    /// Create a new time command from milliseconds since the Unix epoch, without chrono
+    ///
+    /// The data is the same as `Command::time` sends for that instant.
+    ///
+    /// # Arguments
+    ///
+    /// * `millis` - Milliseconds since 1970-01-01T00:00:00Z, negative before it
+    ///
+    /// # Returns
+    ///
+    /// * A new Command containing the time
+    ///
+    pub fn time_from_millis(millis: i64) -> Command {
+        Command::new(CommandType::Time, millis.to_be_bytes().to_vec())
+    }
+
+    /// Create a new time command from a SystemTime, without chrono
+    ///
+    /// Like `Command::time`, the time is rounded down to the millisecond.
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - The time to send, e.g. `SystemTime::now()`
+    ///
+    /// # Returns
+    ///
+    /// * A new Command containing the time
+    ///
+    /// # Panics
+    ///
+    /// * If `time` is more than 292 million years from the Unix epoch
+    ///
+    #[cfg(feature = "std")]
+    pub fn time_system(time: std::time::SystemTime) -> Command {
+        let millis = match time.duration_since(std::time::UNIX_EPOCH) {
+            Ok(since) => i64::try_from(since.as_millis()),
+            // Round times before the epoch down too, away from zero
+            Err(e) => i64::try_from(e.duration().as_nanos().div_ceil(1_000_000)).map(|millis| -millis),
+        };
+        Command::time_from_millis(millis.expect("SystemTime out of range for millisecond timestamps"))
+    }
+
+    /// Create a new time command carrying the current time
+    ///
+    /// The same as `Command::time(Utc::now())`, but also available without chrono.
+    ///
+    /// # Returns
+    ///
+    /// * A new Command containing the current time
+    ///
+    #[cfg(feature = "std")]
+    pub fn time_now() -> Command {
+        Command::time_system(std::time::SystemTime::now())
+    }
+
+    /// Create a new startup command
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to send
+    ///
+    /// # Returns
+    ///
+    /// * A new Command containing the command
+    ///
+    pub fn startup_command(command: Vec<u8>) -> Command {
+        Command::new(CommandType::StartupCommand, command)
+    }
+
+    /// Create a new startup command acknowledgement echoing the accepted filename
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The filename of the startup command being acknowledged
+    ///
+    /// # Returns
+    ///
+    /// * A new StartupCommandAcknowledge Command containing the filename
+    ///
+    pub fn startup_command_ack(name: &str) -> Command {
+        Command::new(CommandType::StartupCommandAcknowledge, name.as_bytes().to_vec())
+    }
+
+    /// Create a new startup command acknowledgement reporting what will become of the command
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The filename of the startup command being acknowledged
+    /// * `status` - Whether the command was received, queued or rejected
+    ///
+    /// # Returns
+    ///
+    /// * A new StartupCommandAcknowledge Command containing the filename and status
+    ///
+    pub fn startup_command_ack_with_status(name: &str, status: StartupStatus) -> Command {
+        let mut data = name.as_bytes().to_vec();
+        data.push(0);
+        data.extend(status.to_data());
+        Command::new(CommandType::StartupCommandAcknowledge, data)
+    }
+
+    /// Decode the StartupStatus carried by a StartupCommandAcknowledge
+    ///
+    /// # Returns
+    ///
+    /// * The StartupStatus, or None if the command carries no status as sent by older firmware
+    ///
+    /// # Errors
+    ///
+    /// * `ValidationError::WrongCommandType` if this is not a StartupCommandAcknowledge
+    /// * `ValidationError::WrongLength` or `ValidationError::InvalidStartupStatus` if the status is malformed
+    ///
+    pub fn startup_status(&self) -> Result<Option<StartupStatus>, ValidationError> {
+        if self.command_type != CommandType::StartupCommandAcknowledge {
+            return Err(ValidationError::WrongCommandType(self.command_type));
+        }
+        StartupStatus::from_data(&self.data)
+    }
+
+    /// Whether this command is the acknowledgement of `command`
+    ///
+    /// The acknowledgement must be of the command's expected ack type, echo
+    /// its sequence number if it has one, and if it is a startup command
+    /// acknowledgement carrying a filename, echo the command's filename. Any
+    /// StartupStatus is not considered, see `startup_status`.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command that was sent
+    ///
+    /// # Returns
+    ///
+    /// * Whether this command acknowledges `command`
+    ///
+    pub fn acknowledges(&self, command: &Command) -> bool {
+        command.command_type.expected_ack() == Some(self.command_type)
+            && (command.seq.is_none() || self.seq == command.seq)
+            && (self.command_type != CommandType::StartupCommandAcknowledge || {
+                let (name, _) = StartupStatus::split_data(&self.data);
+                name.is_empty() || name == command.data
+            })
+    }
+
+    /// Create the acknowledgement of a received command
+    ///
+    /// The acknowledgement echoes the command's sequence number, a startup
+    /// command's filename, and a VersionAcknowledge carries `PROTOCOL_VERSION`.
+    /// Other acknowledgements are empty.
+    ///
+    /// # Arguments
+    ///
+    /// * `received` - The command to acknowledge
+    ///
+    /// # Returns
+    ///
+    /// * The acknowledgement, which `acknowledges` `received`, or None if the
+    ///   command type is not acknowledged
+    ///
+    pub fn ack_for(received: &Command) -> Option<Command> {
+        let ack_type = received.command_type.expected_ack()?;
+        let data = match received.command_type {
+            CommandType::StartupCommand => received.data.clone(),
+            CommandType::Version => vec![PROTOCOL_VERSION],
+            _ => Vec::new(),
+        };
+        Some(Command {
+            command_type: ack_type,
+            data,
+            seq: received.seq,
+            address: None,
+            compress: false,
+            raw: None,
+        })
+    }
+
+    /// Create a new startup command whose data is DEFLATE compressed on the wire
+    ///
+    /// The data is only compressed if that makes it smaller, otherwise it is
+    /// sent as is. Receivers inflate it transparently.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to send
+    ///
+    /// # Returns
+    ///
+    /// * A new Command containing the command
+    ///
+    #[cfg(feature = "compress")]
+    pub fn startup_command_compressed(command: Vec<u8>) -> Command {
+        let mut command = Command::startup_command(command);
+        command.compress = true;
+        command
+    }
+
+    /// Whether the data is compressed on the wire when that makes it smaller
+    pub fn is_compressed(&self) -> bool {
+        self.compress
+    }
+
+    /// The command's data as a UTF-8 string, such as a startup command's filename
+    ///
+    /// # Returns
+    ///
+    /// * The data as a &str
+    ///
+    /// # Errors
+    ///
+    /// * If the data is not valid UTF-8
+    ///
+    pub fn data_as_str(&self) -> Result<&str, core::str::Utf8Error> {
+        core::str::from_utf8(&self.data)
+    }
+
+    /// Create a new PowerDown command carrying a reason
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - Why the payload is being powered down
+    ///
+    /// # Returns
+    ///
+    /// * A new PowerDown Command, with no data if the reason is `Unspecified`
+    ///
+    pub fn power_down(reason: PowerDownReason) -> Command {
+        match reason {
+            PowerDownReason::Unspecified => Command::simple_command(CommandType::PowerDown),
+            reason => Command::new(CommandType::PowerDown, vec![reason.into()]),
+        }
+    }
+
+    /// Decode the reason carried by a PowerDown command
+    ///
+    /// # Returns
+    ///
+    /// * The PowerDownReason, `Unspecified` if the command has no data
+    ///
+    /// # Errors
+    ///
+    /// * `ValidationError::WrongCommandType` if this is not a PowerDown command
+    /// * `ValidationError::WrongLength` or `ValidationError::InvalidPowerDownReason` if the data is malformed
+    ///
+    pub fn power_down_reason(&self) -> Result<PowerDownReason, ValidationError> {
+        if self.command_type != CommandType::PowerDown {
+            return Err(ValidationError::WrongCommandType(self.command_type));
+        }
+        PowerDownReason::from_data(&self.data)
+    }
+
+    /// Create a new Initialised command reporting the payload's firmware version and self-test result
+    ///
+    /// # Arguments
+    ///
+    /// * `version` - The payload's firmware version
+    /// * `status` - The result of the payload's self-test
+    ///
+    /// # Returns
+    ///
+    /// * A new Initialised Command carrying the BootStatus
+    ///
+    pub fn initialised(version: u32, status: u8) -> Command {
+        let mut data = Vec::with_capacity(BootStatus::LEN);
+        data.extend_from_slice(&version.to_be_bytes());
+        data.push(status);
+        Command::new(CommandType::Initialised, data)
+    }
+
+    /// Decode the BootStatus carried by an Initialised command
+    ///
+    /// # Returns
+    ///
+    /// * The BootStatus, or None if the command has no data as sent by older firmware
+    ///
+    /// # Errors
+    ///
+    /// * `ValidationError::WrongCommandType` if this is not an Initialised command
+    /// * `ValidationError::WrongLength` if the data is malformed
+    ///
+    pub fn boot_status(&self) -> Result<Option<BootStatus>, ValidationError> {
+        if self.command_type != CommandType::Initialised {
+            return Err(ValidationError::WrongCommandType(self.command_type));
+        }
+        BootStatus::from_data(&self.data)
+    }
+
+    /// Create a new Nack command rejecting a command
+    ///
+    /// # Arguments
+    ///
+    /// * `rejected` - The type of the rejected command
+    /// * `reason` - Why the command was rejected
+    ///
+    /// # Returns
+    ///
+    /// * A new Nack Command carrying the Rejection
+    ///
+    pub fn nack(rejected: CommandType, reason: u8) -> Command {
+        Command::new(CommandType::Nack, vec![u8::from(rejected), reason])
+    }
+
+    /// Create the Nack rejecting a received command, echoing its sequence number
+    ///
+    /// # Arguments
+    ///
+    /// * `received` - The command to reject
+    /// * `reason` - Why the command was rejected
+    ///
+    /// # Returns
+    ///
+    /// * The Nack, which `rejects` `received`
+    ///
+    pub fn nack_for(received: &Command, reason: u8) -> Command {
+        Command {
+            seq: received.seq,
+            ..Command::nack(received.command_type, reason)
+        }
+    }
+
+    /// Decode the Rejection carried by a Nack command
+    ///
+    /// # Returns
+    ///
+    /// * The Rejection
+    ///
+    /// # Errors
+    ///
+    /// * `ValidationError::WrongCommandType` if this is not a Nack command
+    /// * `ValidationError::WrongLength` or `ValidationError::InvalidCommandType` if the data is malformed
+    ///
+    pub fn rejection(&self) -> Result<Rejection, ValidationError> {
+        if self.command_type != CommandType::Nack {
+            return Err(ValidationError::WrongCommandType(self.command_type));
+        }
+        Rejection::from_data(&self.data)
+    }
+
+    /// Whether this command is a Nack rejecting `command`
+    ///
+    /// The Nack must carry the command's type and echo its sequence number if
+    /// it has one, as `acknowledges` requires of an acknowledgement.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command that was sent
+    ///
+    /// # Returns
+    ///
+    /// * Whether this command rejects `command`
+    ///
+    pub fn rejects(&self, command: &Command) -> bool {
+        self.rejection().is_ok_and(|rejection| rejection.command_type == command.command_type)
+            && (command.seq.is_none() || self.seq == command.seq)
+    }
+
+    /// Create a new simple command with no data
+    ///
+    /// # Arguments
+    ///
+    /// * `command_type` - The type of command
+    ///
+    /// # Returns
+    ///
+    /// * A new Command containing the command type and no data
+    ///
+    pub fn simple_command(command_type: CommandType) -> Command {
+        Command::new(command_type, Vec::new())
+    }
+
+    /// Convert the command to a Vec<u8> encoded with COBS
+    ///
+    /// # Returns
+    ///
+    /// * A Vec<u8> containing the command
+    ///
+    /// # Panics
+    ///
+    /// * If the command type is invalid
+    ///
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode_frame(&self.payload())
+    }
+
+    /// A CRC-16 of the command type and data, for comparing commands by eye in logs
+    ///
+    /// This is the CRC-16/CCITT-FALSE of `[command_type, data...]`, which is
+    /// `binascii.crc_hqx(bytes([command_type]) + data, 0xFFFF)` in Python. The
+    /// sequence number and frame flags are not included, and nothing about it
+    /// is sent on the wire.
+    ///
+    /// # Returns
+    ///
+    /// * The checksum
+    ///
+    pub fn checksum(&self) -> u16 {
+        crc16_update(crc16(&[u8::from(self.command_type)]), &self.data)
+    }
+
+    /// The number of bytes `to_bytes` produces for the command, without encoding it
+    ///
+    /// Nothing is allocated unless the command is to be compressed, in which
+    /// case the data is compressed to measure it.
+    ///
+    /// # Returns
+    ///
+    /// * The length of the COBS encoded frame, including its terminator
+    ///
+    pub fn frame_len(&self) -> usize {
+        let compressed = self.compressed_data();
+        let data = compressed.as_deref().unwrap_or(&self.data);
+        cobs_encoded_len(self.header(compressed.is_some()).chain(data.iter().copied())) + 1
+    }
+
+    /// Encode the command with COBS into an existing buffer
+    ///
+    /// The buffer's contents are replaced by the frame, and its allocation is
+    /// reused so repeated sends need not allocate for the encoded frame.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - The buffer to write the encoded frame into
+    ///
+    pub fn to_bytes_into(&self, buf: &mut Vec<u8>) {
+        encode_frame_into(&self.payload(), buf);
+    }
+
+    /// Convert the command to a Vec<u8> framed for the wire
+    ///
+    /// # Arguments
+    ///
+    /// * `framing` - How to delimit the command
+    ///
+    /// # Returns
+    ///
+    /// * A Vec<u8> containing the command
+    ///
+    /// # Panics
+    ///
+    /// * If `framing` is `LengthPrefixed` and the command does not fit in a u16 length,
+    ///   see `try_to_bytes_with`
+    ///
+    pub fn to_bytes_with(&self, framing: Framing) -> Vec<u8> {
+        self.try_to_bytes_with(framing)
+            .expect("Command too long for a length-prefixed frame")
+    }
+
+    /// Convert the command to a Vec<u8> framed for the wire, if it fits the framing
+    ///
+    /// # Arguments
+    ///
+    /// * `framing` - How to delimit the command
+    ///
+    /// # Returns
+    ///
+    /// * A Vec<u8> containing the command, or None if `framing` is
+    ///   `LengthPrefixed` and the command does not fit in a u16 length
+    ///
+    pub fn try_to_bytes_with(&self, framing: Framing) -> Option<Vec<u8>> {
+        match framing {
+            Framing::Cobs => Some(self.to_bytes()),
+            Framing::LengthPrefixed => encode_length_prefixed(&self.payload()),
+            Framing::CobsTerminated(terminator) => Some(encode_frame_terminated(&self.payload(), terminator)),
+        }
+    }
+
+    /// Convert the command to a Vec<u8> encoded with COBS, with a CRC-16 of the
+    /// command type and data appended before encoding
+    ///
+    /// # Returns
+    ///
+    /// * A Vec<u8> containing the command and its checksum
+    ///
+    pub fn to_bytes_checked(&self) -> Vec<u8> {
+        let mut payload = self.payload();
+        let crc = crc16(&payload);
+        payload.extend_from_slice(&crc.to_be_bytes());
+        encode_frame(&payload)
+    }
+
+    /// Convert a COBS encoded Vec<u8> to a Command
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The Vec<u8> to convert
+    ///
+    /// # Returns
+    ///
+    /// * A Command containing the data from the bytes
+    ///
+    /// # Errors
+    ///
+    /// * `ParseError::MissingTerminator` if there is no null byte in the bytes
+    /// * `ParseError::CobsDecode` if the bytes are not COBS encoded
+    /// * `ParseError::EmptyFrame` if the frame decodes to nothing
+    /// * `ParseError::InvalidCommandType` if the command type is invalid
+    ///
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Command, ParseError> {
+        Ok(Command::try_from(bytes.as_slice())?.with_raw(bytes))
+    }
+
+    /// Convert a Vec<u8> produced by `to_bytes_with` to a Command
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The Vec<u8> to convert
+    /// * `framing` - How the command is delimited
+    ///
+    /// # Returns
+    ///
+    /// * A Command containing the data from the bytes
+    ///
+    /// # Errors
+    ///
+    /// * As `from_bytes` for `Framing::Cobs` and `Framing::CobsTerminated`
+    /// * `ParseError::Truncated` if a length-prefixed frame is shorter than its length
+    /// * `ParseError::EmptyFrame` if a length-prefixed frame has a length of zero
+    ///
+    pub fn from_bytes_with(bytes: Vec<u8>, framing: Framing) -> Result<Command, ParseError> {
+        match framing {
+            Framing::Cobs => Command::from_bytes(bytes),
+            Framing::LengthPrefixed => Ok(Command::from_payload(decode_length_prefixed(&bytes)?)?.with_raw(bytes)),
+            Framing::CobsTerminated(terminator) => {
+                Ok(Command::from_payload(&decode_frame_terminated(&bytes, terminator)?)?.with_raw(bytes))
+            }
+        }
+    }
+
+    /// Convert a COBS encoded Vec<u8> whose first byte is an address to a Command
+    ///
+    /// Every frame on a bus shared by addressed devices carries an address,
+    /// see `new_addressed`.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The Vec<u8> to convert
+    ///
+    /// # Returns
+    ///
+    /// * A Command containing the address and data from the bytes
+    ///
+    /// # Errors
+    ///
+    /// * As `from_bytes`, with `ParseError::EmptyFrame` if the frame holds only an address
+    ///
+    pub fn from_bytes_addressed(bytes: Vec<u8>) -> Result<Command, ParseError> {
+        Command::from_bytes_addressed_with(bytes, Framing::Cobs)
+    }
+
+    /// Convert a Vec<u8> produced by `to_bytes_with` for an addressed command to a Command
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The Vec<u8> to convert
+    /// * `framing` - How the command is delimited
+    ///
+    /// # Returns
+    ///
+    /// * A Command containing the address and data from the bytes
+    ///
+    /// # Errors
+    ///
+    /// * As `from_bytes_with`, with `ParseError::EmptyFrame` if the frame holds only an address
+    ///
+    pub fn from_bytes_addressed_with(bytes: Vec<u8>, framing: Framing) -> Result<Command, ParseError> {
+        let payload = match framing {
+            Framing::Cobs => decode_frame(&bytes)?,
+            Framing::LengthPrefixed => decode_length_prefixed(&bytes)?.to_vec(),
+            Framing::CobsTerminated(terminator) => decode_frame_terminated(&bytes, terminator)?,
+        };
+        let (&address, payload) = payload.split_first().ok_or(ParseError::EmptyFrame)?;
+        let mut command = Command::from_payload(payload)?;
+        command.address = Some(address);
+        Ok(command.with_raw(bytes))
+    }
+
+    /// Convert a COBS encoded Vec<u8> produced by `to_bytes_checked` to a Command
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The Vec<u8> to convert
+    ///
+    /// # Returns
+    ///
+    /// * A Command containing the data from the bytes
+    ///
+    /// # Errors
+    ///
+    /// * `ParseError::ChecksumMismatch` if the CRC-16 does not match the contents
+    /// * Any of the errors returned by `from_bytes`
+    ///
+    pub fn from_bytes_checked(bytes: Vec<u8>) -> Result<Command, ParseError> {
+        let decoded = decode_frame(&bytes)?;
+        if decoded.len() < 2 {
+            return Err(ParseError::EmptyFrame);
+        }
+        let (payload, crc) = decoded.split_at(decoded.len() - 2);
+        let expected = u16::from_be_bytes([crc[0], crc[1]]);
+        let actual = crc16(payload);
+        if expected != actual {
+            return Err(ParseError::ChecksumMismatch { expected, actual });
+        }
+        Ok(Command::from_payload(payload)?.with_raw(bytes))
+    }
+
+    /// The unencoded frame contents, the optional address, command type and
+    /// optional sequence number followed by the data, compressed if that was
+    /// asked for and helps
+    fn payload(&self) -> Vec<u8> {
+        let compressed = self.compressed_data();
+        let mut bytes = Vec::with_capacity(3 + self.data.len());
+        bytes.extend(self.header(compressed.is_some()));
+        bytes.extend(compressed.as_deref().unwrap_or(&self.data));
+        bytes
+    }
+
+    /// The address if there is one, then the command type byte with its
+    /// flags, followed by the sequence number if there is one
+    fn header(&self, compressed: bool) -> impl Iterator<Item = u8> {
+        let mut command_type = u8::from(self.command_type);
+        if compressed {
+            command_type |= COMPRESSED_FLAG;
+        }
+        let command_type = match self.seq {
+            Some(seq) => [command_type | SEQUENCE_FLAG, seq].into_iter().take(2),
+            None => [command_type, 0].into_iter().take(1),
+        };
+        self.address.into_iter().chain(command_type)
+    }
+
+    /// The data compressed, if compression is enabled and makes it smaller
+    #[cfg(feature = "compress")]
+    fn compressed_data(&self) -> Option<Vec<u8>> {
+        if !self.compress {
+            return None;
+        }
+        Some(compress::deflate(&self.data)).filter(|compressed| compressed.len() < self.data.len())
+    }
+
+    /// The data compressed, which without the `compress` feature it never is
+    #[cfg(not(feature = "compress"))]
+    fn compressed_data(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Keep the frame the command was parsed from, for `raw_frame`
+    pub(crate) fn with_raw(self, bytes: Vec<u8>) -> Command {
+        Command { raw: Some(bytes), ..self }
+    }
+
+    /// Convert unencoded frame contents to a Command
+    ///
+    /// # Arguments
+    ///
+    /// * `payload` - The command type and optional sequence number followed by the data
+    ///
+    /// # Returns
+    ///
+    /// * A Command containing the data from the payload
+    ///
+    fn from_payload(payload: &[u8]) -> Result<Command, ParseError> {
+        let (&command_type, rest) = payload.split_first().ok_or(ParseError::EmptyFrame)?;
+        let compressed = command_type & COMPRESSED_FLAG != 0;
+        let inflate = |data: &[u8]| -> Result<Vec<u8>, ParseError> {
+            if !compressed {
+                return Ok(data.to_vec());
+            }
+            #[cfg(feature = "compress")]
+            return compress::inflate(data);
+            #[cfg(not(feature = "compress"))]
+            return Err(ParseError::Decompress);
+        };
+        let mut command = if command_type & SEQUENCE_FLAG != 0 {
+            let (&seq, data) = rest.split_first().ok_or(ParseError::Truncated)?;
+            let command_type = CommandType::try_from(command_type & !SEQUENCE_FLAG & !COMPRESSED_FLAG)
+                .map_err(|_| ParseError::InvalidCommandType(command_type))?;
+            Command::new_with_seq(command_type, inflate(data)?, seq)
+        } else {
+            Command::new(CommandType::try_from(command_type & !COMPRESSED_FLAG)?, inflate(rest)?)
+        };
+        command.compress = compressed;
+        Ok(command)
+    }
+}
+
+/// COBS encode frame contents and terminate them with a null byte
+///
+/// # Arguments
+///
+/// * `payload` - The frame contents
+///
+/// # Returns
+///
+/// * The encoded frame
+///
+fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    encode_frame_into(payload, &mut encoded);
+    encoded
+}
+
+/// COBS encode frame contents into `buf`, replacing its contents
+///
+/// # Arguments
+///
+/// * `payload` - The frame contents
+/// * `buf` - The buffer to write the encoded frame into
+///
+fn encode_frame_into(payload: &[u8], buf: &mut Vec<u8>) {
+    // COBS encode ( decode in python with https://github.com/cmcqueen/cobs-python/ )
+    // The encoder's output depends on the space it is given, so size the
+    // buffer exactly as `encode_vec` does and reserve the terminator separately
+    let max_length = max_encoding_length(payload.len());
+    buf.clear();
+    buf.reserve(max_length + 1);
+    buf.resize(max_length, 0);
+    let length = encode(payload, buf);
+    buf.truncate(length);
+    buf.push(FRAME_TERMINATOR);  // Add a null byte to the end to indicate end of command
+}
+
+/// COBS encode frame contents for `Framing::CobsTerminated`
+///
+/// # Arguments
+///
+/// * `payload` - The frame contents
+/// * `terminator` - The byte ending the frame
+///
+/// # Returns
+///
+/// * The encoded frame, XORed with `terminator` so it is the last byte and no other
+///
+fn encode_frame_terminated(payload: &[u8], terminator: u8) -> Vec<u8> {
+    let mut encoded = encode_frame(payload);
+    for byte in encoded.iter_mut() {
+        *byte ^= terminator;
+    }
+    encoded
+}
+
+/// The length of `bytes` once COBS encoded, as `encode_frame` produces without its terminator
+fn cobs_encoded_len(bytes: impl Iterator<Item = u8>) -> usize {
+    // A code byte starts the frame and replaces each zero, and the encoder
+    // starts a new block after every 254 non-zero bytes, which is dropped if
+    // it would overrun the buffer `encode_frame_into` gives it
+    let mut count = 0;
+    let mut blocks = 0;
+    let mut run = 0;
+    for byte in bytes {
+        count += 1;
+        run = if byte == 0 { 0 } else { run + 1 };
+        if run == 254 {
+            blocks += 1;
+            run = 0;
+        }
+    }
+    (1 + count + blocks).min(max_encoding_length(count))
+}
+
+/// Decode the COBS frame at the start of `bytes`, up to the first null byte
+///
+/// # Arguments
+///
+/// * `bytes` - The encoded frame
+///
+/// # Returns
+///
+/// * The decoded frame contents
+///
+fn decode_frame(bytes: &[u8]) -> Result<Vec<u8>, ParseError> {
+    let null_index = bytes
+        .iter()
+        .position(|&x| x == FRAME_TERMINATOR)
+        .ok_or(ParseError::MissingTerminator)?;
+    let mut decoded = vec![0; null_index];
+    let length = decode(&bytes[0..null_index], &mut decoded).map_err(|_| ParseError::CobsDecode)?;
+    decoded.truncate(length);
+    Ok(decoded)
+}
+
+/// Decode the frame written by `encode_frame_terminated` at the start of
+/// `bytes`, up to the first `terminator`
+///
+/// # Arguments
+///
+/// * `bytes` - The encoded frame
+/// * `terminator` - The byte ending the frame
+///
+/// # Returns
+///
+/// * The decoded frame contents
+///
+fn decode_frame_terminated(bytes: &[u8], terminator: u8) -> Result<Vec<u8>, ParseError> {
+    let end = bytes
+        .iter()
+        .position(|&x| x == terminator)
+        .ok_or(ParseError::MissingTerminator)?;
+    let unmasked: Vec<u8> = bytes[..=end].iter().map(|byte| byte ^ terminator).collect();
+    decode_frame(&unmasked)
+}
+
+/// Prefix frame contents with their length as a big-endian u16
+///
+/// # Arguments
+///
+/// * `payload` - The frame contents
+///
+/// # Returns
+///
+/// * The length-prefixed frame, or None if the payload is longer than u16::MAX bytes
+///
+fn encode_length_prefixed(payload: &[u8]) -> Option<Vec<u8>> {
+    if payload.len() > FRAME_LENGTH_WIDTH.max_len() {
+        return None;
+    }
+    let mut frame = Vec::with_capacity(FRAME_LENGTH_WIDTH.size() + payload.len());
+    write_len(&mut frame, payload.len(), FRAME_LENGTH_WIDTH, Endianness::Big);
+    frame.extend_from_slice(payload);
+    Some(frame)
+}
+
+/// Take the contents of the length-prefixed frame at the start of `bytes`
+///
+/// # Arguments
+///
+/// * `bytes` - The length-prefixed frame
+///
+/// # Returns
+///
+/// * The frame contents
+///
+fn decode_length_prefixed(bytes: &[u8]) -> Result<&[u8], ParseError> {
+    let length = read_len(bytes, FRAME_LENGTH_WIDTH, Endianness::Big).ok_or(ParseError::Truncated)?;
+    bytes[FRAME_LENGTH_WIDTH.size()..]
+        .get(..length)
+        .ok_or(ParseError::Truncated)
+}
+
+/// Compute the CRC-16/CCITT-FALSE (polynomial 0x1021, initial value 0xFFFF) of some bytes
+///
+/// This matches `binascii.crc_hqx(bytes, 0xFFFF)` in Python.
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes to checksum
+///
+/// # Returns
+///
+/// * The CRC-16
+///
+pub(crate) fn crc16(bytes: &[u8]) -> u16 {
+    crc16_update(0xFFFF, bytes)
+}
+
+/// Continue a CRC-16/CCITT-FALSE over more bytes
+///
+/// # Arguments
+///
+/// * `crc` - The CRC-16 of the bytes so far
+/// * `bytes` - The bytes that follow them
+///
+/// # Returns
+///
+/// * The CRC-16 of all the bytes
+///
+fn crc16_update(mut crc: u16, bytes: &[u8]) -> u16 {
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_datetime_bytes_encoding() {
+        for offset in [-100, 0, 100].iter() {
+            let time = Utc::now() + chrono::Duration::milliseconds(*offset);
+            let bytes = datetime_to_bytes(time);
+            let decoded_time = bytes_to_datetime(&bytes);
+            assert_eq!(decoded_time.timestamp_millis(), time.timestamp_millis());
+        }
+    }
+
+    #[test]
+    fn test_command_encoding() {
+        for command_type in CommandType::all() {
+            for data in [vec![1, 2, 3], vec![4, 5, 6]].iter() {
+                let command = Command::new(*command_type, data.clone());
+                let bytes = command.to_bytes();
+                let decoded = Command::from_bytes(bytes).unwrap();
+                assert_eq!(decoded, command);
+            }
+        }
+    }
+
+    #[test]
+    fn test_try_from_bytes() {
+        let first = Command::startup_command(b"patch01.json".to_vec());
+        let second = Command::simple_command(CommandType::PowerDown);
+        let buffer = [first.to_bytes(), second.to_bytes()].concat();
+        let split = first.to_bytes().len();
+        assert_eq!(Command::try_from(&buffer[..]), Ok(first.clone()));
+        assert_eq!(Command::try_from(&buffer[split..]), Ok(second));
+        assert_eq!(Command::try_from(first.to_bytes()), Ok(first));
+        assert_eq!(Command::try_from(&buffer[..split - 1]), Err(ParseError::MissingTerminator));
+        assert_eq!(Command::try_from(vec![0x01, 0x00]), Err(ParseError::EmptyFrame));
+    }
+
+    /// Frames as produced by the Python `cobs` library, which the payload side uses
+    #[test]
+    fn test_wire_vectors() {
+        let vectors: [(Command, Vec<u8>); 6] = [
+            (Command::simple_command(CommandType::PowerDown), vec![0x02, 0x03, 0x00]),
+            (Command::simple_command(CommandType::Initialised), vec![0x02, 0x02, 0x00]),
+            (
+                Command::startup_command(b"patch01.json".to_vec()),
+                vec![
+                    0x0e, 0x01, 0x70, 0x61, 0x74, 0x63, 0x68, 0x30, 0x31, 0x2e, 0x6a, 0x73, 0x6f, 0x6e, 0x00,
+                ],
+            ),
+            // Zero bytes in the data, including a trailing one
+            (
+                Command::startup_command(vec![0, 1, 0, 2, 0]),
+                vec![0x02, 0x01, 0x02, 0x01, 0x02, 0x02, 0x01, 0x00],
+            ),
+            // A timestamp whose big-endian bytes start with zeros
+            (
+                Command::time_from_millis(1_690_000_000_123),
+                vec![0x01, 0x01, 0x01, 0x07, 0x01, 0x89, 0x7b, 0xd9, 0x84, 0x7b, 0x00],
+            ),
+            (
+                Command::new_with_seq(CommandType::StartupCommand, vec![0], 0),
+                vec![0x02, 0x81, 0x01, 0x01, 0x00],
+            ),
+        ];
+        for (command, expected) in vectors.iter() {
+            assert_eq!(&command.to_bytes(), expected, "{}", command);
+            assert_eq!(&Command::from_bytes(expected.clone()).unwrap(), command);
+        }
+
+        // A full 254 byte block with no zero after it needs no extra code byte
+        let command = Command::startup_command(vec![0x11; 253]);
+        let expected = [&[0xff, 0x01][..], &[0x11; 253], &[0x00]].concat();
+        assert_eq!(command.to_bytes(), expected);
+        let command = Command::startup_command(vec![0x11; 254]);
+        let expected = [&[0xff, 0x01][..], &[0x11; 253], &[0x02, 0x11, 0x00]].concat();
+        assert_eq!(command.to_bytes(), expected);
+    }
+
+    #[test]
+    fn test_frame_len() {
+        let mut commands = vec![
+            Command::simple_command(CommandType::Time),
+            Command::new_with_seq(CommandType::Time, vec![0, 0], 0),
+            Command::startup_command(b"patch01.json".to_vec()),
+            Command::time_from_millis(1_690_000_000_123),
+        ];
+        for len in [252, 253, 254, 255, 506, 507, 508, 509, 1000] {
+            commands.push(Command::startup_command(vec![0x11; len]));
+            commands.push(Command::startup_command((0..len).map(|i| (i % 256) as u8).collect()));
+        }
+        commands.push(Command::startup_command([vec![0x11; 253], vec![0], vec![0x11; 300]].concat()));
+        for command in commands {
+            assert_eq!(command.frame_len(), command.to_bytes().len(), "{}", command);
+        }
+    }
+
+    #[test]
+    fn test_to_bytes_into() {
+        let mut buf = vec![0xaa; 300];
+        for command in [
+            Command::startup_command(b"patch01.json".to_vec()),
+            Command::startup_command(vec![0; 300]),
+            Command::startup_command(vec![0x11; 254]),
+            Command::new_with_seq(CommandType::Time, vec![1, 0, 2], 7),
+            Command::simple_command(CommandType::PowerDown),
+        ] {
+            command.to_bytes_into(&mut buf);
+            assert_eq!(buf, command.to_bytes());
+        }
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_time() {
+        for offset in [-100, 0, 100].iter() {
+            let time = Utc::now() + chrono::Duration::milliseconds(*offset);
+            let command = Command::time(time);
+            let bytes = command.to_bytes();
+            let decoded = Command::from_bytes(bytes).unwrap();
+            assert_eq!(decoded.command_type, CommandType::Time);
+            let decoded_time = bytes_to_datetime(&decoded.data);
+            assert_eq!(decoded_time.timestamp_millis(), time.timestamp_millis());
+        }
+    }
+
+    #[test]
+    fn test_time_without_chrono() {
+        let command = Command::time_from_millis(1_690_000_000_123);
+        assert_eq!(command.command_type, CommandType::Time);
+        assert_eq!(command.data, [0, 0, 1, 0x89, 0x7b, 0xd9, 0x84, 0x7b]);
+        #[cfg(feature = "chrono")]
+        assert_eq!(command, Command::time(Utc.timestamp_millis_opt(1_690_000_000_123).unwrap()));
+
+        // The data is signed, so the extremes read back unchanged
+        for millis in [i64::MIN, -1, i64::MAX] {
+            let data = Command::time_from_millis(millis).data;
+            assert_eq!(i64::from_be_bytes(data.try_into().unwrap()), millis);
+        }
+        #[cfg(feature = "chrono")]
+        assert_eq!(
+            Command::time_from_millis(-1).data_as_datetime(),
+            Ok(Utc.with_ymd_and_hms(1969, 12, 31, 23, 59, 59).unwrap() + chrono::Duration::milliseconds(999))
+        );
+
+        #[cfg(feature = "std")]
+        {
+            let since_epoch = |millis: u64| std::time::UNIX_EPOCH + std::time::Duration::from_millis(millis);
+            assert_eq!(Command::time_system(since_epoch(1_690_000_000_123)), command);
+            let time = since_epoch(1_690_000_000_123) + std::time::Duration::from_micros(999);
+            assert_eq!(Command::time_system(time), command);
+            // Before the epoch rounds down too, as chrono does
+            let time = std::time::UNIX_EPOCH - std::time::Duration::from_micros(1500);
+            assert_eq!(Command::time_system(time).data, (-2i64).to_be_bytes());
+            #[cfg(feature = "chrono")]
+            assert_eq!(Command::time_system(time), Command::time(DateTime::<Utc>::from(time)));
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_time_now() {
+        let millis = |command: Command| i64::from_be_bytes(command.data.try_into().unwrap());
+        let before = millis(Command::time_system(std::time::SystemTime::now()));
+        let command = Command::time_now();
+        let after = millis(Command::time_system(std::time::SystemTime::now()));
+        assert_eq!(command.command_type, CommandType::Time);
+        assert!(command.is_valid());
+        assert!((before..=after).contains(&millis(command)));
+    }
+
+    #[test]
+    fn test_startup_command() {
+        for startup_command in ["patch01.json", "orbit05.json", "asdfGHJK.json"].iter() {
+            let command = Command::startup_command(startup_command.as_bytes().to_vec());
+            let bytes = command.to_bytes();
+            let decoded = Command::from_bytes(bytes).unwrap();
+            assert_eq!(decoded, (CommandType::StartupCommand, startup_command.as_bytes()));
+            assert_eq!(decoded.data_as_str(), Ok(*startup_command));
+        }
+        assert!(Command::startup_command(vec![0xff, 0xfe]).data_as_str().is_err());
+    }
 
     #[test]
     fn test_simple_command() {
-        for command_type in [CommandType::Initialised, CommandType::PowerDown, CommandType::TimeAcknowledge, CommandType::StartupCommandAcknowledge, CommandType::InitialisedAcknowledge, CommandType::StartupCommandAcknowledge].iter() {
+        for command_type in CommandType::all() {
             let command = Command::simple_command(*command_type);
             let bytes = command.to_bytes();
-            let decoded = Command::from_bytes(bytes);
-            assert_eq!(decoded.command_type, *command_type);
-            assert_eq!(decoded.data, Vec::new());
+            let decoded = Command::from_bytes(bytes).unwrap();
+            assert_eq!(decoded, (*command_type, &[][..]));
+        }
+    }
+
+    #[test]
+    fn test_all_command_types() {
+        assert_eq!(CommandType::all().len(), COMMAND_TYPE_COUNT);
+        for (byte, command_type) in CommandType::all().iter().enumerate() {
+            assert_eq!(u8::from(*command_type), byte as u8);
+            assert_eq!(CommandType::try_from(byte as u8), Ok(*command_type));
+        }
+        // A new variant must be added to `all` and the count
+        assert_eq!(
+            CommandType::try_from(COMMAND_TYPE_COUNT as u8),
+            Ok(CommandType::Unknown(UnknownCommandType(COMMAND_TYPE_COUNT as u8)))
+        );
+    }
+
+    #[test]
+    fn test_kind_byte() {
+        for command_type in CommandType::all().iter().copied().chain([unknown(42)]) {
+            let command = Command::new_with_seq(command_type, vec![1, 2, 3], 7);
+            assert_eq!(CommandType::try_from(command.kind_byte()), Ok(command.command_type));
+            let decoded = Command::from_bytes(command.to_bytes()).unwrap();
+            assert_eq!(decoded.kind_byte(), command.kind_byte());
+        }
+    }
+
+    #[test]
+    fn test_command_type_byte_round_trip() {
+        for byte in 0..=u8::MAX {
+            if let Ok(command_type) = CommandType::try_from(byte) {
+                assert_eq!(u8::from(command_type), byte);
+            }
+        }
+    }
+
+    #[test]
+    fn test_command_type_as_map_key() {
+        let handlers: std::collections::HashMap<CommandType, String> = CommandType::all()
+            .iter()
+            .map(|command_type| (*command_type, command_type.to_string()))
+            .collect();
+        assert_eq!(handlers.len(), COMMAND_TYPE_COUNT);
+        assert_eq!(handlers[&CommandType::Heartbeat], "Heartbeat");
+        assert!(!handlers.contains_key(&unknown(42)));
+    }
+
+    #[test]
+    fn test_invalid_command_type() {
+        assert_eq!(CommandType::try_from(200), Err(InvalidCommandType(200)));
+        // Bytes with COMPRESSED_FLAG set would be read back as compressed
+        for byte in [COMPRESSED_FLAG, 0x41, 0x7f] {
+            assert_eq!(CommandType::try_from(byte), Err(InvalidCommandType(byte)));
+        }
+        assert_eq!(CommandType::try_from(0x3f), Ok(unknown(0x3f)));
+    }
+
+    /// The Unknown command type holding `byte`
+    fn unknown(byte: u8) -> CommandType {
+        CommandType::Unknown(UnknownCommandType::try_from(byte).unwrap())
+    }
+
+    #[test]
+    fn test_unknown_command_type() {
+        assert_eq!(CommandType::try_from(42), Ok(unknown(42)));
+        let mut frame = cobs::encode_vec(&[42, 1, 2, 3]);
+        frame.push(0);
+        let command = Command::from_bytes(frame.clone()).unwrap();
+        assert_eq!(command, (unknown(42), &[1, 2, 3][..]));
+        assert_eq!(command.to_bytes(), frame);
+
+        let command = Command::new_with_seq(unknown(42), vec![1], 5);
+        assert_eq!(Command::from_bytes(command.to_bytes()).unwrap(), command);
+    }
+
+    #[test]
+    fn test_unknown_command_type_bytes() {
+        // Assigned bytes are the known types, and flag bits would change the frame's meaning
+        assert_eq!(UnknownCommandType::try_from(3), Err(InvalidCommandType(3)));
+        assert_eq!(UnknownCommandType::try_from(0x90), Err(InvalidCommandType(0x90)));
+        assert_eq!(UnknownCommandType::try_from(0x50), Err(InvalidCommandType(0x50)));
+        assert_eq!(UnknownCommandType::try_from(0x3f).map(u8::from), Ok(0x3f));
+
+        // So every Unknown that can be made round trips, with and without a sequence number
+        for byte in 0..=u8::MAX {
+            let Ok(command_type @ CommandType::Unknown(_)) = CommandType::try_from(byte) else {
+                continue;
+            };
+            for command in [
+                Command::new(command_type, vec![5, 6, 7]),
+                Command::new_with_seq(command_type, vec![5, 6, 7], 5),
+            ] {
+                assert_eq!(Command::from_bytes(command.to_bytes()).unwrap(), command);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Command::from_bytes(vec![0x02, 0x01]).unwrap_err(), ParseError::MissingTerminator);
+        assert_eq!(Command::from_bytes(vec![0x05, 0x01, 0x00]).unwrap_err(), ParseError::CobsDecode);
+    }
+
+    #[test]
+    fn test_empty_frame() {
+        assert_eq!(Command::from_bytes(vec![0x01, 0x00]).unwrap_err(), ParseError::EmptyFrame);
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_try_bytes_to_datetime() {
+        assert_eq!(try_bytes_to_datetime(&[0, 1, 2]), Err(TimeDecodeError::TooShort(3)));
+        assert_eq!(try_bytes_to_datetime(&i64::MAX.to_be_bytes()), Err(TimeDecodeError::OutOfRange(i64::MAX)));
+        assert_eq!(try_bytes_to_datetime(&0i64.to_be_bytes()).unwrap().timestamp_millis(), 0);
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_time_endianness() {
+        let time = Utc.timestamp_millis_opt(0x0102_0304_0506).unwrap();
+        assert_eq!(datetime_to_bytes(time), [0, 0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(datetime_to_bytes_with(time, Endianness::default()), datetime_to_bytes(time));
+        let little = datetime_to_bytes_with(time, Endianness::Little);
+        assert_eq!(little, [6, 5, 4, 3, 2, 1, 0, 0]);
+        assert_eq!(Command::time_with(time, Endianness::Little).data, little);
+        assert_eq!(try_bytes_to_datetime_with(&little, Endianness::Little), Ok(time));
+        assert_ne!(try_bytes_to_datetime(&little), Ok(time));
+        assert_eq!(try_bytes_to_datetime_with(&little[..7], Endianness::Little), Err(TimeDecodeError::TooShort(7)));
+    }
+
+    #[test]
+    fn test_length_fields() {
+        let cases = [
+            (LengthWidth::U16, Endianness::Big, vec![0x01, 0x02]),
+            (LengthWidth::U16, Endianness::Little, vec![0x02, 0x01]),
+            (LengthWidth::U32, Endianness::Big, vec![0, 0, 0x01, 0x02]),
+            (LengthWidth::U32, Endianness::Little, vec![0x02, 0x01, 0, 0]),
+        ];
+        for (width, endianness, expected) in cases {
+            let mut out = vec![0xff];
+            write_len(&mut out, 0x0102, width, endianness);
+            assert_eq!(out[1..], expected[..]);
+            assert_eq!(out.len(), 1 + width.size());
+            assert_eq!(read_len(&out[1..], width, endianness), Some(0x0102));
+            assert_eq!(read_len(&out[1..width.size()], width, endianness), None);
+        }
+        let mut out = Vec::new();
+        write_len(&mut out, u16::MAX as usize + 1, LengthWidth::U32, Endianness::Big);
+        assert_eq!(read_len(&out, LengthWidth::U32, Endianness::Big), Some(0x1_0000));
+
+        assert_eq!(LengthWidth::U16.max_len(), 0xffff);
+
+        // Length-prefixed frames carry a big-endian u16
+        let frame = Command::simple_command(CommandType::Heartbeat).to_bytes_with(Framing::LengthPrefixed);
+        assert_eq!(read_len(&frame, LengthWidth::U16, Endianness::Big), Some(frame.len() - 2));
+    }
+
+    #[test]
+    fn test_try_to_bytes_with() {
+        let longest = Command::startup_command(vec![1; 0xffff - 1]);
+        let frame = longest.try_to_bytes_with(Framing::LengthPrefixed).unwrap();
+        assert_eq!(frame, longest.to_bytes_with(Framing::LengthPrefixed));
+        let too_long = Command::startup_command(vec![1; 0xffff]);
+        assert_eq!(too_long.try_to_bytes_with(Framing::LengthPrefixed), None);
+        assert_eq!(too_long.try_to_bytes_with(Framing::Cobs), Some(too_long.to_bytes()));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_write_len_overflow() {
+        write_len(&mut Vec::new(), u16::MAX as usize + 1, LengthWidth::U16, Endianness::Big);
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_time_nanos() {
+        let time = Utc.timestamp_nanos(1_690_000_000_123_456_789);
+        let bytes = datetime_to_bytes_nanos(time);
+        assert_eq!(bytes_to_datetime_nanos(&bytes), time);
+        assert_eq!(try_bytes_to_datetime_nanos(&bytes[..4]), Err(TimeDecodeError::TooShort(4)));
+
+        let command = Command::from_bytes(Command::time_nanos(time).to_bytes()).unwrap();
+        assert_eq!(command.command_type, CommandType::TimeNanos);
+        assert_eq!(command.data_as_datetime(), Ok(time));
+        assert_eq!(CommandType::TimeNanos.expected_ack(), Some(CommandType::TimeAcknowledge));
+
+        // The millisecond encoding still truncates
+        assert_eq!(bytes_to_datetime(&datetime_to_bytes(time)).timestamp_subsec_nanos(), 123_000_000);
+
+        // i64 nanoseconds only reach 2262-04-11
+        let late = Utc.with_ymd_and_hms(2300, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(
+            Command::try_time_nanos(late),
+            Err(ValidationError::InvalidTime(TimeDecodeError::OutOfRange(late.timestamp_millis())))
+        );
+        assert_eq!(Command::try_time_nanos(time), Ok(Command::time_nanos(time)));
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_data_as_datetime() {
+        let time = Utc.timestamp_millis_opt(1_690_000_000_123).unwrap();
+        assert_eq!(Command::time(time).data_as_datetime(), Ok(time));
+        // A TimeAcknowledge carries no time, and is invalid if given one
+        let ack = Command::new(CommandType::TimeAcknowledge, datetime_to_bytes(time));
+        assert_eq!(ack.data_as_datetime(), Err(TimeDecodeError::NotTimeCommand(CommandType::TimeAcknowledge)));
+        assert!(!ack.is_valid());
+        assert_eq!(
+            Command::new(CommandType::Time, vec![1, 2]).data_as_datetime(),
+            Err(TimeDecodeError::TooShort(2))
+        );
+        assert_eq!(
+            Command::new(CommandType::StartupCommand, datetime_to_bytes(time)).data_as_datetime(),
+            Err(TimeDecodeError::NotTimeCommand(CommandType::StartupCommand))
+        );
+    }
+
+    #[test]
+    fn test_command_formatting() {
+        let command = Command::startup_command(vec![0x0a, 0xff, 0x00]);
+        assert_eq!(command.to_string(), "StartupCommand [0a ff 00] (3 bytes)");
+        assert_eq!(
+            format!("{:?}", command),
+            "Command { command_type: StartupCommand, data: [0a ff 00] (3 bytes), checksum: 364a }"
+        );
+        let command = Command::startup_command(vec![1; 20]);
+        assert_eq!(
+            command.to_string(),
+            "StartupCommand [01 01 01 01 01 01 01 01 01 01 01 01 01 01 01 01 ...] (20 bytes)"
+        );
+        assert_eq!(CommandType::TimeAcknowledge.to_string(), "TimeAcknowledge");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let command = Command::startup_command(vec![1, 2, 3]);
+        let json = serde_json::to_string(&command).unwrap();
+        #[cfg(not(feature = "serde-base64"))]
+        assert_eq!(json, r#"{"command_type":"StartupCommand","data":[1,2,3]}"#);
+        #[cfg(feature = "serde-base64")]
+        assert_eq!(json, r#"{"command_type":"StartupCommand","data":"AQID"}"#);
+        assert_eq!(serde_json::from_str::<Command>(&json).unwrap(), command);
+    }
+
+    #[test]
+    fn test_checksum() {
+        let command = Command::startup_command(vec![0x0a, 0xff, 0x00]);
+        assert_eq!(command.checksum(), 0x364a);
+        assert_eq!(command.checksum(), crc16(&[1, 0x0a, 0xff, 0x00]));
+        assert_eq!(Command::new_with_seq(CommandType::StartupCommand, vec![0x0a, 0xff, 0x00], 7).checksum(), 0x364a);
+        assert_ne!(Command::startup_command(vec![0x0a, 0xff, 0x01]).checksum(), 0x364a);
+    }
+
+    #[test]
+    fn test_checked_encoding() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc16_update(crc16(b"1234"), b"56789"), 0x29B1);
+        let command = Command::startup_command(b"patch01.json".to_vec());
+        let bytes = command.to_bytes_checked();
+        assert_eq!(Command::from_bytes_checked(bytes.clone()).unwrap(), command);
+
+        // Flip a bit in the data without touching the COBS structure
+        let mut corrupted = bytes.clone();
+        corrupted[3] ^= 0x01;
+        assert!(matches!(
+            Command::from_bytes_checked(corrupted).unwrap_err(),
+            ParseError::ChecksumMismatch { .. }
+        ));
+    }
+
+    #[test]
+    fn test_sequence_number() {
+        let command = Command::new_with_seq(CommandType::Time, vec![1, 2, 3], 42);
+        let bytes = command.to_bytes();
+        assert_eq!(bytes, vec![0x06, 0x80, 42, 1, 2, 3, 0x00]);
+        let decoded = Command::from_bytes(bytes).unwrap();
+        assert_eq!(decoded.seq(), Some(42));
+        assert_eq!(decoded, command);
+        assert_eq!(Command::from_bytes(vec![0x02, 0x80, 0x00]).unwrap_err(), ParseError::Truncated);
+        assert_eq!(Command::simple_command(CommandType::Time).seq(), None);
+    }
+
+    #[test]
+    fn test_addressed_command() {
+        let command = Command::new_addressed(5, CommandType::Time, vec![1, 2, 3]);
+        assert_eq!(command.address(), Some(5));
+        let bytes = command.to_bytes();
+        assert_eq!(bytes, vec![0x02, 5, 0x04, 1, 2, 3, 0x00]);
+        assert_eq!(command.frame_len(), bytes.len());
+        assert_eq!(Command::from_bytes_addressed(bytes).unwrap(), command);
+        assert_eq!(Command::simple_command(CommandType::Time).address(), None);
+        assert_eq!(Command::from_bytes_addressed(vec![0x01, 0x00]).unwrap_err(), ParseError::EmptyFrame);
+        assert_eq!(Command::from_bytes_addressed(vec![0x02, 5, 0x00]).unwrap_err(), ParseError::EmptyFrame);
+    }
+
+    #[test]
+    fn test_cobs_terminated() {
+        let command = Command::new_addressed(5, CommandType::Time, vec![1, 2, 3]);
+        let framing = Framing::CobsTerminated(0xff);
+        let bytes = command.to_bytes_with(framing);
+        assert_eq!(bytes, vec![0xfd, 0xfa, 0xfb, 0xfe, 0xfd, 0xfc, 0xff]);
+        assert_eq!(Command::from_bytes_addressed_with(bytes.clone(), framing).unwrap(), command);
+        assert_eq!(Command::from_bytes_with(bytes[..6].to_vec(), framing), Err(ParseError::MissingTerminator));
+
+        let command = Command::startup_command(vec![0, 0xff, 0]);
+        assert_eq!(command.to_bytes_with(Framing::CobsTerminated(FRAME_TERMINATOR)), command.to_bytes());
+        let bytes = command.to_bytes_with(framing);
+        assert_eq!(Command::from_bytes_with(bytes.clone(), framing).unwrap().raw_frame(), Some(&bytes[..]));
+        assert_eq!(Framing::Cobs.terminator(), Some(0));
+        assert_eq!(Framing::LengthPrefixed.terminator(), None);
+    }
+
+    #[test]
+    fn test_acknowledgement_mapping() {
+        let table = [
+            (CommandType::Time, Some(CommandType::TimeAcknowledge), false),
+            (CommandType::StartupCommand, Some(CommandType::StartupCommandAcknowledge), false),
+            (CommandType::Initialised, Some(CommandType::InitialisedAcknowledge), false),
+            (CommandType::PowerDown, Some(CommandType::PowerDownAcknowledge), false),
+            (CommandType::TimeAcknowledge, None, true),
+            (CommandType::StartupCommandAcknowledge, None, true),
+            (CommandType::InitialisedAcknowledge, None, true),
+            (CommandType::PowerDownAcknowledge, None, true),
+            (CommandType::RequestSendFile, None, false),
+            (CommandType::ReadyReceiveFile, None, false),
+            (CommandType::SendFileData, None, false),
+            (CommandType::ReceivedFileData, None, false),
+            (CommandType::SendFileHash, None, false),
+            (CommandType::ReceiveFileSuccess, None, false),
+            (CommandType::ReceiveFileErrorRetry, None, false),
+            (CommandType::ReceiveFileErrorAbort, None, false),
+            (CommandType::SendFileAbort, None, false),
+            (CommandType::TimeNanos, Some(CommandType::TimeAcknowledge), false),
+            (CommandType::Heartbeat, Some(CommandType::HeartbeatAcknowledge), false),
+            (CommandType::HeartbeatAcknowledge, None, true),
+            (CommandType::Version, Some(CommandType::VersionAcknowledge), false),
+            (CommandType::VersionAcknowledge, None, true),
+            (CommandType::Fragment, None, false),
+            (CommandType::Nack, None, false),
+        ];
+        assert_eq!(table.len(), COMMAND_TYPE_COUNT);
+        for (command_type, ack, is_ack) in table.iter() {
+            assert_eq!(command_type.expected_ack(), *ack, "{}", command_type);
+            assert_eq!(command_type.is_acknowledgement(), *is_ack, "{}", command_type);
+            if let Some(ack) = ack {
+                assert!(ack.is_acknowledgement());
+            }
+        }
+    }
+
+    #[test]
+    fn test_new_validated() {
+        let time = Command::time_from_millis(1_690_000_000_123).data;
+        assert!(Command::new_validated(CommandType::Time, time).is_ok());
+        assert_eq!(
+            Command::new_validated(CommandType::Time, vec![0; 4]).unwrap_err(),
+            ValidationError::WrongLength { command_type: CommandType::Time, expected: 8, actual: 4 }
+        );
+        #[cfg(feature = "chrono")]
+        assert_eq!(
+            Command::new_validated(CommandType::Time, i64::MAX.to_be_bytes().to_vec()).unwrap_err(),
+            ValidationError::InvalidTime(TimeDecodeError::OutOfRange(i64::MAX))
+        );
+        assert!(Command::new_validated(CommandType::InitialisedAcknowledge, vec![]).is_ok());
+        assert!(Command::new_validated(CommandType::PowerDownAcknowledge, vec![1]).is_err());
+        assert!(Command::new_validated(CommandType::StartupCommand, b"patch01.json".to_vec()).is_ok());
+    }
+
+    #[test]
+    fn test_ack_for() {
+        for &command_type in CommandType::all() {
+            let received = Command::new_with_seq(command_type, b"orbit05.json".to_vec(), 9);
+            match Command::ack_for(&received) {
+                Some(ack) => {
+                    assert_eq!(Some(ack.command_type), command_type.expected_ack());
+                    assert!(ack.acknowledges(&received), "{:?}", command_type);
+                }
+                None => assert_eq!(command_type.expected_ack(), None),
+            }
+        }
+        let ack = Command::ack_for(&Command::startup_command(b"orbit05.json".to_vec())).unwrap();
+        assert_eq!(ack, Command::startup_command_ack("orbit05.json"));
+        assert_eq!(
+            Command::ack_for(&Command::simple_command(CommandType::Heartbeat)),
+            Some(Command::simple_command(CommandType::HeartbeatAcknowledge))
+        );
+        assert_eq!(
+            Command::ack_for(&Command::new(CommandType::Version, vec![1])).unwrap().data,
+            [PROTOCOL_VERSION]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let parsed = Command::from_bytes(Command::new(CommandType::Time, vec![0; 4]).to_bytes()).unwrap();
+        assert_eq!(
+            parsed.validate(),
+            Err(ValidationError::WrongLength { command_type: CommandType::Time, expected: 8, actual: 4 })
+        );
+        assert!(!parsed.is_valid());
+        assert!(Command::time_from_millis(1_690_000_000_123).is_valid());
+        assert!(Command::initialised(3, 0).is_valid());
+        assert!(Command::new(CommandType::Initialised, vec![3]).validate().is_err());
+        assert_eq!(
+            Command::new(CommandType::PowerDown, vec![0xee]).validate(),
+            Err(ValidationError::InvalidPowerDownReason(0xee))
+        );
+        assert!(Command::new(unknown(0x3f), vec![1, 2, 3]).is_valid());
+    }
+
+    #[test]
+    fn test_power_down_reason() {
+        for reason in [
+            PowerDownReason::Unspecified,
+            PowerDownReason::Scheduled,
+            PowerDownReason::Thermal,
+            PowerDownReason::Emergency,
+        ] {
+            let command = Command::from_bytes(Command::power_down(reason).to_bytes()).unwrap();
+            assert_eq!(command.power_down_reason(), Ok(reason));
+        }
+        assert!(Command::power_down(PowerDownReason::Unspecified).data.is_empty());
+        assert_eq!(
+            Command::simple_command(CommandType::PowerDown).power_down_reason(),
+            Ok(PowerDownReason::Unspecified)
+        );
+        assert_eq!(
+            Command::new(CommandType::PowerDown, vec![9]).power_down_reason(),
+            Err(ValidationError::InvalidPowerDownReason(9))
+        );
+        assert!(Command::new_validated(CommandType::PowerDown, vec![1, 2]).is_err());
+        assert_eq!(
+            Command::simple_command(CommandType::Initialised).power_down_reason(),
+            Err(ValidationError::WrongCommandType(CommandType::Initialised))
+        );
+    }
+
+    #[test]
+    fn test_boot_status() {
+        let command = Command::from_bytes(Command::initialised(0x0102_0304, 7).to_bytes()).unwrap();
+        assert_eq!(command.data, [1, 2, 3, 4, 7]);
+        assert_eq!(command.boot_status(), Ok(Some(BootStatus { version: 0x0102_0304, status: 7 })));
+        assert!(Command::new_validated(CommandType::Initialised, command.data).is_ok());
+
+        let legacy = Command::new_validated(CommandType::Initialised, vec![]).unwrap();
+        assert_eq!(legacy.boot_status(), Ok(None));
+        assert!(Command::new_validated(CommandType::Initialised, vec![1, 2]).is_err());
+        assert_eq!(
+            Command::new(CommandType::Initialised, vec![1, 2]).boot_status(),
+            Err(ValidationError::WrongLength { command_type: CommandType::Initialised, expected: 5, actual: 2 })
+        );
+        assert_eq!(
+            Command::simple_command(CommandType::PowerDown).boot_status(),
+            Err(ValidationError::WrongCommandType(CommandType::PowerDown))
+        );
+    }
+
+    #[test]
+    fn test_raw_frame() {
+        let frame = vec![0x03, 0x83, 0x01, 0x00];
+        let command = Command::from_bytes(frame.clone()).unwrap();
+        assert_eq!(command.raw_frame(), Some(&frame[..]));
+        assert_eq!(command.clone().raw_frame(), Some(&frame[..]));
+        assert_eq!(Command::simple_command(CommandType::PowerDown).raw_frame(), None);
+        assert_eq!(Command::try_from(&frame[..]).unwrap().raw_frame(), None);
+
+        let framed = Command::startup_command(b"orbit05.json".to_vec()).to_bytes_with(Framing::LengthPrefixed);
+        let command = Command::from_bytes_with(framed.clone(), Framing::LengthPrefixed).unwrap();
+        assert_eq!(command.raw_frame(), Some(&framed[..]));
+        let checked = Command::simple_command(CommandType::Heartbeat).to_bytes_checked();
+        assert_eq!(Command::from_bytes_checked(checked.clone()).unwrap().raw_frame(), Some(&checked[..]));
+    }
+
+    #[test]
+    fn test_eq_tuple() {
+        let command = Command::new_with_seq(CommandType::StartupCommand, b"orbit05.json".to_vec(), 3);
+        assert_eq!(command, (CommandType::StartupCommand, &b"orbit05.json"[..]));
+        assert_ne!(command, (CommandType::StartupCommand, &b"orbit06.json"[..]));
+        assert_ne!(command, (CommandType::StartupCommandAcknowledge, &b"orbit05.json"[..]));
+    }
+
+    #[test]
+    fn test_nack() {
+        let command = Command::new_with_seq(CommandType::PowerDown, vec![1], 5);
+        let nack = Command::from_bytes(Command::nack_for(&command, 9).to_bytes()).unwrap();
+        assert_eq!(nack.data, [3, 9]);
+        assert_eq!(nack.rejection(), Ok(Rejection { command_type: CommandType::PowerDown, reason: 9 }));
+        assert!(nack.is_valid());
+        assert!(nack.rejects(&command));
+        assert!(!nack.acknowledges(&command));
+        assert!(!Command::nack(CommandType::PowerDown, 9).rejects(&command));
+        assert!(Command::nack(CommandType::PowerDown, 9).rejects(&Command::simple_command(CommandType::PowerDown)));
+        assert!(!nack.rejects(&Command::new_with_seq(CommandType::Heartbeat, vec![], 5)));
+
+        assert_eq!(
+            Command::new(CommandType::Nack, vec![3]).rejection(),
+            Err(ValidationError::WrongLength { command_type: CommandType::Nack, expected: 2, actual: 1 })
+        );
+        assert_eq!(
+            Command::new(CommandType::Nack, vec![0x80, 0]).validate(),
+            Err(ValidationError::InvalidCommandType(0x80))
+        );
+        assert_eq!(
+            Command::simple_command(CommandType::Heartbeat).rejection(),
+            Err(ValidationError::WrongCommandType(CommandType::Heartbeat))
+        );
+    }
+
+    #[test]
+    fn test_startup_command_ack() {
+        let command = Command::startup_command(b"patch01.json".to_vec());
+        assert!(Command::startup_command_ack("patch01.json").acknowledges(&command));
+        assert!(!Command::startup_command_ack("orbit05.json").acknowledges(&command));
+        // Legacy acknowledgements without a filename are still accepted
+        assert!(Command::simple_command(CommandType::StartupCommandAcknowledge).acknowledges(&command));
+        assert!(!Command::simple_command(CommandType::TimeAcknowledge).acknowledges(&command));
+        assert!(Command::new_validated(CommandType::StartupCommandAcknowledge, b"patch01.json".to_vec()).is_ok());
+    }
+
+    #[test]
+    fn test_startup_status() {
+        let command = Command::startup_command(b"patch01.json".to_vec());
+        for status in [StartupStatus::Received, StartupStatus::Queued, StartupStatus::Rejected(7)] {
+            let ack = Command::startup_command_ack_with_status("patch01.json", status);
+            assert!(ack.is_valid());
+            assert!(ack.acknowledges(&command));
+            assert_eq!(ack.startup_status(), Ok(Some(status)));
+            let parsed = Command::from_bytes(ack.to_bytes()).unwrap();
+            assert_eq!(parsed.startup_status(), Ok(Some(status)));
+        }
+        let queued = |name| Command::startup_command_ack_with_status(name, StartupStatus::Queued);
+        assert!(!queued("orbit05.json").acknowledges(&command));
+        assert!(queued("").acknowledges(&command));
+
+        // Older firmware only echoes the filename, or nothing at all
+        assert_eq!(Command::startup_command_ack("patch01.json").startup_status(), Ok(None));
+        assert_eq!(Command::simple_command(CommandType::StartupCommandAcknowledge).startup_status(), Ok(None));
+
+        let ack = |data: &[u8]| Command::new(CommandType::StartupCommandAcknowledge, data.to_vec());
+        assert_eq!(ack(b"a.json\0\x09").startup_status(), Err(ValidationError::InvalidStartupStatus(9)));
+        let command_type = CommandType::StartupCommandAcknowledge;
+        assert_eq!(
+            ack(b"a.json\0\x02").startup_status(),
+            Err(ValidationError::WrongLength { command_type, expected: 9, actual: 8 })
+        );
+        assert_eq!(
+            ack(b"a.json\0").validate(),
+            Err(ValidationError::WrongLength { command_type, expected: 8, actual: 7 })
+        );
+        assert_eq!(
+            Command::simple_command(CommandType::Heartbeat).startup_status(),
+            Err(ValidationError::WrongCommandType(CommandType::Heartbeat))
+        );
+    }
+
+    proptest::proptest! {
+        /// No input can make the parsers panic, they return an error instead
+        #[test]
+        fn prop_parsers_never_panic(bytes in proptest::collection::vec(proptest::num::u8::ANY, 0..600)) {
+            let _ = Command::from_bytes(bytes.clone());
+            let _ = Command::from_bytes_checked(bytes.clone());
+            let _ = Command::from_bytes_with(bytes.clone(), Framing::LengthPrefixed);
+            let _ = Command::from_bytes_with(bytes.clone(), Framing::CobsTerminated(0x7e));
+            let _ = Command::try_from(&bytes[..]);
+            let mut decoder = FrameDecoder::with_max_frame_size(256);
+            let _ = decoder.push(&bytes);
+            for framing in [Framing::Cobs, Framing::LengthPrefixed, Framing::CobsTerminated(0x7e)] {
+                decoder.clear();
+                decoder.set_framing(framing);
+                decoder.extend(&bytes);
+                // Each call consumes a frame or reports an error, so this drains the buffer
+                for _ in 0..=bytes.len() {
+                    if let Ok(None) = decoder.next_frame() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        /// Every byte is either a command type or rejected
+        #[test]
+        fn prop_command_type_from_any_byte(byte in proptest::num::u8::ANY) {
+            if let Ok(command_type) = CommandType::try_from(byte) {
+                proptest::prop_assert_eq!(u8::from(command_type), byte);
+            }
+        }
+
+        /// Any command survives encoding and decoding
+        #[test]
+        fn prop_command_round_trip(
+            index in 0..COMMAND_TYPE_COUNT,
+            data in proptest::collection::vec(proptest::num::u8::ANY, 0..600),
+            seq in proptest::option::of(proptest::num::u8::ANY),
+        ) {
+            let command_type = CommandType::all()[index];
+            let command = match seq {
+                Some(seq) => Command::new_with_seq(command_type, data, seq),
+                None => Command::new(command_type, data),
+            };
+            proptest::prop_assert_eq!(Command::from_bytes(command.to_bytes()), Ok(command.clone()));
+            proptest::prop_assert_eq!(Command::from_bytes_checked(command.to_bytes_checked()), Ok(command.clone()));
+            proptest::prop_assert_eq!(
+                Command::from_bytes_with(command.to_bytes_with(Framing::LengthPrefixed), Framing::LengthPrefixed),
+                Ok(command.clone())
+            );
+            proptest::prop_assert_eq!(command.frame_len(), command.to_bytes().len());
         }
     }
 }
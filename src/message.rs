@@ -0,0 +1,199 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use chrono::{DateTime, Utc};
+
+use crate::{BootStatus, Command, CommandType, PowerDownReason, Rejection, StartupStatus, ValidationError};
+
+/// A command with its data decoded according to its type
+///
+/// `Command` is the wire-level form, while a Message can be matched on
+/// without decoding the data by hand. Sequence numbers and addresses are
+/// not kept, see `Command::seq` and `Command::address` for those.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub enum Message {
+    /// Set the payload's clock
+    Time(DateTime<Utc>),
+    /// Set the payload's clock with nanosecond precision
+    TimeNanos(DateTime<Utc>),
+    /// Run the startup command in the named file
+    StartupCommand(String),
+    /// The acknowledgement of a StartupCommand, echoing its filename and
+    /// reporting its StartupStatus unless sent by older firmware
+    StartupCommandAck(Option<String>, Option<StartupStatus>),
+    /// The payload has come up, with its BootStatus unless sent by older firmware
+    Initialised(Option<BootStatus>),
+    /// Power the payload down
+    PowerDown(PowerDownReason),
+    /// Keepalive checking the link is still up
+    Heartbeat,
+    /// The sender's protocol version
+    Version(u8),
+    /// The acknowledgement of a Version, carrying the receiver's protocol version
+    VersionAck(u8),
+    /// The payload rejected a command
+    Nack(Rejection),
+    /// An acknowledgement carrying no data, holding its own command type,
+    /// e.g. `CommandType::TimeAcknowledge`
+    Ack(CommandType),
+    /// Any other command, such as those of a file transfer, left undecoded
+    Other(Command),
+}
+
+impl TryFrom<Command> for Message {
+    type Error = ValidationError;
+
+    /// Decode a command's data according to its type
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to decode
+    ///
+    /// # Returns
+    ///
+    /// * The Message carrying the decoded data
+    ///
+    /// # Errors
+    ///
+    /// * Any error returned by `Command::validate`
+    /// * `ValidationError::InvalidTime` if a TimeNanos command's time is out of range
+    /// * `ValidationError::InvalidUtf8` if a filename is not UTF-8
+    ///
+    fn try_from(command: Command) -> Result<Message, ValidationError> {
+        command.validate()?;
+        let message = match command.command_type {
+            CommandType::Time => Message::Time(command.data_as_datetime().map_err(ValidationError::InvalidTime)?),
+            CommandType::TimeNanos => {
+                Message::TimeNanos(command.data_as_datetime().map_err(ValidationError::InvalidTime)?)
+            }
+            CommandType::StartupCommand => Message::StartupCommand(filename(command.command_type, command.data)?),
+            CommandType::StartupCommandAcknowledge => {
+                let status = command.startup_status()?;
+                let (name, _) = StartupStatus::split_data(&command.data);
+                let name = match name {
+                    [] => None,
+                    name => Some(filename(command.command_type, name.to_vec())?),
+                };
+                Message::StartupCommandAck(name, status)
+            }
+            CommandType::Initialised => Message::Initialised(command.boot_status()?),
+            CommandType::PowerDown => Message::PowerDown(command.power_down_reason()?),
+            CommandType::Heartbeat => Message::Heartbeat,
+            CommandType::Version => Message::Version(command.data[0]),
+            CommandType::VersionAcknowledge => Message::VersionAck(command.data[0]),
+            CommandType::Nack => Message::Nack(command.rejection()?),
+            CommandType::TimeAcknowledge
+            | CommandType::InitialisedAcknowledge
+            | CommandType::PowerDownAcknowledge
+            | CommandType::HeartbeatAcknowledge => Message::Ack(command.command_type),
+            _ => Message::Other(command),
+        };
+        Ok(message)
+    }
+}
+
+impl TryFrom<Message> for Command {
+    type Error = ValidationError;
+
+    /// Encode a Message's data into a Command, with no sequence number or address
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The Message to encode
+    ///
+    /// # Returns
+    ///
+    /// * The Command carrying the message
+    ///
+    /// # Errors
+    ///
+    /// * `ValidationError::InvalidTime` if a TimeNanos time is out of range for `Command::try_time_nanos`
+    ///
+    fn try_from(message: Message) -> Result<Command, ValidationError> {
+        let command = match message {
+            Message::Time(time) => Command::time(time),
+            Message::TimeNanos(time) => Command::try_time_nanos(time)?,
+            Message::StartupCommand(name) => Command::startup_command(name.into_bytes()),
+            Message::StartupCommandAck(name, Some(status)) => {
+                Command::startup_command_ack_with_status(name.as_deref().unwrap_or_default(), status)
+            }
+            Message::StartupCommandAck(Some(name), None) => Command::startup_command_ack(&name),
+            Message::StartupCommandAck(None, None) => Command::simple_command(CommandType::StartupCommandAcknowledge),
+            Message::Initialised(Some(status)) => Command::initialised(status.version, status.status),
+            Message::Initialised(None) => Command::simple_command(CommandType::Initialised),
+            Message::PowerDown(reason) => Command::power_down(reason),
+            Message::Heartbeat => Command::simple_command(CommandType::Heartbeat),
+            Message::Version(version) => Command::new(CommandType::Version, alloc::vec![version]),
+            Message::VersionAck(version) => Command::new(CommandType::VersionAcknowledge, alloc::vec![version]),
+            Message::Nack(rejection) => Command::nack(rejection.command_type, rejection.reason),
+            Message::Ack(command_type) => Command::simple_command(command_type),
+            Message::Other(command) => command,
+        };
+        Ok(command)
+    }
+}
+
+/// The filename carried by a StartupCommand or its acknowledgement
+fn filename(command_type: CommandType, name: Vec<u8>) -> Result<String, ValidationError> {
+    String::from_utf8(name).map_err(|_| ValidationError::InvalidUtf8(command_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_message_round_trip() {
+        let time = chrono::TimeZone::timestamp_millis_opt(&Utc, 1_690_000_000_123).unwrap();
+        let messages = [
+            Message::Time(time),
+            Message::TimeNanos(time),
+            Message::StartupCommand(String::from("orbit05.json")),
+            Message::StartupCommandAck(Some(String::from("orbit05.json")), None),
+            Message::StartupCommandAck(Some(String::from("orbit05.json")), Some(StartupStatus::Rejected(3))),
+            Message::StartupCommandAck(None, Some(StartupStatus::Queued)),
+            Message::StartupCommandAck(None, None),
+            Message::Initialised(Some(BootStatus { version: 3, status: 0 })),
+            Message::Initialised(None),
+            Message::PowerDown(PowerDownReason::Thermal),
+            Message::PowerDown(PowerDownReason::Unspecified),
+            Message::Heartbeat,
+            Message::Version(1),
+            Message::VersionAck(2),
+            Message::Nack(Rejection { command_type: CommandType::PowerDown, reason: 4 }),
+            Message::Ack(CommandType::TimeAcknowledge),
+            Message::Other(Command::new(CommandType::SendFileData, vec![1, 2, 3])),
+        ];
+        for message in messages {
+            let command = Command::try_from(message.clone()).unwrap();
+            let decoded = Command::from_bytes(command.to_bytes()).unwrap();
+            assert_eq!(Message::try_from(decoded), Ok(message));
+        }
+    }
+
+    #[test]
+    fn test_message_from_command() {
+        let command = Command::new_with_seq(CommandType::StartupCommand, b"patch01.json".to_vec(), 5);
+        assert_eq!(Message::try_from(command), Ok(Message::StartupCommand(String::from("patch01.json"))));
+        assert_eq!(
+            Message::try_from(Command::startup_command(vec![0xff])),
+            Err(ValidationError::InvalidUtf8(CommandType::StartupCommand))
+        );
+        assert_eq!(
+            Message::try_from(Command::new(CommandType::Time, vec![0; 4])),
+            Err(ValidationError::WrongLength { command_type: CommandType::Time, expected: 8, actual: 4 })
+        );
+        assert!(matches!(
+            Message::try_from(Command::new(CommandType::Time, vec![0x7f; 8])),
+            Err(ValidationError::InvalidTime(_))
+        ));
+    }
+
+    #[test]
+    fn test_command_from_message_out_of_range() {
+        let late = chrono::TimeZone::with_ymd_and_hms(&Utc, 2300, 1, 1, 0, 0, 0).unwrap();
+        assert!(matches!(Command::try_from(Message::TimeNanos(late)), Err(ValidationError::InvalidTime(_))));
+        assert!(Command::try_from(Message::Time(late)).is_ok());
+    }
+}
@@ -0,0 +1,650 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{invalid_frame, Command, CommandType, FrameDecoder, Rejection, StartupStatus};
+
+/// Number of bytes read from the loopback per read when receiving a message
+const READ_CHUNK_SIZE: usize = 256;
+
+/// Number of times `send_pipelined` sends a command before giving up on its acknowledgement
+const MAX_PIPELINED_ATTEMPTS: u32 = 3;
+
+/// Most commands `send_pipelined` keeps in flight, one per sequence number
+const MAX_WINDOW: usize = 256;
+
+/// A link that commands can be sent and received over
+///
+/// Implemented by `UartConnection` for the payload's serial link and by
+/// `LoopbackTransport` for testing without hardware.
+pub trait Transport {
+    /// Send a command to the peer
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to send
+    ///
+    /// # Returns
+    ///
+    /// * The number of bytes written, the length of the whole frame
+    ///
+    fn send_message(&mut self, command: Command) -> std::io::Result<usize>;
+
+    /// Receive a command from the peer
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - How long to wait for a command
+    ///
+    /// # Returns
+    ///
+    /// * The received command, or None if nothing valid arrived within `timeout`
+    ///
+    fn receive_message(&mut self, timeout: Duration) -> std::io::Result<Option<Command>>;
+
+    /// Send a command and wait for its acknowledgement
+    ///
+    /// If the command has a sequence number, only an acknowledgement echoing
+    /// it is accepted, likewise a startup command acknowledgement echoing a
+    /// different filename is ignored, see `Command::acknowledges`. A Nack
+    /// rejecting the command ends the wait straight away, see `Command::rejects`,
+    /// as does a startup command acknowledgement with a `Rejected` status.
+    /// Any other commands received while waiting are discarded.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to send
+    /// * `timeout` - How long to wait for the acknowledgement
+    ///
+    /// # Returns
+    ///
+    /// * The acknowledgement command
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidInput` if the command type is not acknowledged
+    /// * `TimedOut` if no acknowledgement arrived within `timeout`
+    /// * An error carrying the `Rejection` if the peer rejected the command,
+    ///   including a startup command acknowledged as `StartupStatus::Rejected`
+    ///
+    fn send_and_await_ack(&mut self, command: Command, timeout: Duration) -> std::io::Result<Command> {
+        let ack_type = command.command_type.expected_ack().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{:?} is not acknowledged", command.command_type),
+            )
+        })?;
+        let deadline = Instant::now() + timeout;
+        self.send_message(command.clone())?;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            if let Some(response) = self.receive_message(remaining)? {
+                if response.acknowledges(&command) {
+                    return match rejected_startup(&response) {
+                        Some(error) => Err(error),
+                        None => Ok(response),
+                    };
+                }
+                if response.rejects(&command) {
+                    return Err(rejected(&response));
+                }
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("No {:?} received", ack_type),
+        ))
+    }
+
+    /// Send a command and wait for its acknowledgement, resending it if none arrives
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to send
+    /// * `max_attempts` - The maximum number of times to send the command
+    /// * `backoff` - How long to wait between attempts, a Duration for a constant delay
+    /// * `timeout` - How long to wait for the acknowledgement on each attempt
+    ///
+    /// # Returns
+    ///
+    /// * The acknowledgement command
+    ///
+    /// # Errors
+    ///
+    /// * An error carrying `RetriesExhausted` if no attempt was acknowledged
+    /// * An error carrying the `Rejection` if the peer rejected the command,
+    ///   which is not resent
+    ///
+    fn send_with_retry(
+        &mut self,
+        command: Command,
+        max_attempts: u32,
+        backoff: impl Into<Backoff>,
+        timeout: Duration,
+    ) -> std::io::Result<Command>
+    where
+        Self: Sized,
+    {
+        let backoff = backoff.into();
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match self.send_and_await_ack(command.clone(), timeout) {
+                Ok(ack) => return Ok(ack),
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidInput || is_rejection(&e) => return Err(e),
+                Err(e) if attempts >= max_attempts => {
+                    return Err(std::io::Error::new(
+                        e.kind(),
+                        RetriesExhausted { attempts, last_error: e },
+                    ))
+                }
+                Err(_) => std::thread::sleep(backoff.delay(attempts)),
+            }
+        }
+    }
+
+    /// Send commands keeping up to `window` of them awaiting acknowledgement at once
+    ///
+    /// Rather than waiting a round trip after every command, the next command
+    /// is sent as soon as one in flight is acknowledged. Each command is sent
+    /// with a sequence number assigned in order, replacing any it had, and
+    /// acknowledgements are matched to commands by it. A command whose
+    /// acknowledgement has not arrived within `timeout` is resent, up to
+    /// three times in all.
+    ///
+    /// # Arguments
+    ///
+    /// * `commands` - The commands to send, in order
+    /// * `window` - The most commands awaiting acknowledgement at once, at most 256
+    /// * `timeout` - How long to wait for each acknowledgement before resending
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidInput` if any command type is not acknowledged, before anything is sent
+    /// * A `TimedOut` error carrying `RetriesExhausted` if a command was never acknowledged
+    /// * An error carrying the `Rejection` if the peer rejected a command
+    ///
+    fn send_pipelined(&mut self, commands: Vec<Command>, window: usize, timeout: Duration) -> std::io::Result<()> {
+        if let Some(command) = commands.iter().find(|c| c.command_type.expected_ack().is_none()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{:?} is not acknowledged", command.command_type),
+            ));
+        }
+        let window = window.clamp(1, MAX_WINDOW);
+        let mut pending = commands.into_iter().enumerate().map(|(index, mut command)| {
+            command.seq = Some(index as u8);
+            command
+        });
+        let mut in_flight: VecDeque<InFlight> = VecDeque::with_capacity(window);
+        loop {
+            while in_flight.len() < window {
+                let Some(command) = pending.next() else { break };
+                self.send_message(command.clone())?;
+                in_flight.push_back(InFlight { command, attempts: 1, deadline: Instant::now() + timeout });
+            }
+            let Some(earliest) = in_flight.iter().map(|flight| flight.deadline).min() else {
+                return Ok(());
+            };
+
+            let now = Instant::now();
+            if earliest <= now {
+                for flight in in_flight.iter_mut().filter(|flight| flight.deadline <= now) {
+                    if flight.attempts >= MAX_PIPELINED_ATTEMPTS {
+                        let last_error = std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            format!("No acknowledgement of {}", flight.command),
+                        );
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            RetriesExhausted { attempts: flight.attempts, last_error },
+                        ));
+                    }
+                    self.send_message(flight.command.clone())?;
+                    flight.attempts += 1;
+                    flight.deadline = now + timeout;
+                }
+                continue;
+            }
+
+            if let Some(response) = self.receive_message(earliest - now)? {
+                if let Some(index) = in_flight.iter().position(|flight| response.acknowledges(&flight.command)) {
+                    if let Some(error) = rejected_startup(&response) {
+                        return Err(error);
+                    }
+                    in_flight.remove(index);
+                } else if in_flight.iter().any(|flight| response.rejects(&flight.command)) {
+                    return Err(rejected(&response));
+                }
+            }
+        }
+    }
+}
+
+/// The error returned when the peer rejects a command
+///
+/// # Arguments
+///
+/// * `nack` - The Nack received, which `rejects` the command
+///
+fn rejected(nack: &Command) -> std::io::Error {
+    let rejection = nack.rejection().expect("a Nack that rejects a command carries a Rejection");
+    std::io::Error::other(rejection)
+}
+
+/// The error returned when the peer acknowledges a startup command as `Rejected`
+///
+/// # Arguments
+///
+/// * `ack` - The acknowledgement received
+///
+/// # Returns
+///
+/// * An error carrying the `Rejection` of the startup command, or None if
+///   `ack` does not reject one
+///
+fn rejected_startup(ack: &Command) -> Option<std::io::Error> {
+    match ack.startup_status() {
+        Ok(Some(StartupStatus::Rejected(reason))) => Some(std::io::Error::other(Rejection {
+            command_type: CommandType::StartupCommand,
+            reason,
+        })),
+        _ => None,
+    }
+}
+
+/// Whether an error is the peer rejecting a command
+fn is_rejection(error: &std::io::Error) -> bool {
+    error.get_ref().is_some_and(|inner| inner.is::<Rejection>())
+}
+
+/// A command sent by `send_pipelined` that has not been acknowledged yet
+struct InFlight {
+    command: Command,
+    /// The number of times the command has been sent
+    attempts: u32,
+    /// When to resend the command if it is still unacknowledged
+    deadline: Instant,
+}
+
+/// Delay between attempts made by `Transport::send_with_retry`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Backoff {
+    /// Wait the same duration between every attempt
+    Constant(Duration),
+    /// Wait `initial` after the first attempt, multiplying the delay by `factor` after each subsequent one
+    Exponential { initial: Duration, factor: u32 },
+}
+
+impl Backoff {
+    /// The delay to wait after a failed attempt
+    ///
+    /// # Arguments
+    ///
+    /// * `attempt` - The number of the attempt that failed, starting at 1
+    ///
+    /// # Returns
+    ///
+    /// * The duration to wait before the next attempt
+    ///
+    pub fn delay(&self, attempt: u32) -> Duration {
+        match *self {
+            Backoff::Constant(delay) => delay,
+            Backoff::Exponential { initial, factor } => {
+                let multiplier = factor.saturating_pow(attempt.saturating_sub(1));
+                initial.saturating_mul(multiplier)
+            }
+        }
+    }
+}
+
+impl From<Duration> for Backoff {
+    fn from(delay: Duration) -> Backoff {
+        Backoff::Constant(delay)
+    }
+}
+
+/// Error carried by the `std::io::Error` returned when `send_with_retry` gives up
+///
+/// # Fields
+///
+/// * `attempts` - The number of times the command was sent
+/// * `last_error` - The error from the final attempt
+///
+#[derive(Debug)]
+pub struct RetriesExhausted {
+    pub attempts: u32,
+    pub last_error: std::io::Error,
+}
+
+impl std::fmt::Display for RetriesExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Gave up after {} attempts: {}", self.attempts, self.last_error)
+    }
+}
+
+impl std::error::Error for RetriesExhausted {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.last_error)
+    }
+}
+
+/// One direction of a `LoopbackTransport`
+#[derive(Default)]
+struct Pipe {
+    bytes: Mutex<VecDeque<u8>>,
+    available: Condvar,
+}
+
+/// An in-memory `Transport` for tests
+///
+/// A transport made with `new` receives whatever it sends, while the two ends
+/// made by `pair` receive what the other sends. Both ends can be moved to
+/// different threads. Raw bytes can also be exchanged through `Read` and `Write`,
+/// reads block for up to the timeout set with `set_timeout`.
+pub struct LoopbackTransport {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    decoder: FrameDecoder,
+    timeout: Duration,
+}
+
+impl LoopbackTransport {
+    /// Create a transport that receives the commands it sends
+    ///
+    /// # Returns
+    ///
+    /// * A new LoopbackTransport
+    ///
+    pub fn new() -> LoopbackTransport {
+        let pipe = Arc::new(Pipe::default());
+        LoopbackTransport::with_pipes(pipe.clone(), pipe)
+    }
+
+    /// Create two transports connected to each other
+    ///
+    /// # Returns
+    ///
+    /// * The two ends of the link
+    ///
+    pub fn pair() -> (LoopbackTransport, LoopbackTransport) {
+        let a_to_b = Arc::new(Pipe::default());
+        let b_to_a = Arc::new(Pipe::default());
+        (
+            LoopbackTransport::with_pipes(b_to_a.clone(), a_to_b.clone()),
+            LoopbackTransport::with_pipes(a_to_b, b_to_a),
+        )
+    }
+
+    fn with_pipes(incoming: Arc<Pipe>, outgoing: Arc<Pipe>) -> LoopbackTransport {
+        LoopbackTransport {
+            incoming,
+            outgoing,
+            decoder: FrameDecoder::new(),
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    /// Set how long `read` blocks waiting for bytes
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The read timeout, defaults to one second
+    ///
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Read from the incoming pipe, blocking no later than `deadline`
+    fn read_before(&mut self, buffer: &mut [u8], deadline: Instant) -> std::io::Result<usize> {
+        let mut bytes = self.incoming.bytes.lock().unwrap();
+        while bytes.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            bytes = self.incoming.available.wait_timeout(bytes, remaining).unwrap().0;
+        }
+        let count = buffer.len().min(bytes.len());
+        for (slot, byte) in buffer.iter_mut().zip(bytes.drain(..count)) {
+            *slot = byte;
+        }
+        Ok(count)
+    }
+}
+
+impl Default for LoopbackTransport {
+    fn default() -> LoopbackTransport {
+        LoopbackTransport::new()
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn send_message(&mut self, command: Command) -> std::io::Result<usize> {
+        let frame = command.to_bytes();
+        self.write_all(&frame)?;
+        Ok(frame.len())
+    }
+
+    fn receive_message(&mut self, timeout: Duration) -> std::io::Result<Option<Command>> {
+        let deadline = Instant::now() + timeout;
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        loop {
+            if let Some(frame) = self.decoder.next_frame().map_err(invalid_frame)? {
+                // Skip undecodable frames and resync on the next one
+                if let Ok(command) = Command::from_bytes(frame) {
+                    return Ok(Some(command));
+                }
+                continue;
+            }
+            match self.read_before(&mut chunk, deadline) {
+                Ok(count) => self.decoder.extend(&chunk[..count]),
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Read for LoopbackTransport {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        if !self.decoder.buffered().is_empty() {
+            return Ok(self.decoder.take_buffered(buffer));
+        }
+        let deadline = Instant::now() + self.timeout;
+        self.read_before(buffer, deadline)
+    }
+}
+
+impl Write for LoopbackTransport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.outgoing.bytes.lock().unwrap().extend(buf);
+        self.outgoing.available.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loopback() {
+        let mut transport = LoopbackTransport::new();
+        let commands = [
+            Command::time_now(),
+            Command::startup_command(b"orbit05.json".to_vec()),
+            Command::simple_command(CommandType::Initialised),
+        ];
+        for command in commands.iter() {
+            assert_eq!(transport.send_message(command.clone()).unwrap(), command.frame_len());
+        }
+        for command in commands.iter() {
+            let received = transport.receive_message(Duration::from_millis(100)).unwrap();
+            assert_eq!(received.as_ref(), Some(command));
+        }
+        assert_eq!(transport.receive_message(Duration::from_millis(10)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_pair_send_and_await_ack() {
+        let (mut ground, mut payload) = LoopbackTransport::pair();
+        let responder = std::thread::spawn(move || {
+            let received = payload.receive_message(Duration::from_secs(1)).unwrap().unwrap();
+            let ack = received.command_type.expected_ack().unwrap();
+            payload.send_message(Command::simple_command(ack)).unwrap();
+        });
+        let ack = ground
+            .send_and_await_ack(Command::simple_command(CommandType::PowerDown), Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(ack.command_type, CommandType::PowerDownAcknowledge);
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn test_send_and_await_ack_matches_seq() {
+        let (mut ground, mut payload) = LoopbackTransport::pair();
+        let responder = std::thread::spawn(move || {
+            let received = payload.receive_message(Duration::from_secs(1)).unwrap().unwrap();
+            let ack = received.command_type.expected_ack().unwrap();
+            let seq = received.seq().unwrap();
+            // A stale acknowledgement for an earlier command is ignored
+            payload.send_message(Command::new_with_seq(ack, Vec::new(), seq.wrapping_sub(1))).unwrap();
+            payload.send_message(Command::new_with_seq(ack, Vec::new(), seq)).unwrap();
+        });
+        let command = Command::new_with_seq(CommandType::Time, vec![0; 8], 7);
+        let ack = ground.send_and_await_ack(command, Duration::from_secs(1)).unwrap();
+        assert_eq!(ack.seq(), Some(7));
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn test_send_and_await_ack_matches_filename() {
+        let (mut ground, mut payload) = LoopbackTransport::pair();
+        let responder = std::thread::spawn(move || {
+            let received = payload.receive_message(Duration::from_secs(1)).unwrap().unwrap();
+            let name = received.data_as_str().unwrap();
+            payload.send_message(Command::startup_command_ack("stale.json")).unwrap();
+            payload.send_message(Command::startup_command_ack(name)).unwrap();
+        });
+        let ack = ground
+            .send_and_await_ack(Command::startup_command(b"patch01.json".to_vec()), Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(ack.data_as_str(), Ok("patch01.json"));
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn test_send_and_await_ack_rejected() {
+        let (mut ground, mut payload) = LoopbackTransport::pair();
+        let responder = std::thread::spawn(move || {
+            let mut received = 0;
+            while let Some(command) = payload.receive_message(Duration::from_millis(300)).unwrap() {
+                received += 1;
+                // A Nack for another command is ignored
+                payload.send_message(Command::nack(CommandType::Heartbeat, 1)).unwrap();
+                payload.send_message(Command::nack_for(&command, 4)).unwrap();
+            }
+            received
+        });
+        let command = Command::new_with_seq(CommandType::PowerDown, Vec::new(), 3);
+        let start_time = Instant::now();
+        let error = ground.send_and_await_ack(command.clone(), Duration::from_secs(5)).unwrap_err();
+        assert!(start_time.elapsed() < Duration::from_secs(1));
+        let rejection = error.get_ref().unwrap().downcast_ref::<Rejection>().unwrap();
+        assert_eq!(*rejection, Rejection { command_type: CommandType::PowerDown, reason: 4 });
+
+        // A rejected command is not resent
+        let error = ground.send_with_retry(command.clone(), 3, Duration::ZERO, Duration::from_secs(5)).unwrap_err();
+        assert!(is_rejection(&error));
+        let error = ground.send_pipelined(vec![command], 4, Duration::from_secs(5)).unwrap_err();
+        assert!(is_rejection(&error));
+        assert_eq!(responder.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_send_and_await_ack_startup_status() {
+        let (mut ground, mut payload) = LoopbackTransport::pair();
+        let responder = std::thread::spawn(move || {
+            let mut received = 0;
+            while let Some(command) = payload.receive_message(Duration::from_millis(300)).unwrap() {
+                received += 1;
+                let name = command.data_as_str().unwrap();
+                let status = match name {
+                    "queued.json" => StartupStatus::Queued,
+                    _ => StartupStatus::Rejected(6),
+                };
+                let mut ack = Command::startup_command_ack_with_status(name, status);
+                ack.seq = command.seq();
+                payload.send_message(ack).unwrap();
+            }
+            received
+        });
+        let ack = ground
+            .send_and_await_ack(Command::startup_command(b"queued.json".to_vec()), Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(ack.startup_status(), Ok(Some(StartupStatus::Queued)));
+
+        let command = Command::startup_command(b"missing.json".to_vec());
+        let error = ground.send_and_await_ack(command.clone(), Duration::from_secs(1)).unwrap_err();
+        let rejection = error.get_ref().unwrap().downcast_ref::<Rejection>().unwrap();
+        assert_eq!(*rejection, Rejection { command_type: CommandType::StartupCommand, reason: 6 });
+
+        // A rejected startup command is not resent
+        let error = ground.send_with_retry(command.clone(), 3, Duration::ZERO, Duration::from_secs(1)).unwrap_err();
+        assert!(is_rejection(&error));
+        let error = ground.send_pipelined(vec![command], 4, Duration::from_secs(1)).unwrap_err();
+        assert!(is_rejection(&error));
+        assert_eq!(responder.join().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_send_pipelined() {
+        let (mut ground, mut payload) = LoopbackTransport::pair();
+        let responder = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            let mut dropped = false;
+            while let Some(command) = payload.receive_message(Duration::from_millis(300)).unwrap() {
+                received.push(command.seq().unwrap());
+                // Lose the first transmission of the third command
+                if command.seq() == Some(2) && !dropped {
+                    dropped = true;
+                    continue;
+                }
+                payload.send_message(Command::ack_for(&command).unwrap()).unwrap();
+            }
+            received
+        });
+        let commands: Vec<Command> = (0..10u8)
+            .map(|i| Command::startup_command(format!("patch{:02}.json", i).into_bytes()))
+            .collect();
+        ground.send_pipelined(commands, 4, Duration::from_millis(100)).unwrap();
+        let received = responder.join().unwrap();
+        assert_eq!(received.len(), 11);
+        assert_eq!(received.iter().filter(|&&seq| seq == 2).count(), 2);
+        for seq in 0..10 {
+            assert!(received.contains(&seq));
+        }
+    }
+
+    #[test]
+    fn test_send_pipelined_errors() {
+        let mut ground = LoopbackTransport::pair().0;
+        let error = ground
+            .send_pipelined(vec![Command::simple_command(CommandType::TimeAcknowledge)], 4, Duration::from_millis(10))
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+        let error = ground
+            .send_pipelined(vec![Command::simple_command(CommandType::Heartbeat)], 4, Duration::from_millis(10))
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        let retries = error.get_ref().unwrap().downcast_ref::<RetriesExhausted>().unwrap();
+        assert_eq!(retries.attempts, 3);
+        assert!(ground.send_pipelined(Vec::new(), 4, Duration::from_millis(10)).is_ok());
+    }
+}
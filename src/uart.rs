@@ -1,23 +1,110 @@
 use std::time::{Duration, Instant};
 use serial::*;
 // use uart_rs::{Connection, UartResult};
-use crate::{Command, CommandType, Ftp};
+use crate::{Command, ParseError, ReceiveError, FrameDecoder, Framing, Ftp, FtpError, FtpConfig, FtpProgress, Transport, DEFAULT_MAX_FRAME_SIZE};
 use std::io::{Read, Write};
-use std::fs::File;
-use serial::{SerialPort, SerialPortSettings};
-use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use serial::SerialPort;
+use crate::ftp::FtpLink;
+use log::{debug, info, trace, warn};
 
-const UART_RECEIVE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Number of bytes requested from the UART device per read
+const READ_CHUNK_SIZE: usize = 256;
+
+/// Longest to sleep between reads when the device returns without waiting
+/// for data, so an idle receive does not spin
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Software flow control byte asking the sender to resume
+const XON: u8 = 0x11;
+
+/// Software flow control byte asking the sender to pause
+const XOFF: u8 = 0x13;
+
+/// Precedes a data byte that would otherwise be taken for XON, XOFF or
+/// itself with software flow control, the byte following it is XORed with
+/// `FLOW_CONTROL_ESCAPE_MASK`
+const FLOW_CONTROL_ESCAPE: u8 = 0x7d;
+
+/// XORed with an escaped data byte, so it is sent as neither XON, XOFF nor `FLOW_CONTROL_ESCAPE`
+const FLOW_CONTROL_ESCAPE_MASK: u8 = 0x20;
+
+/// Most bytes written at once with software flow control, so an XOFF is
+/// acted on before much more is sent
+const FLOW_CONTROL_CHUNK_SIZE: usize = 16;
+
+/// Number of times the device is reopened after a disconnect before giving up
+const MAX_RECONNECT_ATTEMPTS: u32 = 3;
+
+/// Time to wait between attempts to reopen the device, e.g. while a USB
+/// adapter re-enumerates
+const RECONNECT_DELAY: Duration = Duration::from_millis(250);
+
+/// Settings used by `UartConnection::with_defaults` and `UartConnectionBuilder`:
+/// 115200 baud, 8 data bits, no parity, 1 stop bit and no flow control
+pub const DEFAULT_PORT_SETTINGS: PortSettings = PortSettings {
+    baud_rate: Baud115200,
+    char_size: Bits8,
+    parity: ParityNone,
+    stop_bits: Stop1,
+    flow_control: FlowNone,
+};
+
+/// Timeout used by `UartConnection::with_defaults` and `UartConnectionBuilder`
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Counters describing the traffic over a UartConnection
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Stats {
+    /// Bytes written to the device, including file transfers
+    pub bytes_sent: u64,
+    /// Bytes read from the device, including file transfers
+    pub bytes_received: u64,
+    /// Frames received and decoded into commands
+    pub frames_decoded: u64,
+    /// Frames received that could not be decoded
+    pub parse_errors: u64,
+    /// Receives that timed out with nothing decoded
+    pub timeouts: u64,
+}
 
 pub struct UartConnection {
-    // port: Box<dyn SerialPort>,
+    /// The open device, or None until it is first used when created with `new`
+    port: Option<Box<dyn SerialPort + Send>>,
     path: String,
     settings: PortSettings,
     timeout: Duration,
+    /// Bytes read from the device that have not yet been handed out
+    decoder: FrameDecoder,
+    /// How file transfers are run
+    ftp: FtpConfig,
+    stats: Stats,
+    /// Whether to reopen the device when it is found to be disconnected
+    auto_reconnect: bool,
+    /// Longest a frame may take to write, or None to use `timeout` per write
+    send_timeout: Option<Duration>,
+    /// Called with true before transmitting and false once transmission has drained
+    turnaround: Option<Box<dyn FnMut(bool) + Send>>,
+    /// Whether `turnaround` has been told the line is transmitting
+    transmitting: bool,
+    /// Whether to log a hexdump of every frame sent and received
+    trace_frames: bool,
+    /// Our address on a multi-drop bus, or None if frames are not addressed
+    device_address: Option<u8>,
+    /// Whether to act on and strip XON and XOFF bytes from the payload
+    software_flow_control: bool,
+    /// Whether the payload has sent XOFF and not yet XON
+    paused: bool,
+    /// Whether the last byte read was `FLOW_CONTROL_ESCAPE`, so the next is escaped data
+    unescape_next: bool,
 }
 
 impl UartConnection {
-    /// Create a new UartConnection
+    /// Create a new UartConnection without opening the UART device
+    ///
+    /// The device is opened the first time the connection is used, so errors
+    /// such as a missing device only surface then. Use `connect` to open it
+    /// straight away.
     ///
     /// # Arguments
     ///
@@ -29,181 +116,2553 @@ impl UartConnection {
     ///
     /// * A new UartConnection
     ///
-    pub fn new(
-        uart_path: String,
-        uart_setting: PortSettings,
-        uart_timeout: Duration,
-    ) -> std::io::Result<Self> {
-        // let mut port = serial::open(&uart_path)?;
-        // port.configure(&uart_setting)?;
-        // port.set_timeout(uart_timeout)?;
-        Ok(Self {
+    pub fn new(uart_path: String, uart_setting: PortSettings, uart_timeout: Duration) -> Self {
+        Self {
+            port: None,
             path: uart_path,
             settings: uart_setting,
             timeout: uart_timeout,
-        })
+            decoder: FrameDecoder::new(),
+            ftp: FtpConfig::default(),
+            stats: Stats::default(),
+            auto_reconnect: false,
+            send_timeout: None,
+            turnaround: None,
+            transmitting: false,
+            trace_frames: false,
+            device_address: None,
+            software_flow_control: false,
+            paused: false,
+            unescape_next: false,
+        }
     }
 
-    /// Send a message to the UART device
+    /// Create a new UartConnection with `DEFAULT_PORT_SETTINGS` and a one second timeout
+    ///
+    /// Like `new`, the device is not opened until the connection is first
+    /// used. Use `UartConnectionBuilder` to change any of the settings.
     ///
     /// # Arguments
     ///
-    /// * `command` - The command to send
+    /// * `uart_path` - The path to the UART device
     ///
     /// # Returns
     ///
-    /// * A UartResult containing the result of the send
+    /// * A new UartConnection
     ///
-    pub fn send_message(&mut self, command: Command) -> std::io::Result<()> {
-        let data = command.to_bytes();
-        let mut port = serial::open(&self.path)?;
-        port.configure(&self.settings)?;
-        port.set_timeout(self.timeout)?;
-        match port.write(&data) {
-            Ok(_) => {
-                println!("Sent: {:?}", data);
-                Ok(())
-            }
-            Err(e) => Err(e),
-        }
+    pub fn with_defaults(uart_path: &str) -> Self {
+        Self::new(String::from(uart_path), DEFAULT_PORT_SETTINGS, DEFAULT_TIMEOUT)
     }
 
-    /// Receive a message from the UART device
+    /// Create a new UartConnection, opening and configuring the UART device
+    ///
+    /// The opened device is kept and reused for every later call.
     ///
     /// # Arguments
     ///
-    /// * `timeout` - The timeout of the receive
+    /// * `uart_path` - The path to the UART device
+    /// * `uart_setting` - The settings of the UART device
+    /// * `uart_timeout` - The timeout of the UART device
     ///
     /// # Returns
     ///
-    /// * An Option containing the received message
+    /// * A new UartConnection with the device open
     ///
-    pub fn receive_message(&mut self, timeout: Duration) -> std::io::Result<Option<Command>> {
-        let start_time = Instant::now();
-        let mut data = Vec::new();
-        loop {
-            if start_time.elapsed() > timeout {
-                break;
-            }
-            let mut buffer = [0u8; 1];
-            if let Ok(response) = self.read(&mut buffer) {
-                let byte = buffer[0];
-                data.push(byte);
-                if byte == 0 {
-                    break;
-                }
-            }
+    /// # Errors
+    ///
+    /// * If the device could not be opened or configured
+    ///
+    pub fn connect(
+        uart_path: String,
+        uart_setting: PortSettings,
+        uart_timeout: Duration,
+    ) -> std::io::Result<Self> {
+        let mut connection = Self::new(uart_path, uart_setting, uart_timeout);
+        connection.reconnect()?;
+        Ok(connection)
+    }
+
+    /// Reopen the UART device, e.g. after it has been disconnected
+    ///
+    /// # Returns
+    ///
+    /// * An error if the device could not be opened or configured
+    ///
+    pub fn reconnect(&mut self) -> std::io::Result<()> {
+        self.port = Some(open_port(&self.path, &self.settings, self.timeout)?);
+        Ok(())
+    }
+
+    /// Flush outstanding output and close the UART device
+    ///
+    /// The device is released as soon as this returns, so another process
+    /// can open it straight away. Dropping the connection does the same but
+    /// can only log a failed flush.
+    ///
+    /// # Errors
+    ///
+    /// * If the outstanding output could not be flushed, the device is closed regardless
+    ///
+    pub fn close(mut self) -> std::io::Result<()> {
+        self.release()
+    }
+
+    /// Flush outstanding output, release the line and drop the device handle
+    fn release(&mut self) -> std::io::Result<()> {
+        let flushed = match self.port.as_mut() {
+            Some(port) => port.flush(),
+            None => Ok(()),
+        };
+        let released = self.end_transmit();
+        if self.port.take().is_some() {
+            debug!("Closed {}", self.path);
         }
-        println!("Received: {:?}", data);
-        Ok(Command::from_bytes(data))
+        flushed.and(released)
     }
 
-    pub fn receive_init(&mut self, timeout: Duration) -> std::io::Result<()> {
-        let start_time = Instant::now();
-        let mut data = Vec::new();
+    /// The open UART device, opening it first if this is its first use
+    fn port(&mut self) -> std::io::Result<&mut Box<dyn SerialPort + Send>> {
+        if self.port.is_none() {
+            self.reconnect()?;
+        }
+        Ok(self.port.as_mut().expect("port was just opened"))
+    }
+
+    /// Set the port timeout back to `self.timeout` after reading with a deadline
+    fn restore_timeout(&mut self) -> std::io::Result<()> {
+        if let Some(port) = self.port.as_mut() {
+            port.set_timeout(self.timeout)?;
+        }
+        Ok(())
+    }
+
+    /// Reopen the device after a disconnect, trying up to `MAX_RECONNECT_ATTEMPTS` times
+    ///
+    /// # Returns
+    ///
+    /// * The error from the last attempt if the device could not be reopened
+    ///
+    fn reopen(&mut self) -> std::io::Result<()> {
+        self.port = None;
+        let mut attempt = 1;
         loop {
-            if start_time.elapsed() > timeout {
-                break;
-            }
-            let mut buffer = [0u8; 1];
-            if let Ok(response) = self.read(&mut buffer) {
-                let byte = buffer[0];
-                data.push(byte);
-                if byte == 0 {
-                    if data[data.len() - 3] == 0x02 && data[data.len() - 2] == 0x02 && data[data.len() - 1] == 0x00 {
-                        // info!("Initialised");
-                        break;
-                    }
+            match self.reconnect() {
+                Ok(()) => {
+                    info!("Reconnected to {}", self.path);
+                    return Ok(());
+                }
+                Err(e) if attempt >= MAX_RECONNECT_ATTEMPTS => {
+                    warn!("Giving up reconnecting to {}: {}", self.path, e);
+                    return Err(e);
+                }
+                Err(e) => {
+                    warn!("Reconnecting to {} failed (attempt {}): {}", self.path, attempt, e);
+                    attempt += 1;
+                    std::thread::sleep(RECONNECT_DELAY);
                 }
             }
         }
+    }
+
+    /// Set whether to reopen the device when it is found to be disconnected
+    ///
+    /// When set, `send_message` and `receive_message` reopen the device, e.g.
+    /// after a USB adapter is unplugged and plugged back in, and carry on. If
+    /// it cannot be reopened they fail with `ReceiveError::Disconnected`, or a
+    /// `NotConnected` error from `send_message`.
+    ///
+    /// # Arguments
+    ///
+    /// * `auto_reconnect` - Whether to reconnect, defaults to false
+    ///
+    pub fn set_auto_reconnect(&mut self, auto_reconnect: bool) {
+        self.auto_reconnect = auto_reconnect;
+    }
+
+    /// Set the timeout used for reads and writes
+    ///
+    /// Reconfigures the device if it is already open. Bytes already read but
+    /// not yet decoded are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The new timeout of the UART device
+    ///
+    /// # Errors
+    ///
+    /// * If the open device could not be reconfigured
+    ///
+    pub fn set_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        self.timeout = timeout;
+        if let Some(port) = self.port.as_mut() {
+            port.set_timeout(timeout)?;
+        }
         Ok(())
     }
-}
 
-impl Read for UartConnection {
-    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
-        let mut port = serial::open(&self.path)?;
-        port.configure(&self.settings)?;
-        port.set_timeout(self.timeout)?;
-        Ok(port.read(buffer)?)
+    /// Set the baud rate, character size, parity, stop bits and flow control
+    ///
+    /// Reconfigures the device if it is already open. Bytes already read but
+    /// not yet decoded are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `settings` - The new settings of the UART device
+    ///
+    /// # Errors
+    ///
+    /// * If the open device could not be reconfigured
+    ///
+    pub fn set_settings(&mut self, settings: PortSettings) -> std::io::Result<()> {
+        self.settings = settings;
+        if let Some(port) = self.port.as_mut() {
+            port.configure(&settings)?;
+        }
+        Ok(())
     }
-}
 
-impl Write for UartConnection {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let mut port = serial::open(&self.path)?;
-        port.configure(&self.settings)?;
-        port.set_timeout(self.timeout)?;
-        port.write(buf)?;
-        Ok(buf.len())
+    /// The settings file transfers over this connection use
+    pub fn ftp_config(&self) -> &FtpConfig {
+        &self.ftp
     }
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        let mut port = serial::open(&self.path)?;
-        port.configure(&self.settings)?;
-        port.set_timeout(self.timeout)?;
-        Ok(port.flush()?)
-        // Ok(())
+    /// Replace the settings file transfers over this connection use
+    ///
+    /// `set_ftp_root`, `set_ftp_chunk_size`, `set_ftp_verify_chunks` and
+    /// `set_ftp_timeout` each change one of them.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The new settings, defaults to `FtpConfig::default()`
+    ///
+    pub fn set_ftp_config(&mut self, config: FtpConfig) {
+        self.ftp = config;
     }
-}
 
-impl Ftp for UartConnection {
-    fn ftp(&mut self) -> std::io::Result<()> {
-        let mut buffer = [0; 1024];
-        let mut file_name = String::new();
+    /// Set the directory that files received with `ftp` are written into
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - The destination directory, defaults to the working directory
+    ///
+    pub fn set_ftp_root(&mut self, root: impl Into<PathBuf>) {
+        self.ftp.dest_root = root.into();
+    }
 
-        // Receive file name
-        loop {
-            let bytes_read = self.read(&mut buffer)?;
-            file_name.push_str(std::str::from_utf8(&buffer[..bytes_read]).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?);
-            if bytes_read < buffer.len() {
-                break;
-            }
-        }
+    /// Set the largest frame `receive_message` accepts
+    ///
+    /// Longer frames are discarded and reported as an `InvalidData` error
+    /// carrying `ParseError::FrameTooLarge`.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_frame_size` - The largest frame, excluding its terminator,
+    ///   defaults to `DEFAULT_MAX_FRAME_SIZE`
+    ///
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.decoder.set_max_frame_size(max_frame_size);
+    }
 
-        // Remove trailing null bytes and any directory path
-        file_name = file_name.trim_end_matches(char::from(0)).rsplit('/').next().unwrap().to_string();
+    /// The traffic counters accumulated since the connection was created or last reset
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
 
-        // Send READY_RECEIVE_FILE message
-        self.write_all(b"READY_RECEIVE_FILE")?;
+    /// Reset the traffic counters to zero
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::default();
+    }
 
-        // Receive file data
-        let mut file_data = Vec::new();
-        loop {
-            let bytes_read = self.read(&mut buffer)?;
-            file_data.extend_from_slice(&buffer[..bytes_read]);
-            if bytes_read < buffer.len() {
-                break;
-            }
-        }
+    /// Set how commands are delimited in both directions
+    ///
+    /// # Arguments
+    ///
+    /// * `framing` - The framing both ends use, defaults to `Framing::Cobs`
+    ///
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.decoder.set_framing(framing);
+    }
+
+    /// Set the size of the chunks files are transferred in
+    ///
+    /// The receiver rejects chunks larger than its own chunk size.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunk_size` - The chunk size in bytes, defaults to `FTP_CHUNK_SIZE`
+    ///
+    pub fn set_ftp_chunk_size(&mut self, chunk_size: usize) {
+        self.ftp.chunk_size = chunk_size.max(1);
+    }
+
+    /// Set whether each chunk of file data carries its own SHA-256
+    ///
+    /// This lets a corrupted chunk be resent on its own rather than restarting
+    /// the whole transfer. Both ends must use the same setting.
+    ///
+    /// # Arguments
+    ///
+    /// * `verify_chunks` - Whether to hash each chunk, defaults to true
+    ///
+    pub fn set_ftp_verify_chunks(&mut self, verify_chunks: bool) {
+        self.ftp.verify_per_chunk = verify_chunks;
+    }
+
+    /// Set the longest each phase of a file transfer may take
+    ///
+    /// Receiving the file name and size, each chunk, the file hash and each
+    /// response from the peer are separate phases. A transfer whose peer
+    /// stops responding fails with `FtpError::Timeout` after this long.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The timeout of each phase, defaults to `FTP_TIMEOUT`
+    ///
+    pub fn set_ftp_timeout(&mut self, timeout: Duration) {
+        self.ftp.phase_timeout = timeout;
+    }
 
-        // Send RECEIVED_FILE_DATA message
-        self.write_all(b"RECEIVED_FILE_DATA")?;
+    /// Set the longest a whole frame may take to write and flush
+    ///
+    /// # Arguments
+    ///
+    /// * `send_timeout` - The send timeout, or None to only bound each write
+    ///   by the connection timeout, defaults to None
+    ///
+    pub fn set_send_timeout(&mut self, send_timeout: Option<Duration>) {
+        self.send_timeout = send_timeout;
+    }
+
+    /// Set a hook that switches the line direction, for half-duplex buses such as RS-485
+    ///
+    /// The hook is called with true before anything is written, e.g. to assert
+    /// the transceiver's driver enable line, and with false once everything
+    /// written has been flushed out of the UART. Frames sent with
+    /// `send_message` release the line straight away, bytes written through
+    /// `Write` release it on `flush` or before the next read.
+    ///
+    /// # Arguments
+    ///
+    /// * `turnaround` - Called with whether the connection is about to transmit
+    ///
+    pub fn set_turnaround(&mut self, turnaround: impl FnMut(bool) + Send + 'static) {
+        self.turnaround = Some(Box::new(turnaround));
+    }
+
+    /// Set whether to log a hexdump of every frame sent and received
+    ///
+    /// Frames are logged at debug level along with the command they decode
+    /// to, for comparing the bytes on the wire against the peer's.
+    ///
+    /// # Arguments
+    ///
+    /// * `trace_frames` - Whether to log frames, defaults to false
+    ///
+    pub fn set_trace_frames(&mut self, trace_frames: bool) {
+        self.trace_frames = trace_frames;
+    }
+
+    /// Set our address on a bus shared by several devices
+    ///
+    /// With an address set, every received frame is expected to start with
+    /// the address of the device it is for, and frames for other devices are
+    /// skipped. Commands sent should be made with `Command::new_addressed`.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Our address, or None if frames are not addressed, defaults to None
+    ///
+    pub fn set_device_address(&mut self, address: Option<u8>) {
+        self.device_address = address;
+    }
+
+    /// Set whether to honour XON/XOFF software flow control from the payload
+    ///
+    /// When set, XON (0x11) and XOFF (0x13) bytes are removed from everything
+    /// read before it reaches the frame decoder. After an XOFF, sends wait
+    /// for an XON, failing with `TimedOut` if none arrives within the send
+    /// timeout, or the connection timeout if there is none. Output is written
+    /// a few bytes at a time so an XOFF takes effect mid-frame.
+    ///
+    /// Frames and file data carry 0x11 and 0x13 as ordinary bytes, so both
+    /// sides escape them: XON, XOFF and the escape byte 0x7d are sent as 0x7d
+    /// followed by the byte XORed with 0x20, as in PPP. The payload must
+    /// escape its output the same way and unescape ours.
+    ///
+    /// # Arguments
+    ///
+    /// * `software_flow_control` - Whether to honour XON/XOFF, defaults to false
+    ///
+    pub fn set_software_flow_control(&mut self, software_flow_control: bool) {
+        self.software_flow_control = software_flow_control;
+        self.paused = false;
+        self.unescape_next = false;
+    }
 
-        // Compute file hash
-        let file_hash = Sha256::digest(&file_data);
+    /// Our address on a bus shared by several devices, if one is set
+    pub fn device_address(&self) -> Option<u8> {
+        self.device_address
+    }
 
-        // Send SEND_FILE_HASH message
-        self.write_all(b"SEND_FILE_HASH")?;
+    /// Decode a received frame, with its address if frames are addressed
+    fn decode(&self, frame: Vec<u8>) -> std::result::Result<Command, ParseError> {
+        match self.device_address {
+            Some(_) => Command::from_bytes_addressed_with(frame, self.decoder.framing()),
+            None => Command::from_bytes_with(frame, self.decoder.framing()),
+        }
+    }
 
-        // Receive file hash
-        let mut hash_buffer = [0; 32];
-        self.read_exact(&mut hash_buffer)?;
+    /// Whether a received command is for this device rather than another on the bus
+    fn addressed_to_us(&self, command: &Command) -> bool {
+        self.device_address.is_none() || command.address() == self.device_address
+    }
 
-        // Check file hash
-        if hash_buffer != file_hash.as_slice() {
-            self.write_all(b"RECEIVE_FILE_ERROR_RETRY")?;
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "File hash does not match"));
+    /// Log a hexdump of a frame if `trace_frames` is set
+    ///
+    /// # Arguments
+    ///
+    /// * `direction` - Whether the frame was "Sent" or "Received"
+    /// * `frame` - The raw frame
+    /// * `decoded` - The command the frame decodes to, or why it does not
+    ///
+    fn trace_frame(&self, direction: &str, frame: &[u8], decoded: std::result::Result<&Command, &ParseError>) {
+        if !self.trace_frames {
+            return;
         }
+        match decoded {
+            Ok(command) => debug!("{} {} frame ({} bytes):\n{}", direction, command.command_type, frame.len(), Hexdump(frame)),
+            Err(e) => debug!("{} undecodable frame ({}, {} bytes):\n{}", direction, e, frame.len(), Hexdump(frame)),
+        }
+    }
 
-        // Send RECEIVE_FILE_SUCCESS message
-        self.write_all(b"RECEIVE_FILE_SUCCESS")?;
+    /// Send a message to the UART device
+    ///
+    /// The whole frame is written and flushed before returning.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to send
+    ///
+    /// # Returns
+    ///
+    /// * The number of bytes written, the length of the whole frame
+    ///
+    /// # Errors
+    ///
+    /// * An `InvalidInput` error if the command is too long for the framing, see
+    ///   `Command::try_to_bytes_with`
+    /// * A `TimedOut` error if the frame could not be written within the send timeout
+    /// * If writing to the device failed
+    ///
+    pub fn send_message(&mut self, command: Command) -> std::io::Result<usize> {
+        let data = command.try_to_bytes_with(self.decoder.framing()).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is too long for {:?} framing", command.command_type, self.decoder.framing()),
+            )
+        })?;
+        let mut result = self.write_frame(&data);
+        if let Err(e) = &result {
+            if self.auto_reconnect && is_disconnect(e) {
+                warn!("Device disconnected while sending: {}", e);
+                self.reopen().map_err(|e| std::io::Error::from(ReceiveError::Disconnected(e)))?;
+                result = self.write_frame(&data);
+            }
+        }
+        match result {
+            Ok(_) => {
+                self.stats.bytes_sent += data.len() as u64;
+                debug!("Sent: {}", command);
+                trace!("Sent frame: {:02x?}", data);
+                self.trace_frame("Sent", &data, Ok(&command));
+                Ok(data.len())
+            }
+            Err(e) => Err(e),
+        }
+    }
 
-        // Write file data to disk
-        let mut file = File::create(&file_name)?;
-        file.write_all(&file_data)?;
+    /// Receive a message from the UART device
+    ///
+    /// If a frame fails to decode, such as the tail of a frame whose start was
+    /// missed, the error is returned and the bytes following its terminator are
+    /// kept, so the next call resyncs on the next frame.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The timeout of the receive
+    ///
+    /// # Returns
+    ///
+    /// * The received message
+    ///
+    /// # Errors
+    ///
+    /// * `ReceiveError::Timeout` if no frame arrived within `timeout`
+    /// * `ReceiveError::Parse` if a frame arrived but could not be decoded
+    /// * `ReceiveError::Io` if reading from the device failed
+    /// * `ReceiveError::Disconnected` if the device was disconnected and auto
+    ///   reconnect could not reopen it
+    ///
+    pub fn receive_message(&mut self, timeout: Duration) -> std::result::Result<Command, ReceiveError> {
+        self.receive_message_until(Instant::now() + timeout)
+    }
 
-        Ok(())
+    /// Receive a message from the UART device, waiting no later than `deadline`
+    ///
+    /// Unlike `receive_message`, a sequence of receives can share one deadline
+    /// without each one extending it.
+    ///
+    /// # Arguments
+    ///
+    /// * `deadline` - The instant after which the receive gives up
+    ///
+    /// # Returns
+    ///
+    /// * The received message
+    ///
+    /// # Errors
+    ///
+    /// * As for `receive_message`, with `ReceiveError::Timeout` once `deadline` passes
+    ///
+    pub fn receive_message_until(&mut self, deadline: Instant) -> std::result::Result<Command, ReceiveError> {
+        let result = loop {
+            let frame = match self.next_frame(deadline) {
+                Ok(frame) => frame,
+                Err(e) => break Err(e),
+            };
+            let decoded = self.decode(frame.clone());
+            self.trace_frame("Received", &frame, decoded.as_ref());
+            match decoded {
+                Ok(command) if !self.addressed_to_us(&command) => {
+                    trace!("Skipping {} for device {:?}", command, command.address());
+                }
+                Ok(command) => break Ok(command),
+                Err(e) => {
+                    debug!("Received undecodable frame {:02x?} ({}), resyncing", frame, e);
+                    break Err(ReceiveError::Parse(e));
+                }
+            }
+        };
+        match &result {
+            Ok(command) => {
+                self.stats.frames_decoded += 1;
+                trace!("Received: {}", command);
+            }
+            Err(ReceiveError::Parse(_)) => self.stats.parse_errors += 1,
+            Err(ReceiveError::Timeout) => self.stats.timeouts += 1,
+            Err(ReceiveError::Io(_)) | Err(ReceiveError::Disconnected(_)) => {}
+        }
+        self.restore_timeout()?;
+        result
     }
-}
\ No newline at end of file
+
+    /// Read the next frame from the UART device without decoding it, e.g. to relay or hash it
+    ///
+    /// The frame is taken from the same buffer as `receive_message`, so the
+    /// two can be mixed without losing bytes. Frames addressed to other
+    /// devices are not filtered out.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - How long to wait for a frame
+    ///
+    /// # Returns
+    ///
+    /// * The raw frame including its terminator or length prefix, or None if
+    ///   no frame arrived within `timeout`
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidData` if the next frame was larger than the maximum frame size
+    /// * If reading from the device failed, or it was disconnected and auto
+    ///   reconnect could not reopen it
+    ///
+    pub fn read_frame(&mut self, timeout: Duration) -> std::io::Result<Option<Vec<u8>>> {
+        let result = self.next_frame(Instant::now() + timeout);
+        self.restore_timeout()?;
+        match result {
+            Ok(frame) => {
+                if self.trace_frames {
+                    debug!("Read raw frame ({} bytes):\n{}", frame.len(), Hexdump(&frame));
+                }
+                Ok(Some(frame))
+            }
+            Err(ReceiveError::Timeout) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Iterate over the commands received from the UART device
+    ///
+    /// Each step waits up to `timeout` for the next command using the same
+    /// buffered decoder as `receive_message`, so no bytes are lost between
+    /// commands. Frames that fail to decode are yielded as `ReceiveError::Parse`
+    /// and iteration carries on with the next frame. The iterator ends once a
+    /// wait times out with nothing received, and ends after yielding an IO error.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - How long to wait for each command
+    ///
+    /// # Returns
+    ///
+    /// * An iterator over the received commands
+    ///
+    pub fn commands(&mut self, timeout: Duration) -> impl Iterator<Item = std::result::Result<Command, ReceiveError>> + '_ {
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            match self.receive_message(timeout) {
+                Err(ReceiveError::Timeout) => {
+                    done = true;
+                    None
+                }
+                Err(e @ (ReceiveError::Io(_) | ReceiveError::Disconnected(_))) => {
+                    done = true;
+                    Some(Err(e))
+                }
+                result => Some(result),
+            }
+        })
+    }
+
+    /// Discard stale input, e.g. before starting a new exchange
+    ///
+    /// Everything already buffered by the connection is dropped, then the
+    /// device is read without blocking until no more bytes are available.
+    ///
+    /// # Returns
+    ///
+    /// * The number of bytes discarded
+    ///
+    pub fn drain_input(&mut self) -> std::io::Result<usize> {
+        self.read_available()?;
+        let discarded = self.decoder.clear();
+        debug!("Drained {} stale bytes", discarded);
+        Ok(discarded)
+    }
+
+    /// Receive every command available within a window
+    ///
+    /// Waits up to `timeout` for a first command, then also decodes every
+    /// further complete frame that has already arrived, without waiting for
+    /// more. Any partial frame at the end stays buffered for the next receive.
+    /// Frames that fail to decode are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - How long to wait for the first command
+    ///
+    /// # Returns
+    ///
+    /// * The received commands in order, empty if none arrived within `timeout`
+    ///
+    pub fn receive_messages(&mut self, timeout: Duration) -> std::io::Result<Vec<Command>> {
+        let mut commands = Vec::new();
+        match Transport::receive_message(self, timeout)? {
+            Some(command) => commands.push(command),
+            None => return Ok(commands),
+        }
+        self.read_available()?;
+        loop {
+            match self.decoder.next_frame() {
+                Ok(Some(frame)) => match self.decode(frame.clone()) {
+                    Ok(command) if !self.addressed_to_us(&command) => {
+                        trace!("Skipping {} for device {:?}", command, command.address());
+                    }
+                    Ok(command) => {
+                        self.trace_frame("Received", &frame, Ok(&command));
+                        self.stats.frames_decoded += 1;
+                        trace!("Received: {}", command);
+                        commands.push(command);
+                    }
+                    Err(e) => {
+                        self.trace_frame("Received", &frame, Err(&e));
+                        self.stats.parse_errors += 1;
+                        debug!("Skipping undecodable frame ({})", e);
+                    }
+                },
+                Ok(None) => return Ok(commands),
+                Err(e) => {
+                    self.stats.parse_errors += 1;
+                    debug!("Skipping frame ({})", e);
+                }
+            }
+        }
+    }
+
+    /// Read everything the device has available into the decoder without blocking
+    ///
+    /// # Returns
+    ///
+    /// * The number of bytes read
+    ///
+    fn read_available(&mut self) -> std::io::Result<usize> {
+        let mut total = 0;
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        self.port()?.set_timeout(Duration::ZERO)?;
+        let result = loop {
+            match self.read_port(&mut chunk) {
+                Ok(0) => break Ok(total),
+                Ok(count) => {
+                    self.decoder.extend(&chunk[..count]);
+                    total += count;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock) => {
+                    break Ok(total)
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        self.restore_timeout()?;
+        result
+    }
+
+    /// Exchange protocol versions with the peer
+    ///
+    /// Sends a Version command carrying `PROTOCOL_VERSION` and waits for the
+    /// peer's VersionAcknowledge carrying its own version.
+    ///
+    /// # Returns
+    ///
+    /// * The peer's protocol version
+    ///
+    /// # Errors
+    ///
+    /// * `TimedOut` if the peer did not reply within the connection's timeout
+    /// * `InvalidData` if the peer's version is outside
+    ///   `MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION` or its reply is malformed
+    ///
+    pub fn handshake(&mut self) -> std::io::Result<u8> {
+        let version = Command::new(crate::CommandType::Version, vec![crate::PROTOCOL_VERSION]);
+        let ack = self.send_and_await_ack(version, self.timeout)?;
+        let peer_version = match *ack.data.as_slice() {
+            [peer_version] => peer_version,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Malformed protocol version acknowledgement",
+                ))
+            }
+        };
+        if !(crate::MIN_PROTOCOL_VERSION..=crate::PROTOCOL_VERSION).contains(&peer_version) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Unsupported peer protocol version {}", peer_version),
+            ));
+        }
+        info!("Peer speaks protocol version {}", peer_version);
+        Ok(peer_version)
+    }
+
+    /// Check the link is alive by sending a heartbeat and waiting for its acknowledgement
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - How long to wait for the acknowledgement
+    ///
+    /// # Returns
+    ///
+    /// * Whether the acknowledgement arrived within `timeout`
+    ///
+    /// # Errors
+    ///
+    /// * If sending or receiving fails for any reason other than the timeout
+    ///
+    pub fn heartbeat(&mut self, timeout: Duration) -> std::io::Result<bool> {
+        let heartbeat = Command::simple_command(crate::CommandType::Heartbeat);
+        match self.send_and_await_ack(heartbeat, timeout) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Wait for the payload to report that it has initialised
+    ///
+    /// The init message is an Initialised command, carrying a BootStatus
+    /// unless sent by older firmware. Returns as soon as it is received;
+    /// anything else received first, or that fails to decode, is discarded.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The longest to wait for the Initialised command
+    ///
+    /// # Returns
+    ///
+    /// * The Initialised command, or None if it did not arrive within `timeout`
+    ///
+    /// # Errors
+    ///
+    /// * If reading from the device failed
+    ///
+    pub fn receive_init(&mut self, timeout: Duration) -> std::io::Result<Option<Command>> {
+        let deadline = Instant::now() + timeout;
+        let mut result = Ok(None);
+        while Instant::now() < deadline {
+            match self.next_frame(deadline).map(|frame| self.decode(frame)) {
+                Ok(Ok(command))
+                    if command.command_type == crate::CommandType::Initialised && self.addressed_to_us(&command) =>
+                {
+                    match command.boot_status() {
+                        Ok(Some(status)) => info!("Initialised: firmware {}, self-test {}", status.version, status.status),
+                        _ => info!("Initialised"),
+                    }
+                    result = Ok(Some(command));
+                    break;
+                }
+                Ok(_) | Err(ReceiveError::Parse(_)) | Err(ReceiveError::Timeout) => {}
+                Err(e @ (ReceiveError::Io(_) | ReceiveError::Disconnected(_))) => {
+                    result = Err(e.into());
+                    break;
+                }
+            }
+        }
+        self.restore_timeout()?;
+        result
+    }
+
+    /// Bring the payload up with the full startup handshake
+    ///
+    /// Sends the Time command and waits for its acknowledgement, sends the
+    /// StartupCommand for `startup_file` and waits for its acknowledgement,
+    /// waits for the payload to report Initialised and finally replies with
+    /// InitialisedAcknowledge. Other commands received along the way are
+    /// discarded.
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - The time to set on the payload
+    /// * `startup_file` - The filename of the startup command to run
+    /// * `timeout` - How long to wait at each step
+    ///
+    /// # Errors
+    ///
+    /// * A `StartupError` naming the step that failed, carrying the IO error
+    ///   that failed it, `TimedOut` if the payload did not respond in time
+    ///
+    #[cfg(feature = "chrono")]
+    pub fn run_startup_sequence(
+        &mut self,
+        time: chrono::DateTime<chrono::Utc>,
+        startup_file: &str,
+        timeout: Duration,
+    ) -> std::result::Result<(), crate::StartupError> {
+        self.send_and_await_ack(Command::time(time), timeout)
+            .map_err(crate::StartupError::Time)?;
+        self.send_and_await_ack(Command::startup_command(startup_file.as_bytes().to_vec()), timeout)
+            .map_err(crate::StartupError::StartupCommand)?;
+        self.await_initialised(timeout).map_err(crate::StartupError::Initialised)?;
+        self.send_message(Command::simple_command(crate::CommandType::InitialisedAcknowledge))
+            .map_err(crate::StartupError::InitialisedAcknowledge)?;
+        Ok(())
+    }
+
+    /// Wait for the payload to report Initialised, discarding anything else
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - How long to wait
+    ///
+    /// # Errors
+    ///
+    /// * `TimedOut` if no Initialised arrived within `timeout`
+    /// * If reading from the device failed
+    ///
+    #[cfg(feature = "chrono")]
+    fn await_initialised(&mut self, timeout: Duration) -> std::io::Result<()> {
+        match self.receive_init(timeout)? {
+            Some(_) => Ok(()),
+            None => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "No Initialised received")),
+        }
+    }
+
+    /// Write and flush a whole frame, turning the line around either side of it
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - The encoded frame
+    ///
+    fn write_frame(&mut self, frame: &[u8]) -> std::io::Result<()> {
+        self.begin_transmit();
+        let result = self.write_and_flush(frame);
+        let released = self.end_transmit();
+        result.and(released)
+    }
+
+    /// Tell the turnaround hook the line is about to transmit, if it has not been told yet
+    fn begin_transmit(&mut self) {
+        if let Some(turnaround) = self.turnaround.as_mut() {
+            if !self.transmitting {
+                turnaround(true);
+                self.transmitting = true;
+            }
+        }
+    }
+
+    /// Wait for everything written to leave the UART, then release the line
+    fn end_transmit(&mut self) -> std::io::Result<()> {
+        if !self.transmitting {
+            return Ok(());
+        }
+        // flush blocks until the UART has shifted out its last byte
+        let flushed = match self.port.as_mut() {
+            Some(port) => port.flush(),
+            None => Ok(()),
+        };
+        if let Some(turnaround) = self.turnaround.as_mut() {
+            turnaround(false);
+        }
+        self.transmitting = false;
+        flushed
+    }
+
+    /// Write and flush a whole frame, within the send timeout if one is set
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - The encoded frame
+    ///
+    fn write_and_flush(&mut self, frame: &[u8]) -> std::io::Result<()> {
+        let send_timeout = match self.send_timeout {
+            Some(send_timeout) => send_timeout,
+            None if self.software_flow_control => self.timeout,
+            None => {
+                let port = self.port()?;
+                port.write_all(frame)?;
+                return port.flush();
+            }
+        };
+        self.write_all_before(frame, Instant::now() + send_timeout)
+    }
+
+    /// Write and flush all of `bytes`, giving up at `deadline`
+    ///
+    /// With software flow control the bytes are escaped, see `set_software_flow_control`.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The bytes to write
+    /// * `deadline` - The instant after which to stop writing
+    ///
+    /// # Errors
+    ///
+    /// * `TimedOut` if the bytes could not all be written before `deadline`
+    /// * If writing to the device failed
+    ///
+    fn write_all_before(&mut self, bytes: &[u8], deadline: Instant) -> std::io::Result<()> {
+        let escaped;
+        let bytes = if self.software_flow_control {
+            escaped = escape_flow_control(bytes);
+            &escaped[..]
+        } else {
+            bytes
+        };
+        let mut written = 0;
+        let result = loop {
+            if written == bytes.len() {
+                break self.port()?.flush();
+            }
+            let mut end = bytes.len();
+            if self.software_flow_control {
+                if let Err(e) = self.await_resume(deadline) {
+                    break Err(e);
+                }
+                end = end.min(written + FLOW_CONTROL_CHUNK_SIZE);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("Sent {} of {} bytes before the deadline", written, bytes.len()),
+                ));
+            }
+            let port = self.port()?;
+            port.set_timeout(remaining)?;
+            match port.write(&bytes[written..end]) {
+                Ok(0) => break Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(count) => written += count,
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::TimedOut | std::io::ErrorKind::Interrupted) => {}
+                Err(e) => break Err(e),
+            }
+        };
+        self.restore_timeout()?;
+        result
+    }
+
+    /// Read from the UART device until a complete frame has been buffered
+    ///
+    /// Bytes following the frame terminator are kept for the next call.
+    ///
+    /// # Arguments
+    ///
+    /// * `deadline` - The instant after which to stop waiting for a frame
+    ///
+    /// # Returns
+    ///
+    /// * The frame including its terminator or length prefix
+    ///
+    /// # Errors
+    ///
+    /// * `ReceiveError::Timeout` if the deadline passed first
+    /// * `ReceiveError::Parse` if the next frame was too large
+    /// * `ReceiveError::Io` if reading from the device failed
+    /// * `ReceiveError::Disconnected` if the device could not be reopened
+    ///
+    fn next_frame(&mut self, deadline: Instant) -> std::result::Result<Vec<u8>, ReceiveError> {
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        let mut reconnected = false;
+        loop {
+            if let Some(frame) = self.decoder.next_frame()? {
+                return Ok(frame);
+            }
+            match self.read_before(&mut chunk, deadline) {
+                Ok(count) => self.decoder.extend(&chunk[..count]),
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                    if Instant::now() >= deadline {
+                        return Err(ReceiveError::Timeout);
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) if self.auto_reconnect && !reconnected && is_disconnect(&e) => {
+                    warn!("Device disconnected while receiving: {}", e);
+                    self.reopen().map_err(ReceiveError::Disconnected)?;
+                    reconnected = true;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Read from the UART device, blocking no later than `deadline`
+    ///
+    /// The port timeout is left set to the remaining time, callers should
+    /// call `restore_timeout` once they are done reading. Devices that return
+    /// straight away with nothing read, rather than waiting out the timeout,
+    /// are polled every `IDLE_POLL_INTERVAL` instead of in a busy loop.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - The buffer to read into
+    /// * `deadline` - The instant after which the read gives up
+    ///
+    /// # Returns
+    ///
+    /// * The number of bytes read, at least one
+    ///
+    /// # Errors
+    ///
+    /// * `TimedOut` if nothing was read before `deadline`
+    ///
+    fn read_before(&mut self, buffer: &mut [u8], deadline: Instant) -> std::io::Result<usize> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            self.port()?.set_timeout(remaining)?;
+            match self.read_port(buffer) {
+                Ok(0) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            std::thread::sleep(IDLE_POLL_INTERVAL.min(deadline.saturating_duration_since(Instant::now())));
+        }
+    }
+
+    /// Read from the UART device, counting the bytes received
+    fn read_port(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        self.end_transmit()?;
+        self.read_device(buffer)
+    }
+
+    /// Read from the UART device without releasing the line, stripping flow
+    /// control bytes and unescaping data if enabled
+    ///
+    /// # Returns
+    ///
+    /// * The number of bytes left in `buffer`, which may be zero if all
+    ///   those read were flow control bytes
+    ///
+    fn read_device(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.port()?.read(buffer)?;
+        self.stats.bytes_received += count as u64;
+        if !self.software_flow_control {
+            return Ok(count);
+        }
+        Ok(self.strip_flow_control(&mut buffer[..count]))
+    }
+
+    /// Act on and remove the XON and XOFF bytes among those read, and unescape the data bytes
+    ///
+    /// An escape byte at the end of `bytes` is carried over to the next read.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The bytes read, compacted in place
+    ///
+    /// # Returns
+    ///
+    /// * The number of bytes kept at the start of `bytes`
+    ///
+    fn strip_flow_control(&mut self, bytes: &mut [u8]) -> usize {
+        let mut kept = 0;
+        for index in 0..bytes.len() {
+            // Escaped bytes are never XON or XOFF, so those may arrive even between an escape and its byte
+            match bytes[index] {
+                XOFF => {
+                    debug!("Payload sent XOFF, pausing output");
+                    self.paused = true;
+                }
+                XON => {
+                    debug!("Payload sent XON, resuming output");
+                    self.paused = false;
+                }
+                byte if self.unescape_next => {
+                    self.unescape_next = false;
+                    bytes[kept] = byte ^ FLOW_CONTROL_ESCAPE_MASK;
+                    kept += 1;
+                }
+                FLOW_CONTROL_ESCAPE => self.unescape_next = true,
+                byte => {
+                    bytes[kept] = byte;
+                    kept += 1;
+                }
+            }
+        }
+        kept
+    }
+
+    /// Pick up any flow control bytes waiting on the device, then wait for
+    /// an XON if the payload has paused output
+    ///
+    /// Other bytes read meanwhile are kept for the frame decoder. The port
+    /// timeout is left changed, callers should call `restore_timeout`.
+    ///
+    /// # Arguments
+    ///
+    /// * `deadline` - The instant after which to stop waiting
+    ///
+    /// # Errors
+    ///
+    /// * `TimedOut` if output was still paused at `deadline`
+    ///
+    fn await_resume(&mut self, deadline: Instant) -> std::io::Result<()> {
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        // Poll without blocking first, only waiting once paused
+        let mut timeout = Duration::ZERO;
+        loop {
+            self.port()?.set_timeout(timeout)?;
+            match self.read_device(&mut chunk) {
+                Ok(count) => self.decoder.extend(&chunk[..count]),
+                Err(e) if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted
+                ) => {}
+                Err(e) => return Err(e),
+            }
+            if !self.paused {
+                return Ok(());
+            }
+            timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "Payload sent XOFF and no XON before the deadline",
+                ));
+            }
+            std::thread::sleep(IDLE_POLL_INTERVAL.min(timeout));
+        }
+    }
+}
+
+/// Escape the bytes that software flow control would otherwise act on
+///
+/// # Arguments
+///
+/// * `bytes` - The data to send
+///
+/// # Returns
+///
+/// * The data with each XON, XOFF and `FLOW_CONTROL_ESCAPE` replaced by
+///   `FLOW_CONTROL_ESCAPE` and the byte XORed with `FLOW_CONTROL_ESCAPE_MASK`
+///
+fn escape_flow_control(bytes: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            XON | XOFF | FLOW_CONTROL_ESCAPE => escaped.extend([FLOW_CONTROL_ESCAPE, byte ^ FLOW_CONTROL_ESCAPE_MASK]),
+            byte => escaped.push(byte),
+        }
+    }
+    escaped
+}
+
+/// Builder for a UartConnection, defaulting to the payload's standard
+/// 115200 baud 8N1 configuration with no flow control
+#[derive(Clone, Debug)]
+pub struct UartConnectionBuilder {
+    path: String,
+    settings: PortSettings,
+    timeout: Duration,
+    max_frame_size: usize,
+    auto_reconnect: bool,
+    send_timeout: Option<Duration>,
+    trace_frames: bool,
+    device_address: Option<u8>,
+    software_flow_control: bool,
+}
+
+impl UartConnectionBuilder {
+    /// Create a new UartConnectionBuilder with the default settings
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the UART device
+    ///
+    /// # Returns
+    ///
+    /// * A new UartConnectionBuilder
+    ///
+    pub fn new(path: impl Into<String>) -> UartConnectionBuilder {
+        UartConnectionBuilder {
+            path: path.into(),
+            settings: DEFAULT_PORT_SETTINGS,
+            timeout: DEFAULT_TIMEOUT,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            auto_reconnect: false,
+            send_timeout: None,
+            trace_frames: false,
+            device_address: None,
+            software_flow_control: false,
+        }
+    }
+
+    /// Set the baud rate, defaults to 115200
+    pub fn baud_rate(mut self, baud_rate: BaudRate) -> UartConnectionBuilder {
+        self.settings.baud_rate = baud_rate;
+        self
+    }
+
+    /// Set the character size, defaults to 8 bits
+    pub fn char_size(mut self, char_size: CharSize) -> UartConnectionBuilder {
+        self.settings.char_size = char_size;
+        self
+    }
+
+    /// Set the parity, defaults to none
+    pub fn parity(mut self, parity: Parity) -> UartConnectionBuilder {
+        self.settings.parity = parity;
+        self
+    }
+
+    /// Set the number of stop bits, defaults to 1
+    pub fn stop_bits(mut self, stop_bits: StopBits) -> UartConnectionBuilder {
+        self.settings.stop_bits = stop_bits;
+        self
+    }
+
+    /// Set the flow control, defaults to none
+    pub fn flow_control(mut self, flow_control: FlowControl) -> UartConnectionBuilder {
+        self.settings.flow_control = flow_control;
+        self
+    }
+
+    /// Set the timeout of the UART device, defaults to 1 second
+    pub fn timeout(mut self, timeout: Duration) -> UartConnectionBuilder {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the longest a whole frame may take to send, defaults to None
+    pub fn send_timeout(mut self, send_timeout: Option<Duration>) -> UartConnectionBuilder {
+        self.send_timeout = send_timeout;
+        self
+    }
+
+    /// Set the largest frame accepted, defaults to `DEFAULT_MAX_FRAME_SIZE`
+    pub fn max_frame_size(mut self, max_frame_size: usize) -> UartConnectionBuilder {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Set whether to reopen the device when it is disconnected, defaults to false
+    pub fn auto_reconnect(mut self, auto_reconnect: bool) -> UartConnectionBuilder {
+        self.auto_reconnect = auto_reconnect;
+        self
+    }
+
+    /// Set whether to log a hexdump of every frame, defaults to false
+    pub fn trace_frames(mut self, trace_frames: bool) -> UartConnectionBuilder {
+        self.trace_frames = trace_frames;
+        self
+    }
+
+    /// Set our address on a multi-drop bus, defaults to frames not being addressed
+    pub fn device_address(mut self, address: u8) -> UartConnectionBuilder {
+        self.device_address = Some(address);
+        self
+    }
+
+    /// Set whether to honour XON/XOFF software flow control from the payload, defaults to false
+    pub fn software_flow_control(mut self, software_flow_control: bool) -> UartConnectionBuilder {
+        self.software_flow_control = software_flow_control;
+        self
+    }
+
+    /// Open the UART device and build the connection
+    ///
+    /// # Returns
+    ///
+    /// * A new UartConnection with the device open
+    ///
+    /// # Errors
+    ///
+    /// * If the device could not be opened or configured
+    ///
+    pub fn build(self) -> std::io::Result<UartConnection> {
+        let mut connection = self.build_lazy();
+        connection.reconnect()?;
+        Ok(connection)
+    }
+
+    /// Build the connection without opening the UART device, as `UartConnection::new` does
+    fn build_lazy(self) -> UartConnection {
+        let mut connection = UartConnection::new(self.path, self.settings, self.timeout);
+        connection.set_max_frame_size(self.max_frame_size);
+        connection.set_auto_reconnect(self.auto_reconnect);
+        connection.set_send_timeout(self.send_timeout);
+        connection.set_trace_frames(self.trace_frames);
+        connection.set_device_address(self.device_address);
+        connection.set_software_flow_control(self.software_flow_control);
+        connection
+    }
+}
+
+impl Transport for UartConnection {
+    fn send_message(&mut self, command: Command) -> std::io::Result<usize> {
+        UartConnection::send_message(self, command)
+    }
+
+    /// Receive a message, skipping frames that fail to decode until `timeout` elapses
+    fn receive_message(&mut self, timeout: Duration) -> std::io::Result<Option<Command>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match UartConnection::receive_message(self, remaining) {
+                Ok(command) => return Ok(Some(command)),
+                Err(ReceiveError::Timeout) => return Ok(None),
+                Err(ReceiveError::Parse(_)) => {}
+                Err(e @ (ReceiveError::Io(_) | ReceiveError::Disconnected(_))) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Number of bytes on each line of a `Hexdump`
+const HEXDUMP_WIDTH: usize = 16;
+
+/// Formats bytes as lines of offset, hex and printable ASCII, like `hexdump -C`
+struct Hexdump<'a>(&'a [u8]);
+
+impl std::fmt::Display for Hexdump<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (line, bytes) in self.0.chunks(HEXDUMP_WIDTH).enumerate() {
+            if line > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{:08x} ", line * HEXDUMP_WIDTH)?;
+            for i in 0..HEXDUMP_WIDTH {
+                match bytes.get(i) {
+                    Some(byte) => write!(f, " {:02x}", byte)?,
+                    None => write!(f, "   ")?,
+                }
+            }
+            write!(f, "  |")?;
+            for &byte in bytes {
+                let printable = byte.is_ascii_graphic() || byte == b' ';
+                write!(f, "{}", if printable { byte as char } else { '.' })?;
+            }
+            write!(f, "|")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether an error means the device has gone away, e.g. a USB adapter was unplugged
+fn is_disconnect(error: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    // EIO, ENXIO and ENODEV are reported by Linux tty drivers for removed devices
+    matches!(
+        error.kind(),
+        NotFound | NotConnected | BrokenPipe | ConnectionReset | ConnectionAborted | UnexpectedEof
+    ) || matches!(error.raw_os_error(), Some(5) | Some(6) | Some(19))
+}
+
+/// Open and configure a UART device
+///
+/// # Arguments
+///
+/// * `path` - The path to the UART device
+/// * `settings` - The settings of the UART device
+/// * `timeout` - The timeout of the UART device
+///
+/// # Returns
+///
+/// * The opened port
+///
+fn open_port(
+    path: &str,
+    settings: &PortSettings,
+    timeout: Duration,
+) -> std::io::Result<Box<dyn SerialPort + Send>> {
+    let mut port = serial::open(path)?;
+    port.configure(settings)?;
+    port.set_timeout(timeout)?;
+    Ok(Box::new(port))
+}
+
+impl Drop for UartConnection {
+    fn drop(&mut self) {
+        if let Err(e) = self.release() {
+            warn!("Failed to flush {} while closing it: {}", self.path, e);
+        }
+    }
+}
+
+impl Read for UartConnection {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        // Hand out anything already buffered by the frame reader first
+        if !self.decoder.buffered().is_empty() {
+            return Ok(self.decoder.take_buffered(buffer));
+        }
+        if !self.software_flow_control {
+            return self.read_port(buffer);
+        }
+        // A read of nothing but flow control bytes must not look like end of file
+        let result = UartConnection::read_before(self, buffer, Instant::now() + self.timeout);
+        self.restore_timeout()?;
+        result
+    }
+}
+
+impl Write for UartConnection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.begin_transmit();
+        if self.software_flow_control {
+            // Escaped bytes are written whole, so a partial write never splits an escape
+            let count = buf.len().min(FLOW_CONTROL_CHUNK_SIZE);
+            self.write_all_before(&buf[..count], Instant::now() + self.timeout)?;
+            self.stats.bytes_sent += count as u64;
+            return Ok(count);
+        }
+        let count = self.port()?.write(buf)?;
+        self.stats.bytes_sent += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.port()?.flush()?;
+        self.end_transmit()
+    }
+}
+
+impl Ftp for UartConnection {
+    fn ftp_with_progress(&mut self, progress: FtpProgress) -> std::result::Result<(), FtpError> {
+        let config = self.ftp.clone();
+        crate::ftp::receive_file(self, &config, progress)
+    }
+
+    fn send_file_with_progress(&mut self, path: &std::path::Path, progress: FtpProgress) -> std::result::Result<(), FtpError> {
+        let config = self.ftp.clone();
+        crate::ftp::send_file(self, &config, path, progress)
+    }
+
+    fn ftp_with_config(&mut self, config: &FtpConfig) -> std::result::Result<(), FtpError> {
+        crate::ftp::receive_file(self, config, &mut |_, _| {})
+    }
+
+    fn send_file_with_config(&mut self, path: &std::path::Path, config: &FtpConfig) -> std::result::Result<(), FtpError> {
+        crate::ftp::send_file(self, config, path, &mut |_, _| {})
+    }
+}
+
+impl FtpLink for UartConnection {
+    /// Hand out bytes already buffered by the frame reader first
+    fn read_before(&mut self, buffer: &mut [u8], deadline: Instant) -> std::io::Result<usize> {
+        if !self.decoder.buffered().is_empty() {
+            return Ok(self.decoder.take_buffered(buffer));
+        }
+        UartConnection::read_before(self, buffer, deadline)
+    }
+
+    fn send(&mut self, bytes: &[u8], deadline: Instant) -> std::io::Result<()> {
+        self.begin_transmit();
+        self.write_all_before(bytes, deadline)?;
+        self.stats.bytes_sent += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn end_reads(&mut self) -> std::io::Result<()> {
+        self.restore_timeout()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Backoff, RetriesExhausted, FTP_CHUNK_SIZE};
+    use crate::ftp::{READY_RECEIVE_FILE, RECEIVED_FILE_DATA, RECEIVE_FILE_ERROR_RETRY, RECEIVE_FILE_SUCCESS, SEND_FILE_HASH};
+    use sha2::{Digest, Sha256};
+    use std::collections::VecDeque;
+
+    type Responder = Box<dyn FnMut(&[u8]) -> Vec<u8> + Send>;
+
+    /// In-memory stand-in for a serial port, reads block for the port
+    /// timeout when there is nothing to read, like a real device.
+    struct MockPort {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+        timeout: Duration,
+        /// Produces the bytes the far end sends back in reply to each write
+        respond: Option<Responder>,
+        /// Most bytes returned by a single read, like a UART delivering a few bytes at a time
+        max_read: usize,
+        /// Error returned by every read and write, like an unplugged device
+        fail: Option<std::io::ErrorKind>,
+        /// Most bytes accepted by a single write
+        max_write: usize,
+        /// Bytes accepted in total before writes block, like a stalled receiver
+        write_capacity: usize,
+        /// If set, reads with no input return zero bytes straight away, like a
+        /// non-blocking device, and are counted here
+        idle_reads: Option<std::sync::Arc<std::sync::atomic::AtomicUsize>>,
+    }
+
+    impl MockPort {
+        fn new(input: &[u8]) -> MockPort {
+            MockPort {
+                input: input.iter().copied().collect(),
+                output: Vec::new(),
+                timeout: Duration::from_secs(1),
+                respond: None,
+                max_read: usize::MAX,
+                fail: None,
+                max_write: usize::MAX,
+                write_capacity: usize::MAX,
+                idle_reads: None,
+            }
+        }
+    }
+
+    impl Read for MockPort {
+        fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+            if let Some(kind) = self.fail {
+                return Err(kind.into());
+            }
+            if let (true, Some(idle_reads)) = (self.input.is_empty(), &self.idle_reads) {
+                idle_reads.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                return Ok(0);
+            }
+            if self.input.is_empty() {
+                std::thread::sleep(self.timeout);
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            let count = buffer.len().min(self.input.len()).min(self.max_read);
+            for (slot, byte) in buffer.iter_mut().zip(self.input.drain(..count)) {
+                *slot = byte;
+            }
+            Ok(count)
+        }
+    }
+
+    impl Write for MockPort {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if let Some(kind) = self.fail {
+                return Err(kind.into());
+            }
+            if self.write_capacity == 0 {
+                std::thread::sleep(self.timeout);
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            let count = buf.len().min(self.max_write).min(self.write_capacity);
+            self.write_capacity -= count;
+            self.output.extend_from_slice(&buf[..count]);
+            if let Some(respond) = self.respond.as_mut() {
+                self.input.extend(respond(&buf[..count]));
+            }
+            Ok(count)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            match self.fail {
+                Some(kind) => Err(kind.into()),
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl SerialPort for MockPort {
+        fn timeout(&self) -> Duration {
+            self.timeout
+        }
+
+        fn set_timeout(&mut self, timeout: Duration) -> serial::Result<()> {
+            self.timeout = timeout;
+            Ok(())
+        }
+
+        fn configure(&mut self, _settings: &PortSettings) -> serial::Result<()> {
+            Ok(())
+        }
+
+        fn reconfigure(
+            &mut self,
+            _setup: &dyn Fn(&mut dyn SerialPortSettings) -> serial::Result<()>,
+        ) -> serial::Result<()> {
+            Ok(())
+        }
+
+        fn set_rts(&mut self, _level: bool) -> serial::Result<()> {
+            Ok(())
+        }
+
+        fn set_dtr(&mut self, _level: bool) -> serial::Result<()> {
+            Ok(())
+        }
+
+        fn read_cts(&mut self) -> serial::Result<bool> {
+            Ok(true)
+        }
+
+        fn read_dsr(&mut self) -> serial::Result<bool> {
+            Ok(true)
+        }
+
+        fn read_ri(&mut self) -> serial::Result<bool> {
+            Ok(false)
+        }
+
+        fn read_cd(&mut self) -> serial::Result<bool> {
+            Ok(true)
+        }
+    }
+
+    fn mock_connection(input: &[u8]) -> UartConnection {
+        connection_with_port(MockPort::new(input))
+    }
+
+    fn responding_connection(respond: impl FnMut(&[u8]) -> Vec<u8> + Send + 'static) -> UartConnection {
+        let mut port = MockPort::new(&[]);
+        port.respond = Some(Box::new(respond));
+        connection_with_port(port)
+    }
+
+    fn connection_with_port(port: MockPort) -> UartConnection {
+        UartConnection {
+            port: Some(Box::new(port)),
+            path: String::from("/dev/null"),
+            settings: DEFAULT_PORT_SETTINGS,
+            timeout: Duration::from_secs(10),
+            decoder: FrameDecoder::new(),
+            ftp: FtpConfig::default(),
+            stats: Stats::default(),
+            auto_reconnect: false,
+            send_timeout: None,
+            turnaround: None,
+            transmitting: false,
+            trace_frames: false,
+            device_address: None,
+            software_flow_control: false,
+            paused: false,
+            unescape_next: false,
+        }
+    }
+
+    #[test]
+    fn test_receive_message_respects_timeout() {
+        let mut connection = mock_connection(&[]);
+        let start_time = Instant::now();
+        let received = connection.receive_message(Duration::from_millis(200));
+        let elapsed = start_time.elapsed();
+        assert!(matches!(received, Err(ReceiveError::Timeout)));
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(elapsed < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_receive_message() {
+        let command = Command::simple_command(crate::CommandType::PowerDown);
+        let mut connection = mock_connection(&command.to_bytes());
+        let received = connection.receive_message(Duration::from_millis(200)).unwrap();
+        assert_eq!(received.command_type, crate::CommandType::PowerDown);
+    }
+
+    #[test]
+    fn test_receive_message_until() {
+        let mut input = Command::simple_command(crate::CommandType::Heartbeat).to_bytes();
+        input.extend(Command::simple_command(crate::CommandType::Initialised).to_bytes());
+        let mut connection = mock_connection(&input);
+        let start_time = Instant::now();
+        let deadline = start_time + Duration::from_millis(200);
+        let first = connection.receive_message_until(deadline).unwrap();
+        let second = connection.receive_message_until(deadline).unwrap();
+        assert_eq!(first.command_type, crate::CommandType::Heartbeat);
+        assert_eq!(second.command_type, crate::CommandType::Initialised);
+        assert!(matches!(connection.receive_message_until(deadline), Err(ReceiveError::Timeout)));
+        // The receives share one deadline rather than each waiting 200 ms
+        assert!(start_time.elapsed() < Duration::from_millis(350));
+        assert!(matches!(connection.receive_message_until(start_time), Err(ReceiveError::Timeout)));
+    }
+
+    #[test]
+    fn test_device_address() {
+        let mut input = Command::new_addressed(3, crate::CommandType::Heartbeat, Vec::new()).to_bytes();
+        input.extend(Command::new_addressed(7, crate::CommandType::Initialised, Vec::new()).to_bytes());
+        let mut connection = mock_connection(&input);
+        connection.set_device_address(Some(7));
+        assert_eq!(connection.device_address(), Some(7));
+        let received = connection.receive_message(Duration::from_millis(200)).unwrap();
+        assert_eq!(received.command_type, crate::CommandType::Initialised);
+        assert_eq!(received.address(), Some(7));
+        assert!(matches!(connection.receive_message(Duration::from_millis(50)), Err(ReceiveError::Timeout)));
+    }
+
+    #[test]
+    fn test_read_frame() {
+        let heartbeat = Command::simple_command(crate::CommandType::Heartbeat).to_bytes();
+        let power_down = Command::simple_command(crate::CommandType::PowerDown).to_bytes();
+        let mut connection = mock_connection(&[heartbeat.clone(), power_down].concat());
+        assert_eq!(connection.read_frame(Duration::from_millis(100)).unwrap(), Some(heartbeat));
+        let command = connection.receive_message(Duration::from_millis(100)).unwrap();
+        assert_eq!(command.command_type, crate::CommandType::PowerDown);
+        assert_eq!(connection.read_frame(Duration::from_millis(10)).unwrap(), None);
+
+        let mut connection = mock_connection(&[1; 32]);
+        connection.set_max_frame_size(16);
+        let error = connection.read_frame(Duration::from_millis(100)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_receive_message_keeps_following_frames() {
+        let mut input = Command::simple_command(crate::CommandType::Initialised).to_bytes();
+        input.extend(Command::startup_command(vec![1, 2, 3]).to_bytes());
+        let mut connection = mock_connection(&input);
+        let first = connection.receive_message(Duration::from_millis(200)).unwrap();
+        let second = connection.receive_message(Duration::from_millis(200)).unwrap();
+        assert_eq!(first.command_type, crate::CommandType::Initialised);
+        assert_eq!(second, (crate::CommandType::StartupCommand, &[1, 2, 3][..]));
+    }
+
+    #[test]
+    fn test_send_and_await_ack() {
+        let mut input = Command::simple_command(crate::CommandType::Initialised).to_bytes();
+        input.extend(Command::simple_command(crate::CommandType::TimeAcknowledge).to_bytes());
+        let mut connection = mock_connection(&input);
+        let ack = connection
+            .send_and_await_ack(Command::time_now(), Duration::from_millis(200))
+            .unwrap();
+        assert_eq!(ack.command_type, crate::CommandType::TimeAcknowledge);
+    }
+
+    #[test]
+    fn test_send_and_await_ack_timeout() {
+        let mut connection = mock_connection(&[]);
+        let error = connection
+            .send_and_await_ack(Command::time_now(), Duration::from_millis(100))
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let mut connection = responding_connection(|written| {
+            if written == Command::simple_command(crate::CommandType::Heartbeat).to_bytes() {
+                Command::simple_command(crate::CommandType::HeartbeatAcknowledge).to_bytes()
+            } else {
+                Vec::new()
+            }
+        });
+        assert!(connection.heartbeat(Duration::from_millis(100)).unwrap());
+        let mut connection = mock_connection(&[]);
+        assert!(!connection.heartbeat(Duration::from_millis(50)).unwrap());
+    }
+
+    #[test]
+    fn test_handshake() {
+        for (peer_version, supported) in [(crate::PROTOCOL_VERSION, true), (0, false), (200, false)] {
+            let mut connection = responding_connection(move |_| {
+                Command::new(crate::CommandType::VersionAcknowledge, vec![peer_version]).to_bytes()
+            });
+            connection.timeout = Duration::from_millis(100);
+            match connection.handshake() {
+                Ok(version) => assert!(supported && version == peer_version),
+                Err(e) => assert!(!supported && e.kind() == std::io::ErrorKind::InvalidData),
+            }
+        }
+    }
+
+    #[test]
+    fn test_send_with_retry() {
+        let mut writes = 0;
+        let mut connection = responding_connection(move |_| {
+            writes += 1;
+            if writes == 3 {
+                Command::simple_command(crate::CommandType::PowerDownAcknowledge).to_bytes()
+            } else {
+                Vec::new()
+            }
+        });
+        let command = Command::simple_command(crate::CommandType::PowerDown);
+        let ack = connection
+            .send_with_retry(command, 5, Duration::from_millis(10), Duration::from_millis(50))
+            .unwrap();
+        assert_eq!(ack.command_type, crate::CommandType::PowerDownAcknowledge);
+    }
+
+    #[test]
+    fn test_send_with_retry_gives_up() {
+        let mut connection = responding_connection(|_| Vec::new());
+        let command = Command::simple_command(crate::CommandType::PowerDown);
+        let backoff = Backoff::Exponential { initial: Duration::from_millis(1), factor: 2 };
+        let error = connection
+            .send_with_retry(command, 3, backoff, Duration::from_millis(20))
+            .unwrap_err();
+        let exhausted = error.get_ref().unwrap().downcast_ref::<RetriesExhausted>().unwrap();
+        assert_eq!(exhausted.attempts, 3);
+    }
+
+    #[test]
+    fn test_exponential_backoff() {
+        let backoff = Backoff::Exponential { initial: Duration::from_millis(10), factor: 2 };
+        assert_eq!(backoff.delay(1), Duration::from_millis(10));
+        assert_eq!(backoff.delay(3), Duration::from_millis(40));
+    }
+
+    #[test]
+    fn test_send_file() {
+        let path = std::env::temp_dir().join("ws_api_test_send_file.txt");
+        let contents: Vec<u8> = (0..10_000u32).map(|x| x as u8).collect();
+        std::fs::write(&path, &contents).unwrap();
+        let expected_hash = Sha256::digest(&contents);
+
+        // Acknowledge the name, reject the first chunk once, then accept everything
+        let mut writes = 0;
+        let mut connection = responding_connection(move |written| {
+            writes += 1;
+            match writes {
+                1 => READY_RECEIVE_FILE.to_vec(),
+                2 => RECEIVE_FILE_ERROR_RETRY.to_vec(),
+                3..=5 => RECEIVED_FILE_DATA.to_vec(),
+                6 if written == [0, 0, 0, 0] => SEND_FILE_HASH.to_vec(),
+                7 if written == expected_hash.as_slice() => RECEIVE_FILE_SUCCESS.to_vec(),
+                _ => Vec::new(),
+            }
+        });
+        let mut reports = Vec::new();
+        connection
+            .send_file_with_progress(&path, &mut |sent, total| reports.push((sent, total)))
+            .unwrap();
+        assert_eq!(
+            reports,
+            vec![(0, Some(10_000)), (4096, Some(10_000)), (8192, Some(10_000)), (10_000, Some(10_000))]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_send_file_gives_up_after_retries() {
+        let path = std::env::temp_dir().join("ws_api_test_send_file_gives_up.txt");
+        std::fs::write(&path, [7u8; 100]).unwrap();
+
+        // Accept every chunk but reject every file hash
+        let mut connection = responding_connection(move |written| {
+            if written.starts_with(b"ws_api_test_send_file_gives_up.txt\0") {
+                READY_RECEIVE_FILE.to_vec()
+            } else if written == [0, 0, 0, 0] {
+                SEND_FILE_HASH.to_vec()
+            } else if written.len() == 32 {
+                RECEIVE_FILE_ERROR_RETRY.to_vec()
+            } else {
+                RECEIVED_FILE_DATA.to_vec()
+            }
+        });
+        let error = connection.send_file(&path).unwrap_err();
+        assert!(matches!(error, FtpError::RetriesExhausted { attempts: 3 }));
+        assert_eq!(error.to_string(), "Peer rejected the file hash on all 3 attempts");
+
+        let config = FtpConfig { max_retries: 0, ..FtpConfig::default() };
+        let error = connection.send_file_with_config(&path, &config).unwrap_err();
+        assert!(matches!(error, FtpError::RetriesExhausted { attempts: 1 }));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_send_file_write_timeout() {
+        let path = std::env::temp_dir().join("ws_api_test_send_file_write_timeout.txt");
+        std::fs::write(&path, [7u8; 100]).unwrap();
+        // The peer has stopped reading and its input buffer is full
+        let mut port = MockPort::new(&[]);
+        port.write_capacity = 0;
+        let mut connection = connection_with_port(port);
+        connection.set_ftp_timeout(Duration::from_millis(100));
+        let start_time = Instant::now();
+        assert!(matches!(connection.send_file(&path), Err(FtpError::Timeout)));
+        assert!(start_time.elapsed() < Duration::from_secs(1));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// The bytes `send_file` writes for a file, with chunk hashes and no retries
+    fn sender_stream(file_name: &[u8], contents: &[u8]) -> Vec<u8> {
+        let mut stream = [file_name, &[0]].concat();
+        stream.extend_from_slice(&(contents.len() as u64).to_be_bytes());
+        for chunk in contents.chunks(FTP_CHUNK_SIZE) {
+            stream.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+            stream.extend_from_slice(chunk);
+            stream.extend_from_slice(Sha256::digest(chunk).as_slice());
+        }
+        stream.extend_from_slice(&[0, 0, 0, 0]);
+        stream.extend_from_slice(Sha256::digest(contents).as_slice());
+        stream
+    }
+
+    #[test]
+    fn test_receive_file() {
+        let root = std::env::temp_dir().join("ws_api_test_receive_file");
+        std::fs::create_dir_all(&root).unwrap();
+        let contents: Vec<u8> = (0..6000u32).map(|x| (x * 7) as u8).collect();
+
+        let input = sender_stream(b"received.bin", &contents);
+
+        let mut connection = mock_connection(&input);
+        connection.set_ftp_root(&root);
+        let mut reports = Vec::new();
+        connection.ftp_with_progress(&mut |received, total| reports.push((received, total))).unwrap();
+        assert_eq!(reports, vec![(4096, Some(6000)), (6000, Some(6000))]);
+        assert_eq!(std::fs::read(root.join("received.bin")).unwrap(), contents);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_receive_file_fragmented_reads() {
+        let root = std::env::temp_dir().join("ws_api_test_receive_file_fragmented");
+        std::fs::create_dir_all(&root).unwrap();
+        let contents: Vec<u8> = (0..6000u32).map(|x| (x * 7) as u8).collect();
+
+        let input = sender_stream(b"received.bin", &contents);
+
+        let mut port = MockPort::new(&input);
+        port.max_read = 7;
+        let mut connection = connection_with_port(port);
+        connection.set_ftp_root(&root);
+        connection.ftp().unwrap();
+        assert_eq!(std::fs::read(root.join("received.bin")).unwrap(), contents);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_ftp_errors() {
+        let root = std::env::temp_dir().join("ws_api_test_ftp_errors");
+        std::fs::create_dir_all(&root).unwrap();
+        let contents = b"some file contents".to_vec();
+
+        // The file hash arrives corrupted
+        let mut input = sender_stream(b"corrupted.bin", &contents);
+        *input.last_mut().unwrap() ^= 1;
+        let mut connection = mock_connection(&input);
+        connection.set_ftp_root(&root);
+        assert!(matches!(connection.ftp(), Err(FtpError::HashMismatch)));
+        assert!(!root.join("corrupted.bin").exists());
+        assert!(!root.join("corrupted.bin.part").exists());
+
+        let mut connection = mock_connection(&sender_stream(b"../escape.bin", &contents));
+        connection.set_ftp_root(&root);
+        assert!(matches!(connection.ftp(), Err(FtpError::BadFilename)));
+
+        let path = root.join("send.bin");
+        std::fs::write(&path, &contents).unwrap();
+        let mut connection = responding_connection(|_| b"NOT_READY".to_vec());
+        assert!(matches!(
+            connection.send_file(&path),
+            Err(FtpError::UnexpectedResponse(message)) if message == b"N"
+        ));
+
+        let mut connection = mock_connection(&[]);
+        connection.set_ftp_timeout(Duration::from_millis(50));
+        assert!(matches!(connection.send_file(&path), Err(FtpError::Timeout)));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_ftp_phase_timeouts() {
+        let root = std::env::temp_dir().join("ws_api_test_ftp_phase_timeouts");
+        std::fs::create_dir_all(&root).unwrap();
+        let contents: Vec<u8> = (0..6000u32).map(|x| x as u8).collect();
+        let stream = sender_stream(b"stalled.bin", &contents);
+
+        // The sender dies before the name, partway through the first chunk, and before or partway through the hash
+        let header_len = b"stalled.bin\0".len() + 8;
+        for cut in [0, 5, header_len + 100, stream.len() - 32, stream.len() - 10] {
+            let mut connection = mock_connection(&stream[..cut]);
+            connection.set_ftp_root(&root);
+            connection.set_ftp_timeout(Duration::from_millis(100));
+            let start_time = Instant::now();
+            assert!(matches!(connection.ftp(), Err(FtpError::Timeout)), "cut at {}", cut);
+            // The port timeout alone would block for 10 seconds
+            assert!(start_time.elapsed() < Duration::from_secs(1));
+            assert_eq!(connection.port.as_ref().unwrap().timeout(), connection.timeout);
+        }
+        assert!(!root.join("stalled.bin").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_max_frame_size() {
+        let mut input = Command::startup_command(vec![1, 2, 3]).to_bytes();
+        input.extend(Command::simple_command(crate::CommandType::PowerDown).to_bytes());
+        let mut connection = mock_connection(&input);
+        connection.set_max_frame_size(1);
+        let error = connection.receive_message(Duration::from_millis(100)).unwrap_err();
+        assert!(matches!(error, ReceiveError::Parse(crate::ParseError::FrameTooLarge)));
+    }
+
+    #[test]
+    fn test_receive_message_resyncs_after_garbage() {
+        // Start reading partway through a frame whose COBS code announces more
+        // bytes than arrive before the terminator
+        let frame = Command::startup_command(vec![1, 2, 3]).to_bytes();
+        let mut input = vec![0x09, 0x01, 0x00];
+        input.extend(Command::simple_command(crate::CommandType::PowerDown).to_bytes());
+        input.extend(&frame);
+        let mut connection = mock_connection(&input);
+        connection.set_trace_frames(true);
+        let error = connection.receive_message(Duration::from_millis(100)).unwrap_err();
+        assert!(matches!(error, ReceiveError::Parse(crate::ParseError::CobsDecode)));
+        let command = connection.receive_message(Duration::from_millis(100)).unwrap();
+        assert_eq!(command.command_type, crate::CommandType::PowerDown);
+        let command = connection.receive_message(Duration::from_millis(100)).unwrap();
+        assert_eq!(command.data, vec![1, 2, 3]);
+
+        // The Transport interface skips the garbage frame within one call
+        let mut connection = mock_connection(&input);
+        let command = Transport::receive_message(&mut connection, Duration::from_millis(100)).unwrap().unwrap();
+        assert_eq!(command.command_type, crate::CommandType::PowerDown);
+    }
+
+    #[test]
+    fn test_connect_is_eager_and_new_is_lazy() {
+        let settings = DEFAULT_PORT_SETTINGS;
+        let path = String::from("/dev/ws-api-does-not-exist");
+        let timeout = Duration::from_millis(10);
+        assert!(UartConnection::connect(path.clone(), settings, timeout).is_err());
+
+        let mut connection = UartConnection::new(path, settings, timeout);
+        let command = Command::simple_command(crate::CommandType::PowerDown);
+        assert!(connection.send_message(command).is_err());
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut input = Command::startup_command(vec![1, 2, 3]).to_bytes();
+        input.extend(Command::simple_command(crate::CommandType::PowerDown).to_bytes());
+        let mut connection = mock_connection(&input);
+        let commands: Vec<Command> = connection
+            .commands(Duration::from_millis(50))
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].data, vec![1, 2, 3]);
+        assert_eq!(commands[1].command_type, crate::CommandType::PowerDown);
+    }
+
+    #[test]
+    fn test_builder() {
+        let connection = UartConnectionBuilder::new("/dev/ttyUSB0").build_lazy();
+        assert_eq!(connection.settings.baud_rate, Baud115200);
+        assert_eq!(connection.settings.char_size, Bits8);
+        assert_eq!(connection.settings.parity, ParityNone);
+        assert_eq!(connection.settings.stop_bits, Stop1);
+        assert_eq!(connection.settings.flow_control, FlowNone);
+        assert_eq!(connection.decoder.max_frame_size(), crate::DEFAULT_MAX_FRAME_SIZE);
+
+        let connection = UartConnectionBuilder::new("/dev/ttyUSB0")
+            .baud_rate(Baud9600)
+            .parity(ParityEven)
+            .stop_bits(Stop2)
+            .flow_control(FlowHardware)
+            .timeout(Duration::from_millis(250))
+            .max_frame_size(512)
+            .build_lazy();
+        assert_eq!(connection.path, "/dev/ttyUSB0");
+        assert_eq!(connection.settings.baud_rate, Baud9600);
+        assert_eq!(connection.settings.parity, ParityEven);
+        assert_eq!(connection.settings.stop_bits, Stop2);
+        assert_eq!(connection.settings.flow_control, FlowHardware);
+        assert_eq!(connection.timeout, Duration::from_millis(250));
+        assert_eq!(connection.decoder.max_frame_size(), 512);
+
+        assert!(UartConnectionBuilder::new("/dev/ws-api-does-not-exist").build().is_err());
+    }
+
+    #[test]
+    fn test_length_prefixed_framing() {
+        let mut connection = responding_connection(|written| {
+            // Echo back whatever command was sent, if it was length-prefixed
+            match Command::from_bytes_with(written.to_vec(), Framing::LengthPrefixed) {
+                Ok(command) if written.len() == 2 + 1 + command.data.len() => written.to_vec(),
+                _ => Vec::new(),
+            }
+        });
+        connection.set_framing(Framing::LengthPrefixed);
+        connection.send_message(Command::startup_command(vec![0, 1, 2])).unwrap();
+        let command = connection.receive_message(Duration::from_millis(100)).unwrap();
+        assert_eq!(command, (crate::CommandType::StartupCommand, &[0, 1, 2][..]));
+
+        // A command too long for the u16 length is refused rather than panicking
+        let error = connection.send_message(Command::startup_command(vec![7; 0x10000])).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(connection.stats().bytes_sent, 2 + 1 + 3);
+    }
+
+    #[test]
+    fn test_drain_input() {
+        let mut input = Command::startup_command(vec![1, 2, 3]).to_bytes();
+        input.extend(Command::simple_command(crate::CommandType::PowerDown).to_bytes());
+        let mut port = MockPort::new(&input);
+        port.max_read = 4;
+        let mut connection = connection_with_port(port);
+        // Leave part of the input buffered by the decoder
+        connection.receive_message(Duration::from_millis(100)).unwrap();
+        let remaining = input.len() - Command::startup_command(vec![1, 2, 3]).to_bytes().len();
+        assert_eq!(connection.drain_input().unwrap(), remaining);
+        assert!(matches!(
+            connection.receive_message(Duration::from_millis(50)),
+            Err(ReceiveError::Timeout)
+        ));
+        assert_eq!(connection.drain_input().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_set_timeout_keeps_buffered_bytes() {
+        let mut input = Command::startup_command(vec![1, 2, 3]).to_bytes();
+        input.extend(Command::simple_command(crate::CommandType::PowerDown).to_bytes());
+        let mut port = MockPort::new(&input);
+        port.max_read = 4;
+        let mut connection = connection_with_port(port);
+        connection.receive_message(Duration::from_millis(100)).unwrap();
+        assert!(!connection.decoder.buffered().is_empty());
+
+        connection.set_timeout(Duration::from_millis(250)).unwrap();
+        assert_eq!(connection.timeout, Duration::from_millis(250));
+        assert_eq!(connection.port.as_ref().unwrap().timeout(), Duration::from_millis(250));
+        assert!(!connection.decoder.buffered().is_empty());
+
+        let mut settings = connection.settings;
+        settings.baud_rate = Baud9600;
+        connection.set_settings(settings).unwrap();
+        assert_eq!(connection.settings.baud_rate, Baud9600);
+        assert_eq!(
+            connection.receive_message(Duration::from_millis(100)).unwrap().command_type,
+            crate::CommandType::PowerDown
+        );
+    }
+
+    #[test]
+    fn test_with_defaults() {
+        let connection = UartConnection::with_defaults("/dev/ws-api-does-not-exist");
+        assert!(connection.port.is_none());
+        assert_eq!(connection.path, "/dev/ws-api-does-not-exist");
+        assert_eq!(connection.settings, DEFAULT_PORT_SETTINGS);
+        assert_eq!(connection.settings.baud_rate, Baud115200);
+        assert_eq!(connection.timeout, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_set_timeout_before_open() {
+        let mut connection = UartConnection::new(
+            String::from("/dev/does-not-exist"),
+            DEFAULT_PORT_SETTINGS,
+            Duration::from_secs(1),
+        );
+        connection.set_timeout(Duration::from_millis(20)).unwrap();
+        assert_eq!(connection.timeout, Duration::from_millis(20));
+        assert!(connection.port.is_none());
+    }
+
+    #[test]
+    fn test_send_message_short_writes() {
+        let mut port = MockPort::new(&[]);
+        port.max_write = 3;
+        port.respond = Some(Box::new(|written| written.to_vec()));
+        let mut connection = connection_with_port(port);
+        let command = Command::startup_command(b"patch01.json".to_vec());
+        assert_eq!(connection.send_message(command.clone()).unwrap(), command.frame_len());
+        assert_eq!(connection.receive_message(Duration::from_millis(100)).unwrap(), command);
+
+        connection.set_send_timeout(Some(Duration::from_millis(100)));
+        connection.send_message(command.clone()).unwrap();
+        assert_eq!(connection.receive_message(Duration::from_millis(100)).unwrap(), command);
+    }
+
+    #[test]
+    fn test_send_timeout() {
+        let mut port = MockPort::new(&[]);
+        port.write_capacity = 4;
+        let mut connection = connection_with_port(port);
+        connection.set_send_timeout(Some(Duration::from_millis(100)));
+        let start_time = Instant::now();
+        let error = connection.send_message(Command::startup_command(b"patch01.json".to_vec())).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        assert!(start_time.elapsed() >= Duration::from_millis(100));
+        assert!(start_time.elapsed() < Duration::from_secs(1));
+        assert_eq!(connection.port.as_ref().unwrap().timeout(), connection.timeout);
+        assert_eq!(connection.stats().bytes_sent, 0);
+    }
+
+    #[test]
+    fn test_turnaround() {
+        let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let responder_events = events.clone();
+        let mut connection = responding_connection(move |written| {
+            responder_events.lock().unwrap().push("write");
+            written.to_vec()
+        });
+        let turnaround_events = events.clone();
+        connection.set_turnaround(move |transmitting| {
+            turnaround_events.lock().unwrap().push(if transmitting { "transmit" } else { "receive" });
+        });
+
+        connection.send_message(Command::simple_command(crate::CommandType::PowerDown)).unwrap();
+        assert_eq!(*events.lock().unwrap(), ["transmit", "write", "receive"]);
+        connection.receive_message(Duration::from_millis(100)).unwrap();
+        assert_eq!(events.lock().unwrap().len(), 3);
+
+        // Raw writes keep the line until it is read from
+        events.lock().unwrap().clear();
+        connection.write_all(b"abc").unwrap();
+        connection.write_all(b"def").unwrap();
+        assert_eq!(*events.lock().unwrap(), ["transmit", "write", "write"]);
+        let mut echoed = [0; 6];
+        connection.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"abcdef");
+        assert_eq!(*events.lock().unwrap(), ["transmit", "write", "write", "receive"]);
+    }
+
+    #[test]
+    fn test_software_flow_control_receive() {
+        let heartbeat = Command::simple_command(crate::CommandType::Heartbeat);
+        let frame = heartbeat.to_bytes();
+        let input = [&[XOFF][..], &frame[..1], &[XON], &frame[1..], &[XOFF, b'a', XON, b'b']].concat();
+        let mut connection = mock_connection(&input);
+        connection.set_software_flow_control(true);
+        assert_eq!(connection.receive_message(Duration::from_millis(100)).unwrap(), heartbeat);
+        let mut raw = [0; 2];
+        connection.read_exact(&mut raw).unwrap();
+        assert_eq!(&raw, b"ab");
+        assert!(!connection.paused);
+    }
+
+    #[test]
+    fn test_software_flow_control_send() {
+        let written = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let flow_controlled = |input: &[u8], respond_with: Vec<u8>| {
+            let written = written.clone();
+            let mut respond_with = Some(respond_with);
+            let mut port = MockPort::new(input);
+            port.respond = Some(Box::new(move |bytes: &[u8]| {
+                written.lock().unwrap().extend_from_slice(bytes);
+                respond_with.take().unwrap_or_default()
+            }));
+            let mut connection = connection_with_port(port);
+            connection.set_software_flow_control(true);
+            connection.set_send_timeout(Some(Duration::from_millis(50)));
+            connection
+        };
+        let command = Command::startup_command(vec![7; 40]);
+
+        // Paused until the XON that is already waiting
+        flow_controlled(&[XOFF, XON], Vec::new()).send_message(command.clone()).unwrap();
+        assert_eq!(*written.lock().unwrap(), command.to_bytes());
+
+        // Never resumed
+        written.lock().unwrap().clear();
+        let error = flow_controlled(&[XOFF], Vec::new()).send_message(command.clone()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        assert!(written.lock().unwrap().is_empty());
+
+        // Paused part way through the frame
+        written.lock().unwrap().clear();
+        let error = flow_controlled(&[], vec![XOFF]).send_message(command).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(written.lock().unwrap().len(), FLOW_CONTROL_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn test_software_flow_control_escaping() {
+        // The COBS code byte, sequence number and data all hold XON or XOFF
+        let command = Command::new_with_seq(crate::CommandType::StartupCommand, vec![0x11, 0x13, 0x7d], 0x13);
+        let frame = command.to_bytes();
+        assert!(frame.contains(&XON) && frame.contains(&XOFF));
+        let escaped = escape_flow_control(&frame);
+        assert!(!escaped.contains(&XON) && !escaped.contains(&XOFF));
+
+        // Received escaped, with real flow control bytes between, one byte at a time
+        let input = [&[XOFF][..], &escaped[..3], &[XON], &escaped[3..]].concat();
+        let mut port = MockPort::new(&input);
+        port.max_read = 1;
+        let mut connection = connection_with_port(port);
+        connection.set_software_flow_control(true);
+        assert_eq!(connection.receive_message(Duration::from_millis(100)).unwrap(), command);
+        assert!(!connection.paused);
+
+        // Sent escaped, and read back intact by a peer that unescapes
+        let written = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let port_written = written.clone();
+        let mut port = MockPort::new(&[]);
+        port.respond = Some(Box::new(move |bytes: &[u8]| {
+            port_written.lock().unwrap().extend_from_slice(bytes);
+            Vec::new()
+        }));
+        let mut connection = connection_with_port(port);
+        connection.set_software_flow_control(true);
+        connection.send_message(command.clone()).unwrap();
+        assert_eq!(*written.lock().unwrap(), escaped);
+        let mut receiver = mock_connection(&escaped);
+        receiver.set_software_flow_control(true);
+        assert_eq!(receiver.receive_message(Duration::from_millis(100)).unwrap(), command);
+    }
+
+    #[test]
+    fn test_close() {
+        let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let turnaround_events = events.clone();
+        let mut connection = mock_connection(&[]);
+        connection.set_turnaround(move |transmitting| turnaround_events.lock().unwrap().push(transmitting));
+        connection.write_all(b"abc").unwrap();
+        assert_eq!(*events.lock().unwrap(), [true]);
+        connection.close().unwrap();
+        assert_eq!(*events.lock().unwrap(), [true, false]);
+
+        // Dropping releases the line the same way
+        events.lock().unwrap().clear();
+        let turnaround_events = events.clone();
+        let mut connection = mock_connection(&[]);
+        connection.set_turnaround(move |transmitting| turnaround_events.lock().unwrap().push(transmitting));
+        connection.write_all(b"abc").unwrap();
+        drop(connection);
+        assert_eq!(*events.lock().unwrap(), [true, false]);
+
+        // A device that was never opened is not opened to close it
+        UartConnection::with_defaults("/dev/does-not-exist").close().unwrap();
+
+        let mut port = MockPort::new(&[]);
+        port.fail = Some(std::io::ErrorKind::BrokenPipe);
+        let error = connection_with_port(port).close().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_receive_init_with_boot_status() {
+        let mut input = Command::simple_command(crate::CommandType::PowerDown).to_bytes();
+        input.extend(Command::initialised(3, 0).to_bytes());
+        let mut connection = mock_connection(&input);
+        let start_time = Instant::now();
+        let init = connection.receive_init(Duration::from_secs(2)).unwrap().unwrap();
+        assert!(start_time.elapsed() < Duration::from_secs(1));
+        assert_eq!(init.boot_status(), Ok(Some(crate::BootStatus { version: 3, status: 0 })));
+
+        let mut connection = mock_connection(&Command::simple_command(crate::CommandType::Initialised).to_bytes());
+        let start_time = Instant::now();
+        let init = connection.receive_init(Duration::from_secs(2)).unwrap().unwrap();
+        assert!(start_time.elapsed() < Duration::from_secs(1));
+        assert_eq!(init.boot_status(), Ok(None));
+    }
+
+    #[test]
+    fn test_receive_init_timeout() {
+        let mut connection = mock_connection(&Command::simple_command(crate::CommandType::PowerDown).to_bytes());
+        assert_eq!(connection.receive_init(Duration::from_millis(50)).unwrap(), None);
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_run_startup_sequence() {
+        let acknowledged = std::sync::Arc::new(std::sync::Mutex::new(false));
+        let acknowledged_by_peer = acknowledged.clone();
+        let mut connection = responding_connection(move |frame| {
+            let command = Command::from_bytes(frame.to_vec()).unwrap();
+            match command.command_type {
+                crate::CommandType::Time => Command::simple_command(crate::CommandType::TimeAcknowledge).to_bytes(),
+                crate::CommandType::StartupCommand => {
+                    let mut reply = Command::startup_command_ack("orbit05.json").to_bytes();
+                    reply.extend(Command::simple_command(crate::CommandType::Heartbeat).to_bytes());
+                    reply.extend(Command::initialised(3, 0).to_bytes());
+                    reply
+                }
+                crate::CommandType::InitialisedAcknowledge => {
+                    *acknowledged_by_peer.lock().unwrap() = true;
+                    Vec::new()
+                }
+                _ => Vec::new(),
+            }
+        });
+        connection
+            .run_startup_sequence(chrono::Utc::now(), "orbit05.json", Duration::from_millis(200))
+            .unwrap();
+        assert!(*acknowledged.lock().unwrap());
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_run_startup_sequence_reports_failed_step() {
+        let mut connection = mock_connection(&[]);
+        let error = connection
+            .run_startup_sequence(chrono::Utc::now(), "orbit05.json", Duration::from_millis(50))
+            .unwrap_err();
+        assert!(matches!(error, crate::StartupError::Time(ref e) if e.kind() == std::io::ErrorKind::TimedOut));
+
+        // The payload takes the startup command but never reports Initialised
+        let mut connection = responding_connection(|frame| {
+            match Command::from_bytes(frame.to_vec()).unwrap().command_type {
+                crate::CommandType::Time => Command::simple_command(crate::CommandType::TimeAcknowledge).to_bytes(),
+                crate::CommandType::StartupCommand => Command::startup_command_ack("orbit05.json").to_bytes(),
+                _ => Vec::new(),
+            }
+        });
+        let error = connection
+            .run_startup_sequence(chrono::Utc::now(), "orbit05.json", Duration::from_millis(50))
+            .unwrap_err();
+        assert!(matches!(error, crate::StartupError::Initialised(ref e) if e.kind() == std::io::ErrorKind::TimedOut));
+        assert_eq!(std::io::Error::from(error).kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let frame = Command::startup_command(b"patch01.json\0x".to_vec()).to_bytes();
+        assert_eq!(
+            Hexdump(&frame).to_string(),
+            "00000000  0e 01 70 61 74 63 68 30 31 2e 6a 73 6f 6e 02 78  |..patch01.json.x|\n\
+             00000010  00                                               |.|"
+        );
+        assert_eq!(Hexdump(&[]).to_string(), "");
+    }
+
+    fn unplugged_connection() -> UartConnection {
+        let mut port = MockPort::new(&[]);
+        port.fail = Some(std::io::ErrorKind::BrokenPipe);
+        let mut connection = connection_with_port(port);
+        connection.path = String::from("/dev/does-not-exist");
+        connection
+    }
+
+    #[test]
+    fn test_disconnect_without_auto_reconnect() {
+        let mut connection = unplugged_connection();
+        let error = connection.send_message(Command::simple_command(crate::CommandType::PowerDown)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::BrokenPipe);
+        assert!(matches!(
+            connection.receive_message(Duration::from_millis(50)),
+            Err(ReceiveError::Io(e)) if e.kind() == std::io::ErrorKind::BrokenPipe
+        ));
+    }
+
+    #[test]
+    fn test_auto_reconnect_gives_up() {
+        let mut connection = unplugged_connection();
+        connection.set_auto_reconnect(true);
+        let error = connection.send_message(Command::simple_command(crate::CommandType::PowerDown)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotConnected);
+        assert!(connection.port.is_none());
+
+        let mut connection = unplugged_connection();
+        connection.set_auto_reconnect(true);
+        let start_time = Instant::now();
+        assert!(matches!(
+            connection.receive_message(Duration::from_millis(50)),
+            Err(ReceiveError::Disconnected(_))
+        ));
+        // Every attempt is made before giving up, even past the receive timeout
+        assert!(start_time.elapsed() >= RECONNECT_DELAY * (MAX_RECONNECT_ATTEMPTS - 1));
+    }
+
+    #[test]
+    fn test_receive_messages() {
+        let mut input = Command::simple_command(crate::CommandType::Initialised).to_bytes();
+        for i in 0..100 {
+            input.extend(Command::startup_command(vec![i; 10]).to_bytes());
+        }
+        let partial = Command::simple_command(crate::CommandType::PowerDown).to_bytes();
+        input.extend(&partial[..1]);
+        let mut connection = mock_connection(&input);
+        let commands = connection.receive_messages(Duration::from_millis(100)).unwrap();
+        assert_eq!(commands.len(), 101);
+        assert_eq!(commands[0].command_type, crate::CommandType::Initialised);
+        assert_eq!(commands[100].data, vec![99; 10]);
+        assert_eq!(connection.decoder.buffered(), &partial[..1]);
+        assert!(connection.receive_messages(Duration::from_millis(50)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_stats() {
+        let frame = Command::startup_command(vec![1, 2, 3]).to_bytes();
+        let mut input = frame.clone();
+        input.extend([0x09, 0x01, 0x00]);
+        let mut connection = mock_connection(&input);
+        connection.receive_message(Duration::from_millis(50)).unwrap();
+        assert!(connection.receive_message(Duration::from_millis(50)).is_err());
+        assert!(connection.receive_message(Duration::from_millis(50)).is_err());
+        let sent = Command::simple_command(crate::CommandType::PowerDown);
+        let sent_len = sent.to_bytes().len() as u64;
+        connection.send_message(sent).unwrap();
+        assert_eq!(
+            connection.stats(),
+            Stats {
+                bytes_sent: sent_len,
+                bytes_received: input.len() as u64,
+                frames_decoded: 1,
+                parse_errors: 1,
+                timeouts: 1,
+            }
+        );
+        connection.reset_stats();
+        assert_eq!(connection.stats(), Stats::default());
+    }
+
+    #[test]
+    fn test_idle_receive_does_not_spin() {
+        let idle_reads = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut port = MockPort::new(&[]);
+        port.idle_reads = Some(idle_reads.clone());
+        let mut connection = connection_with_port(port);
+        let start_time = Instant::now();
+        assert!(matches!(connection.receive_message(Duration::from_millis(100)), Err(ReceiveError::Timeout)));
+        assert!(start_time.elapsed() >= Duration::from_millis(100));
+        // Polling every IDLE_POLL_INTERVAL rather than reading in a busy loop
+        let reads = idle_reads.load(std::sync::atomic::Ordering::Relaxed);
+        assert!(reads > 0 && reads <= 21, "{} reads", reads);
+    }
+
+    #[test]
+    fn test_receive_message_with_zero_bytes() {
+        let commands = [
+            Command::startup_command(vec![0, 1, 0, 2, 0]),
+            Command::time_from_millis(1_690_000_000_123),
+            Command::startup_command(vec![0; 300]),
+            Command::new_with_seq(crate::CommandType::StartupCommand, vec![0, 0], 0),
+        ];
+        let input: Vec<u8> = commands.iter().flat_map(|command| command.to_bytes()).collect();
+        for max_read in [1, 3, READ_CHUNK_SIZE] {
+            let mut port = MockPort::new(&input);
+            port.max_read = max_read;
+            let mut connection = connection_with_port(port);
+            for command in commands.iter() {
+                assert_eq!(&connection.receive_message(Duration::from_millis(100)).unwrap(), command);
+            }
+            assert!(connection.decoder.buffered().is_empty());
+        }
+    }
+}
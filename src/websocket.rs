@@ -0,0 +1,244 @@
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::{HandshakeError, Message, WebSocket};
+use crate::{Command, Transport};
+
+/// How commands are carried in websocket messages
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum WebSocketEncoding {
+    /// Each command is a binary message holding its COBS frame, as sent over the UART
+    #[default]
+    Cobs,
+    /// Each command is a text message holding its JSON form, see `Command::to_json`
+    #[cfg(feature = "json")]
+    Json,
+}
+
+/// A `Transport` exchanging commands with the ground station over a websocket
+///
+/// Each websocket message carries one command. Both encodings are accepted
+/// when receiving, and commands are sent in the encoding set with
+/// `set_encoding`. Pings are answered while receiving.
+pub struct WebSocketTransport {
+    socket: WebSocket<TcpStream>,
+    encoding: WebSocketEncoding,
+}
+
+impl WebSocketTransport {
+    /// Connect to a websocket server
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The `ws://` URL of the server, TLS is not supported
+    ///
+    /// # Returns
+    ///
+    /// * A new WebSocketTransport
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidInput` if the URL is not a valid `ws://` URL
+    /// * If the server could not be reached or the handshake failed
+    ///
+    pub fn connect(url: &str) -> std::io::Result<WebSocketTransport> {
+        let request = url.into_client_request().map_err(ws_error)?;
+        let uri = request.uri();
+        let (Some("ws"), Some(host)) = (uri.scheme_str(), uri.host()) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is not a ws:// URL", url),
+            ));
+        };
+        let stream = TcpStream::connect((host, uri.port_u16().unwrap_or(80)))?;
+        let (socket, _) = tungstenite::client(request, stream).map_err(handshake_error)?;
+        Ok(WebSocketTransport::new(socket))
+    }
+
+    /// Accept a websocket connection from a client, completing its handshake
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The accepted TCP connection
+    ///
+    /// # Returns
+    ///
+    /// * A new WebSocketTransport
+    ///
+    /// # Errors
+    ///
+    /// * If the handshake failed
+    ///
+    pub fn accept(stream: TcpStream) -> std::io::Result<WebSocketTransport> {
+        let socket = tungstenite::accept(stream).map_err(handshake_error)?;
+        Ok(WebSocketTransport::new(socket))
+    }
+
+    /// Create a transport over an established websocket
+    ///
+    /// # Arguments
+    ///
+    /// * `socket` - The websocket, after its handshake
+    ///
+    /// # Returns
+    ///
+    /// * A new WebSocketTransport sending COBS frames
+    ///
+    pub fn new(socket: WebSocket<TcpStream>) -> WebSocketTransport {
+        WebSocketTransport {
+            socket,
+            encoding: WebSocketEncoding::default(),
+        }
+    }
+
+    /// The encoding commands are sent in
+    pub fn encoding(&self) -> WebSocketEncoding {
+        self.encoding
+    }
+
+    /// Set the encoding commands are sent in, defaults to `WebSocketEncoding::Cobs`
+    pub fn set_encoding(&mut self, encoding: WebSocketEncoding) {
+        self.encoding = encoding;
+    }
+
+    /// The underlying websocket
+    pub fn get_ref(&self) -> &WebSocket<TcpStream> {
+        &self.socket
+    }
+
+    /// The underlying websocket, mutably
+    pub fn get_mut(&mut self) -> &mut WebSocket<TcpStream> {
+        &mut self.socket
+    }
+
+    /// Consume the transport, returning the underlying websocket
+    pub fn into_inner(self) -> WebSocket<TcpStream> {
+        self.socket
+    }
+
+    /// Decode a received websocket message, if it carries a valid command
+    fn decode(message: Message) -> Option<Command> {
+        match message {
+            Message::Binary(frame) => Command::from_bytes(frame).ok(),
+            #[cfg(feature = "json")]
+            Message::Text(json) => Command::from_json(&json).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn send_message(&mut self, command: Command) -> std::io::Result<usize> {
+        let message = match self.encoding {
+            WebSocketEncoding::Cobs => Message::Binary(command.to_bytes()),
+            #[cfg(feature = "json")]
+            WebSocketEncoding::Json => Message::Text(command.to_json()),
+        };
+        let len = message.len();
+        self.socket.send(message).map_err(ws_error)?;
+        Ok(len)
+    }
+
+    fn receive_message(&mut self, timeout: Duration) -> std::io::Result<Option<Command>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            self.socket.get_mut().set_read_timeout(Some(remaining))?;
+            match self.socket.read() {
+                // Skip messages that are not commands, such as pings
+                Ok(message) => {
+                    if let Some(command) = WebSocketTransport::decode(message) {
+                        return Ok(Some(command));
+                    }
+                }
+                Err(tungstenite::Error::Io(e))
+                    if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {}
+                Err(e) => return Err(ws_error(e)),
+            }
+        }
+    }
+}
+
+/// Convert a websocket error for callers working in std::io::Result
+///
+/// IO errors are passed through and a closed connection becomes `NotConnected`.
+fn ws_error(error: tungstenite::Error) -> std::io::Error {
+    match error {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            std::io::Error::new(std::io::ErrorKind::NotConnected, error)
+        }
+        tungstenite::Error::Url(_) => std::io::Error::new(std::io::ErrorKind::InvalidInput, error),
+        _ => std::io::Error::other(error),
+    }
+}
+
+/// Convert a failed handshake, which over a blocking stream is never interrupted
+fn handshake_error<Role: tungstenite::handshake::HandshakeRole>(error: HandshakeError<Role>) -> std::io::Error {
+    match error {
+        HandshakeError::Failure(e) => ws_error(e),
+        HandshakeError::Interrupted(_) => std::io::ErrorKind::WouldBlock.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CommandType;
+    use std::net::TcpListener;
+
+    /// Start a payload on a websocket server that acknowledges whatever it receives
+    fn spawn_payload() -> (String, std::thread::JoinHandle<Vec<Command>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let payload = std::thread::spawn(move || {
+            let mut payload = WebSocketTransport::accept(listener.accept().unwrap().0).unwrap();
+            let mut received = Vec::new();
+            while let Some(command) = payload.receive_message(Duration::from_millis(300)).unwrap() {
+                if let Some(ack) = Command::ack_for(&command) {
+                    payload.send_message(ack).unwrap();
+                }
+                received.push(command);
+            }
+            received
+        });
+        (url, payload)
+    }
+
+    #[test]
+    fn test_websocket_transport() {
+        let (url, payload) = spawn_payload();
+        let mut ground = WebSocketTransport::connect(&url).unwrap();
+        let startup = Command::startup_command(b"orbit05.json".to_vec());
+        let ack = ground.send_and_await_ack(startup.clone(), Duration::from_secs(1)).unwrap();
+        assert_eq!(ack, (CommandType::StartupCommandAcknowledge, &b"orbit05.json"[..]));
+        let heartbeat = Command::simple_command(CommandType::Heartbeat);
+        assert_eq!(ground.send_message(heartbeat.clone()).unwrap(), heartbeat.frame_len());
+        let ack = ground.receive_message(Duration::from_secs(1)).unwrap().unwrap();
+        assert_eq!(ack.command_type, CommandType::HeartbeatAcknowledge);
+        assert_eq!(ground.receive_message(Duration::from_millis(10)).unwrap(), None);
+        assert_eq!(payload.join().unwrap(), vec![startup, heartbeat]);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_websocket_json() {
+        let (url, payload) = spawn_payload();
+        let mut ground = WebSocketTransport::connect(&url).unwrap();
+        ground.set_encoding(WebSocketEncoding::Json);
+        let time = Command::time_now();
+        let ack = ground.send_and_await_ack(time.clone(), Duration::from_secs(1)).unwrap();
+        assert_eq!(ack.command_type, CommandType::TimeAcknowledge);
+        assert_eq!(payload.join().unwrap(), vec![time]);
+    }
+
+    #[test]
+    fn test_connect_errors() {
+        let error = WebSocketTransport::connect("http://127.0.0.1:1").err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(WebSocketTransport::connect("ws://127.0.0.1:1").is_err());
+    }
+}